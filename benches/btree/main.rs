@@ -1,35 +1,135 @@
+#[path = "../common/mod.rs"]
+mod common;
+
+use rand::seq::SliceRandom;
 use rand::Rng;
 use std::collections::BTreeMap;
 
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
 use kvs_rs::bplustree;
 
 pub fn criterion_benchmark(c: &mut Criterion) {
-    c.bench_function("my btree: insert seq 500K", |b| {
+    // Every iteration runs the whole 500K operation batch
+    let mut group = c.benchmark_group("btree");
+    group.throughput(Throughput::Elements(500_000));
+    group.bench_function("my btree: insert seq 500K", |b| {
         b.iter(|| btree_insert_seq(black_box(500_000)))
     });
-    c.bench_function("reference btree: insert seq 500K", |b| {
+    report_stats(btree_insert_seq(500_000));
+    let append = bplustree::SplitPolicy::Append(90);
+    group.bench_function("my btree (append split): insert seq 500K", |b| {
+        b.iter(|| btree_insert_seq_with(black_box(500_000), append))
+    });
+    report_stats(btree_insert_seq_with(500_000, append));
+    group.bench_function("reference btree: insert seq 500K", |b| {
         b.iter(|| reference_btreemap_insert_seq(black_box(500_000)))
     });
-    c.bench_function("my btree: insert rand 500K", |b| {
+    group.bench_function("my btree: insert rand 500K", |b| {
         b.iter(|| btree_insert_rand(black_box(500_000)))
     });
-    c.bench_function("reference btree: insert rand 500K", |b| {
+    report_stats(btree_insert_rand(500_000));
+    group.bench_function("reference btree: insert rand 500K", |b| {
         b.iter(|| reference_btreemap_insert_rand(black_box(500_000)))
     });
-    c.bench_function("my btree: get rand 500K", |b| {
+    group.bench_function("my btree: get rand 500K", |b| {
         b.iter(|| btree_get_rand(black_box(500_000)))
     });
-    c.bench_function("reference btree: get rand 500K", |b| {
+    report_stats(btree_get_rand(500_000));
+    group.bench_function("reference btree: get rand 500K", |b| {
         b.iter(|| reference_btreemap_get_rand(black_box(500_000)))
     });
+    let (t, keys) = btree_filled_rand(500_000);
+    group.bench_function("my btree: lookup 500K", |b| {
+        b.iter(|| btree_lookup(black_box(&t), &keys))
+    });
+    let csb = t.clone().freeze_with(bplustree::FrozenLayout::Csb);
+    let veb = t.clone().freeze_with(bplustree::FrozenLayout::Veb);
+    let t = t.freeze();
+    group.bench_function("my btree (frozen): lookup 500K", |b| {
+        b.iter(|| frozen_lookup(black_box(&t), &keys))
+    });
+    group.bench_function("my btree (frozen csb): lookup 500K", |b| {
+        b.iter(|| frozen_lookup(black_box(&csb), &keys))
+    });
+    group.bench_function("my btree (frozen veb): lookup 500K", |b| {
+        b.iter(|| frozen_lookup(black_box(&veb), &keys))
+    });
+    let t = btree_filled_seq(500_000);
+    group.bench_function("my btree: scan 500K", |b| {
+        b.iter(|| btree_scan(black_box(&t)))
+    });
+    let t = reference_btreemap_filled_seq(500_000);
+    group.bench_function("reference btree: scan 500K", |b| {
+        b.iter(|| reference_btreemap_scan(black_box(&t)))
+    });
+    group.bench_function("my btree: delete seq 500K", |b| {
+        b.iter_batched(
+            || btree_filled_seq(500_000),
+            |t| btree_delete_seq(t, black_box(500_000)),
+            BatchSize::LargeInput,
+        )
+    });
+    report_stats(btree_delete_seq(btree_filled_seq(500_000), 500_000));
+    group.bench_function("reference btree: delete seq 500K", |b| {
+        b.iter_batched(
+            || reference_btreemap_filled_seq(500_000),
+            |t| reference_btreemap_delete_seq(t, black_box(500_000)),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("my btree: delete rand 500K", |b| {
+        b.iter_batched(
+            || btree_filled_rand(500_000),
+            |(t, keys)| btree_delete_rand(t, black_box(keys)),
+            BatchSize::LargeInput,
+        )
+    });
+    let (t, keys) = btree_filled_rand(500_000);
+    report_stats(btree_delete_rand(t, keys));
+    group.bench_function("reference btree: delete rand 500K", |b| {
+        b.iter_batched(
+            || reference_btreemap_filled_rand(500_000),
+            |(t, keys)| reference_btreemap_delete_rand(t, black_box(keys)),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("my btree: churn seq 500K", |b| {
+        b.iter(|| btree_churn_seq(black_box(500_000), black_box(CHURN_WINDOW)))
+    });
+    report_stats(btree_churn_seq(500_000, CHURN_WINDOW));
+    group.bench_function("reference btree: churn seq 500K", |b| {
+        b.iter(|| reference_btreemap_churn_seq(black_box(500_000), black_box(CHURN_WINDOW)))
+    });
+    group.bench_function("my btree: churn rand 500K", |b| {
+        b.iter(|| btree_churn_rand(black_box(500_000), black_box(CHURN_WINDOW)))
+    });
+    report_stats(btree_churn_rand(500_000, CHURN_WINDOW));
+    group.bench_function("reference btree: churn rand 500K", |b| {
+        b.iter(|| reference_btreemap_churn_rand(black_box(500_000), black_box(CHURN_WINDOW)))
+    });
+    group.finish();
 }
 
-fn btree_insert_seq(n: usize) {
-    let mut t = bplustree::BTree::new();
+// Print the shape the workload left the tree in, so that timing changes can be
+// told apart from structural ones (deeper trees, underfull nodes...)
+fn report_stats(t: bplustree::BTree) {
+    println!("structure: {}", t.stats());
+}
+
+// Number of live keys kept by the churn workloads: each insert past the
+// window deletes the oldest (seq) or a random (rand) live key
+const CHURN_WINDOW: usize = 50_000;
+
+fn btree_insert_seq(n: usize) -> bplustree::BTree {
+    btree_insert_seq_with(n, bplustree::SplitPolicy::default())
+}
+
+fn btree_insert_seq_with(n: usize, policy: bplustree::SplitPolicy) -> bplustree::BTree {
+    let mut t = bplustree::BTree::with_split_policy(policy);
     for i in 0..n {
         t.insert([i as u128; 1], 0);
     }
+    t
 }
 
 fn reference_btreemap_insert_seq(n: usize) {
@@ -39,24 +139,25 @@ fn reference_btreemap_insert_seq(n: usize) {
     }
 }
 
-fn btree_insert_rand(n: usize) {
-    let mut rng = rand::thread_rng();
+fn btree_insert_rand(n: usize) -> bplustree::BTree {
+    let mut rng = common::rng();
     let mut t = bplustree::BTree::new();
     for _ in 0..n {
         t.insert([rng.gen(); 1], 0);
     }
+    t
 }
 
 fn reference_btreemap_insert_rand(n: usize) {
-    let mut rng = rand::thread_rng();
+    let mut rng = common::rng();
     let mut t = BTreeMap::<[u128; 1], u8>::new();
     for _ in 0..n {
         t.insert([rng.gen(); 1], 0);
     }
 }
 
-fn btree_get_rand(n: usize) {
-    let mut rng = rand::thread_rng();
+fn btree_get_rand(n: usize) -> bplustree::BTree {
+    let mut rng = common::rng();
     let mut t = bplustree::BTree::new();
     for _ in 0..n {
         t.insert([rng.gen(); 1], 0);
@@ -64,10 +165,11 @@ fn btree_get_rand(n: usize) {
     for _ in 0..n {
         t.get(&[rng.gen(); 1]);
     }
+    t
 }
 
 fn reference_btreemap_get_rand(n: usize) {
-    let mut rng = rand::thread_rng();
+    let mut rng = common::rng();
     let mut t = BTreeMap::<[u128; 1], u8>::new();
     for _ in 0..n {
         t.insert([rng.gen(); 1], 0);
@@ -77,5 +179,150 @@ fn reference_btreemap_get_rand(n: usize) {
     }
 }
 
+fn btree_lookup(t: &bplustree::BTree, keys: &[u128]) -> usize {
+    keys.iter().filter(|k| t.get(&[**k; 1]).is_some()).count()
+}
+
+fn frozen_lookup(t: &bplustree::FrozenTree, keys: &[u128]) -> usize {
+    keys.iter().filter(|k| t.get(&[**k; 1]).is_some()).count()
+}
+
+fn btree_scan(t: &bplustree::BTree) -> u128 {
+    t.iter().fold(0, |acc, (k, _)| acc.wrapping_add(k[0]))
+}
+
+fn reference_btreemap_scan(t: &BTreeMap<[u128; 1], u8>) -> u128 {
+    t.iter().fold(0, |acc, (k, _)| acc.wrapping_add(k[0]))
+}
+
+fn btree_filled_seq(n: usize) -> bplustree::BTree {
+    let mut t = bplustree::BTree::new();
+    for i in 0..n {
+        t.insert([i as u128; 1], 0);
+    }
+    t
+}
+
+fn reference_btreemap_filled_seq(n: usize) -> BTreeMap<[u128; 1], u8> {
+    let mut t = BTreeMap::<[u128; 1], u8>::new();
+    for i in 0..n {
+        t.insert([i as u128; 1], 0);
+    }
+    t
+}
+
+fn btree_delete_seq(mut t: bplustree::BTree, n: usize) -> bplustree::BTree {
+    for i in 0..n {
+        t.delete(&[i as u128; 1]);
+    }
+    t
+}
+
+fn reference_btreemap_delete_seq(
+    mut t: BTreeMap<[u128; 1], u8>,
+    n: usize,
+) -> BTreeMap<[u128; 1], u8> {
+    for i in 0..n {
+        t.remove(&[i as u128; 1]);
+    }
+    t
+}
+
+// Returns the filled tree and its keys shuffled into a different order
+fn btree_filled_rand(n: usize) -> (bplustree::BTree, Vec<u128>) {
+    let mut rng = common::rng();
+    let mut t = bplustree::BTree::new();
+    let mut keys: Vec<u128> = (0..n).map(|_| rng.gen()).collect();
+    for k in &keys {
+        t.insert([*k; 1], 0);
+    }
+    keys.shuffle(&mut rng);
+    (t, keys)
+}
+
+fn reference_btreemap_filled_rand(n: usize) -> (BTreeMap<[u128; 1], u8>, Vec<u128>) {
+    let mut rng = common::rng();
+    let mut t = BTreeMap::<[u128; 1], u8>::new();
+    let mut keys: Vec<u128> = (0..n).map(|_| rng.gen()).collect();
+    for k in &keys {
+        t.insert([*k; 1], 0);
+    }
+    keys.shuffle(&mut rng);
+    (t, keys)
+}
+
+fn btree_delete_rand(mut t: bplustree::BTree, keys: Vec<u128>) -> bplustree::BTree {
+    for k in keys {
+        t.delete(&[k; 1]);
+    }
+    t
+}
+
+fn reference_btreemap_delete_rand(
+    mut t: BTreeMap<[u128; 1], u8>,
+    keys: Vec<u128>,
+) -> BTreeMap<[u128; 1], u8> {
+    for k in keys {
+        t.remove(&[k; 1]);
+    }
+    t
+}
+
+fn btree_churn_seq(n: usize, window: usize) -> bplustree::BTree {
+    let mut t = bplustree::BTree::new();
+    for i in 0..n {
+        t.insert([i as u128; 1], 0);
+        if i >= window {
+            t.delete(&[(i - window) as u128; 1]);
+        }
+    }
+    t
+}
+
+fn reference_btreemap_churn_seq(n: usize, window: usize) {
+    let mut t = BTreeMap::<[u128; 1], u8>::new();
+    for i in 0..n {
+        t.insert([i as u128; 1], 0);
+        if i >= window {
+            t.remove(&[(i - window) as u128; 1]);
+        }
+    }
+}
+
+fn btree_churn_rand(n: usize, window: usize) -> bplustree::BTree {
+    let mut rng = common::rng();
+    let mut t = bplustree::BTree::new();
+    let mut live: Vec<u128> = Vec::with_capacity(window);
+    for _ in 0..n {
+        let key = rng.gen();
+        t.insert([key; 1], 0);
+        if live.len() < window {
+            live.push(key);
+        } else {
+            let victim = rng.gen_range(0..window);
+            t.delete(&[live[victim]; 1]);
+            live[victim] = key;
+        }
+    }
+    t
+}
+
+fn reference_btreemap_churn_rand(n: usize, window: usize) {
+    let mut rng = common::rng();
+    let mut t = BTreeMap::<[u128; 1], u8>::new();
+    let mut live: Vec<u128> = Vec::with_capacity(window);
+    for _ in 0..n {
+        let key = rng.gen();
+        t.insert([key; 1], 0);
+        if live.len() < window {
+            live.push(key);
+        } else {
+            let victim = rng.gen_range(0..window);
+            t.remove(&[live[victim]; 1]);
+            live[victim] = key;
+        }
+    }
+}
+
 criterion_group!(benches, criterion_benchmark);
 criterion_main!(benches);
@@ -0,0 +1,25 @@
+//! Helpers shared by the benchmark targets, included with `#[path]`.
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::sync::OnceLock;
+
+static SEED: OnceLock<u64> = OnceLock::new();
+
+/// Seed used by every randomized benchmark of the run: read from the
+/// KVS_SEED environment variable when set, drawn at random otherwise.
+/// It is printed once so a surprising run can be reproduced.
+pub fn seed() -> u64 {
+    *SEED.get_or_init(|| {
+        let seed = match std::env::var("KVS_SEED") {
+            Ok(s) => s.parse().expect("KVS_SEED must be a u64"),
+            Err(_) => rand::random(),
+        };
+        eprintln!("benchmark seed: KVS_SEED={}", seed);
+        seed
+    })
+}
+
+/// Fresh RNG seeded with the run's seed, so each workload replays the same keys.
+pub fn rng() -> StdRng {
+    StdRng::seed_from_u64(seed())
+}
@@ -0,0 +1,123 @@
+//! Comparison against other embedded stores, run with
+//! `cargo bench --features compare-bench --bench compare`
+
+#[path = "../common/mod.rs"]
+mod common;
+
+use rand::Rng;
+use redb::backends::InMemoryBackend;
+use redb::{Database, TableDefinition};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use kvs_rs::bplustree;
+
+const TABLE: TableDefinition<u128, u8> = TableDefinition::new("bench");
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compare");
+    group.throughput(Throughput::Elements(100_000));
+    group.bench_function("my btree: insert seq 100K", |b| {
+        b.iter(|| btree_insert(black_box(100_000), false))
+    });
+    group.bench_function("sled: insert seq 100K", |b| {
+        b.iter(|| sled_insert(black_box(100_000), false))
+    });
+    group.bench_function("redb: insert seq 100K", |b| {
+        b.iter(|| redb_insert(black_box(100_000), false))
+    });
+    group.bench_function("my btree: insert rand 100K", |b| {
+        b.iter(|| btree_insert(black_box(100_000), true))
+    });
+    group.bench_function("sled: insert rand 100K", |b| {
+        b.iter(|| sled_insert(black_box(100_000), true))
+    });
+    group.bench_function("redb: insert rand 100K", |b| {
+        b.iter(|| redb_insert(black_box(100_000), true))
+    });
+
+    let t = btree_insert(100_000, false);
+    group.bench_function("my btree: get rand 100K", |b| {
+        b.iter(|| btree_get_rand(&t, black_box(100_000)))
+    });
+    let db = sled_insert(100_000, false);
+    group.bench_function("sled: get rand 100K", |b| {
+        b.iter(|| sled_get_rand(&db, black_box(100_000)))
+    });
+    let db = redb_insert(100_000, false);
+    group.bench_function("redb: get rand 100K", |b| {
+        b.iter(|| redb_get_rand(&db, black_box(100_000)))
+    });
+    group.finish();
+}
+
+fn key(i: usize, rand: bool, rng: &mut impl Rng) -> u128 {
+    match rand {
+        true => rng.gen(),
+        false => i as u128,
+    }
+}
+
+fn btree_insert(n: usize, rand: bool) -> bplustree::BTree {
+    let mut rng = common::rng();
+    let mut t = bplustree::BTree::new();
+    for i in 0..n {
+        t.insert([key(i, rand, &mut rng); 1], 0);
+    }
+    t
+}
+
+fn btree_get_rand(t: &bplustree::BTree, n: usize) {
+    let mut rng = common::rng();
+    for _ in 0..n {
+        black_box(t.get(&[rng.gen_range(0..n as u128); 1]));
+    }
+}
+
+// sled keeps the log in a temporary directory removed on drop; writes are not
+// flushed explicitly, matching its default (asynchronous) durability
+fn sled_insert(n: usize, rand: bool) -> sled::Db {
+    let mut rng = common::rng();
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    for i in 0..n {
+        db.insert(key(i, rand, &mut rng).to_be_bytes(), &[0])
+            .unwrap();
+    }
+    db
+}
+
+fn sled_get_rand(db: &sled::Db, n: usize) {
+    let mut rng = common::rng();
+    for _ in 0..n {
+        black_box(db.get(rng.gen_range(0..n as u128).to_be_bytes()).unwrap());
+    }
+}
+
+// redb runs on its in-memory backend with the whole batch in one write
+// transaction, so the numbers reflect its B-tree rather than fsync costs
+fn redb_insert(n: usize, rand: bool) -> Database {
+    let mut rng = common::rng();
+    let db = Database::builder()
+        .create_with_backend(InMemoryBackend::new())
+        .unwrap();
+    let txn = db.begin_write().unwrap();
+    {
+        let mut table = txn.open_table(TABLE).unwrap();
+        for i in 0..n {
+            table.insert(key(i, rand, &mut rng), 0).unwrap();
+        }
+    }
+    txn.commit().unwrap();
+    db
+}
+
+fn redb_get_rand(db: &Database, n: usize) {
+    let mut rng = common::rng();
+    let txn = db.begin_read().unwrap();
+    let table = txn.open_table(TABLE).unwrap();
+    for _ in 0..n {
+        black_box(table.get(rng.gen_range(0..n as u128)).unwrap());
+    }
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
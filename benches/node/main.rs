@@ -0,0 +1,88 @@
+#[path = "../common/mod.rs"]
+mod common;
+
+use rand::Rng;
+use std::sync::Arc;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use kvs_rs::bplustree::{find, InternalNode, LeafNode, Node, SplitPolicy};
+
+// Keys are spread out (even numbers) so that random odd keys land between them
+fn full_leaf() -> LeafNode {
+    let mut leaf = LeafNode::new();
+    let mut k = 0;
+    while !leaf.is_full() {
+        leaf.insert([k; 1], 0, SplitPolicy::Midpoint);
+        k += 2;
+    }
+    leaf
+}
+
+fn full_internal() -> InternalNode {
+    let mut pivots = Vec::new();
+    loop {
+        pivots.push([pivots.len() as u128 * 2 + 2; 1]);
+        let children = (0..=pivots.len()).map(|_| Arc::new(LeafNode::new()) as _);
+        let node = InternalNode::new_from(&pivots, children);
+        if node.is_full() {
+            return node;
+        }
+    }
+}
+
+// Pre-drawn lookup keys, so the RNG doesn't dominate nanosecond-scale timings
+fn lookup_keys(max: u128) -> Vec<[u128; 1]> {
+    let mut rng = common::rng();
+    (0..1024).map(|_| [rng.gen_range(0..max); 1]).collect()
+}
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let mut rng = common::rng();
+
+    let leaf = full_leaf();
+    let max_key = leaf.len() as u128 * 2;
+    let keys = lookup_keys(max_key);
+    c.bench_function("leaf: get", |b| {
+        let mut keys = keys.iter().cycle();
+        b.iter(|| find(&leaf, keys.next().unwrap()))
+    });
+    c.bench_function("leaf: insert random position", |b| {
+        b.iter_batched(
+            || {
+                let mut leaf = full_leaf();
+                leaf.pop_entry(false);
+                (leaf, rng.gen_range(0..max_key / 2) * 2 + 1)
+            },
+            |(mut leaf, key)| {
+                leaf.insert(black_box([key; 1]), 0, SplitPolicy::Midpoint);
+                leaf
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    c.bench_function("leaf: split", |b| {
+        b.iter_batched(
+            full_leaf,
+            |mut leaf| (leaf.split(SplitPolicy::Midpoint, &[0; 1]), leaf),
+            BatchSize::SmallInput,
+        )
+    });
+
+    let internal = full_internal();
+    let max_pivot = internal.len() as u128 * 2 + 2;
+    let keys = lookup_keys(max_pivot);
+    c.bench_function("internal: pivot search", |b| {
+        let mut keys = keys.iter().cycle();
+        b.iter(|| internal.child_idx(keys.next().unwrap()))
+    });
+    c.bench_function("internal: split", |b| {
+        b.iter_batched(
+            full_internal,
+            |mut node| (node.split(SplitPolicy::Midpoint, &[0; 1]), node),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
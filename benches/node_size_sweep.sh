@@ -0,0 +1,19 @@
+#!/bin/sh
+# Runs the btree benchmarks once per node size, saving a criterion baseline
+# named node-<size> for each so they can be compared afterwards.
+#   benches/node_size_sweep.sh            # default sweep, 128B to 16KB
+#   benches/node_size_sweep.sh 512 4096   # only the given sizes
+# 64B is below the minimum: its leaves would hold a single entry, and the
+# build fails on the splittable node assert (`PIVOTS_SIZE >= 3 &&
+# LEAF_ITEMS_SIZE >= 2`) in src/bplustree/mod.rs.
+# Extra criterion arguments can be passed through BENCH_ARGS.
+set -e
+
+if [ $# -eq 0 ]; then
+    set -- 128 256 512 1024 2048 4096 8192 16384
+fi
+
+for size in "$@"; do
+    echo "== NODE_SIZE=$size"
+    KVS_NODE_SIZE=$size cargo bench --bench btree -- --save-baseline "node-$size" $BENCH_ARGS "my btree"
+done
@@ -0,0 +1,209 @@
+#[path = "../common/mod.rs"]
+mod common;
+
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand_distr::{Distribution, Zipf};
+use std::collections::BTreeMap;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use kvs_rs::bplustree;
+
+// Records loaded before each workload and operations per measured iteration
+const RECORDS: u64 = 100_000;
+const OPS: usize = 100_000;
+// YCSB's default zipfian constant
+const ZIPF_THETA: f64 = 0.99;
+// Longest scan of workload E, scan lengths being uniform up to it
+const MAX_SCAN_LEN: usize = 100;
+// Hot-key cache in front of the tree, for 1% of the records
+const CACHE_ENTRIES: usize = RECORDS as usize / 100;
+
+// Minimal interface shared by our tree and the std reference. Reads take
+// `&mut self` for the cache to fill.
+trait Store {
+    fn read(&mut self, key: u128) -> Option<u8>;
+    fn write(&mut self, key: u128, val: u8);
+    // Entries read, at most `len` from `key` on
+    fn scan(&mut self, key: u128, len: usize) -> usize;
+}
+
+impl Store for bplustree::BTree {
+    fn read(&mut self, key: u128) -> Option<u8> {
+        self.get(&[key; 1])
+    }
+
+    fn write(&mut self, key: u128, val: u8) {
+        self.insert([key; 1], val);
+    }
+
+    fn scan(&mut self, key: u128, len: usize) -> usize {
+        self.range([key; 1]..).take(len).count()
+    }
+}
+
+impl Store for bplustree::CachedTree {
+    fn read(&mut self, key: u128) -> Option<u8> {
+        self.get(&[key; 1])
+    }
+
+    fn write(&mut self, key: u128, val: u8) {
+        self.insert([key; 1], val);
+    }
+
+    // Scans bypass the cache
+    fn scan(&mut self, key: u128, len: usize) -> usize {
+        self.tree().range([key; 1]..).take(len).count()
+    }
+}
+
+impl Store for BTreeMap<[u128; 1], u8> {
+    fn read(&mut self, key: u128) -> Option<u8> {
+        self.get(&[key; 1]).copied()
+    }
+
+    fn write(&mut self, key: u128, val: u8) {
+        self.insert([key; 1], val);
+    }
+
+    fn scan(&mut self, key: u128, len: usize) -> usize {
+        self.range([key; 1]..).take(len).count()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Workload {
+    // A: 50% read, 50% update, zipfian
+    UpdateHeavy,
+    // B: 95% read, 5% update, zipfian
+    ReadMostly,
+    // C: 100% read, zipfian
+    ReadOnly,
+    // D: 95% read, 5% insert, reads skewed towards the latest inserts
+    ReadLatest,
+    // E: 95% short range scan, 5% insert, zipfian scan starts
+    ShortRanges,
+    // F: 50% read, 50% read-modify-write, zipfian
+    ReadModifyWrite,
+}
+
+struct Runner<S: Store> {
+    store: S,
+    rng: StdRng,
+    zipf: Zipf<f64>,
+    // Next key to insert, keys below it are all present
+    next_key: u64,
+}
+
+impl<S: Store> Runner<S> {
+    fn load(mut store: S) -> Runner<S> {
+        for k in 0..RECORDS {
+            store.write(k as u128, 0);
+        }
+        Runner {
+            store,
+            rng: common::rng(),
+            zipf: Zipf::new(RECORDS, ZIPF_THETA).unwrap(),
+            next_key: RECORDS,
+        }
+    }
+
+    fn zipf_key(&mut self) -> u128 {
+        (self.zipf.sample(&mut self.rng) as u64 - 1) as u128
+    }
+
+    // Most recently inserted keys are the most popular
+    fn latest_key(&mut self) -> u128 {
+        let offset = self.zipf.sample(&mut self.rng) as u64;
+        self.next_key.saturating_sub(offset) as u128
+    }
+
+    fn run(&mut self, workload: Workload, ops: usize) {
+        for _ in 0..ops {
+            let p: f64 = self.rng.gen();
+            match workload {
+                Workload::UpdateHeavy | Workload::ReadMostly => {
+                    let update_ratio = match workload {
+                        Workload::UpdateHeavy => 0.5,
+                        _ => 0.05,
+                    };
+                    let key = self.zipf_key();
+                    if p < update_ratio {
+                        self.store.write(key, 1);
+                    } else {
+                        black_box(self.store.read(key));
+                    }
+                }
+                Workload::ReadOnly => {
+                    let key = self.zipf_key();
+                    black_box(self.store.read(key));
+                }
+                Workload::ReadLatest => {
+                    if p < 0.05 {
+                        self.store.write(self.next_key as u128, 0);
+                        self.next_key += 1;
+                    } else {
+                        let key = self.latest_key();
+                        black_box(self.store.read(key));
+                    }
+                }
+                Workload::ShortRanges => {
+                    if p < 0.05 {
+                        self.store.write(self.next_key as u128, 0);
+                        self.next_key += 1;
+                    } else {
+                        let key = self.zipf_key();
+                        let len = self.rng.gen_range(1..=MAX_SCAN_LEN);
+                        black_box(self.store.scan(key, len));
+                    }
+                }
+                Workload::ReadModifyWrite => {
+                    let key = self.zipf_key();
+                    let val = self.store.read(key);
+                    if p < 0.5 {
+                        self.store.write(key, val.unwrap_or(0).wrapping_add(1));
+                    }
+                }
+            }
+        }
+    }
+}
+
+const WORKLOADS: [(&str, Workload); 6] = [
+    ("A", Workload::UpdateHeavy),
+    ("B", Workload::ReadMostly),
+    ("C", Workload::ReadOnly),
+    ("D", Workload::ReadLatest),
+    ("E", Workload::ShortRanges),
+    ("F", Workload::ReadModifyWrite),
+];
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ycsb");
+    group.throughput(Throughput::Elements(OPS as u64));
+    for (name, workload) in WORKLOADS {
+        let mut runner = Runner::load(bplustree::BTree::new());
+        group.bench_function(format!("my btree: ycsb {} 100K", name), |b| {
+            b.iter(|| runner.run(workload, black_box(OPS)))
+        });
+        println!("structure: {}", runner.store.stats());
+        let cached = bplustree::CachedTree::new(bplustree::BTree::new(), CACHE_ENTRIES);
+        let mut runner = Runner::load(cached);
+        group.bench_function(format!("my btree (cached): ycsb {} 100K", name), |b| {
+            b.iter(|| runner.run(workload, black_box(OPS)))
+        });
+        println!(
+            "cache hits: {}, misses: {}",
+            runner.store.hits(),
+            runner.store.misses()
+        );
+        let mut runner = Runner::load(BTreeMap::<[u128; 1], u8>::new());
+        group.bench_function(format!("reference btree: ycsb {} 100K", name), |b| {
+            b.iter(|| runner.run(workload, black_box(OPS)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,126 @@
+//! Reports heap bytes per entry for the B+tree and std's BTreeMap, and the
+//! allocations performed by a full scan.
+//! Run with `cargo run --release --example memory [n]`
+use rand::Rng;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use kvs_rs::bplustree;
+
+// Wraps the system allocator to track the number of live heap bytes and of
+// allocations
+struct CountingAlloc;
+
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        LIVE_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        LIVE_BYTES.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+// Heap bytes retained by whatever `build` returns
+fn measure<T>(build: impl FnOnce() -> T) -> (usize, T) {
+    let before = LIVE_BYTES.load(Ordering::Relaxed);
+    let built = build();
+    let after = LIVE_BYTES.load(Ordering::Relaxed);
+    (after - before, built)
+}
+
+fn report(name: &str, n: usize, bytes: usize) {
+    println!(
+        "{:<28} {:>12} bytes {:>8.1} bytes/entry",
+        name,
+        bytes,
+        bytes as f64 / n as f64
+    );
+}
+
+fn main() {
+    let n: usize = std::env::args()
+        .nth(1)
+        .map(|s| s.parse().expect("n must be a number"))
+        .unwrap_or(1_000_000);
+    let mut rng = rand::thread_rng();
+    let rand_keys: Vec<u128> = (0..n).map(|_| rng.gen()).collect();
+    println!(
+        "{} entries of {} key bytes + {} value bytes",
+        n,
+        std::mem::size_of::<[u128; 1]>(),
+        std::mem::size_of::<u8>()
+    );
+
+    let (bytes, t) = measure(|| {
+        let mut t = bplustree::BTree::new();
+        for i in 0..n {
+            t.insert([i as u128; 1], 0);
+        }
+        t
+    });
+    report("my btree (seq)", n, bytes);
+    drop(t);
+
+    let (bytes, t) = measure(|| {
+        let mut t = bplustree::BTree::new();
+        for k in &rand_keys {
+            t.insert([*k; 1], 0);
+        }
+        t
+    });
+    report("my btree (rand)", n, bytes);
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let scanned = t.iter().count();
+    println!(
+        "full scan of {} entries: {} allocations",
+        scanned,
+        ALLOCATIONS.load(Ordering::Relaxed) - before
+    );
+    drop(t);
+
+    let (bytes, t) =
+        measure(|| bplustree::BTree::bulk_load((0..n).map(|i| ([i as u128; 1], 0))).unwrap());
+    report("my btree (bulk load)", n, bytes);
+    drop(t);
+
+    let (bytes, t) = measure(|| {
+        let mut t = bplustree::BTree::new();
+        for i in 0..n {
+            t.insert([i as u128; 1], 0);
+        }
+        t.freeze()
+    });
+    report("my btree (frozen)", n, bytes);
+    drop(t);
+
+    let (bytes, t) = measure(|| {
+        let mut t = BTreeMap::<[u128; 1], u8>::new();
+        for i in 0..n {
+            t.insert([i as u128; 1], 0);
+        }
+        t
+    });
+    report("reference btree (seq)", n, bytes);
+    drop(t);
+
+    let (bytes, t) = measure(|| {
+        let mut t = BTreeMap::<[u128; 1], u8>::new();
+        for k in &rand_keys {
+            t.insert([*k; 1], 0);
+        }
+        t
+    });
+    report("reference btree (rand)", n, bytes);
+    drop(t);
+}
@@ -0,0 +1,12 @@
+#![no_main]
+
+use kvs_rs::bplustree::LeafNode;
+use libfuzzer_sys::fuzz_target;
+
+// Arbitrary bytes must either be rejected by validation or decode to a valid leaf,
+// never crash the decoder
+fuzz_target!(|data: &[u8]| {
+    let mut aligned = rkyv::AlignedVec::with_capacity(data.len());
+    aligned.extend_from_slice(data);
+    let _ = rkyv::check_archived_root::<LeafNode>(&aligned);
+});
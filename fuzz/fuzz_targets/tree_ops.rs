@@ -0,0 +1,62 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use kvs_rs::bplustree::BTree;
+use libfuzzer_sys::fuzz_target;
+use std::collections::BTreeMap;
+
+// Keys are drawn from a u16 space so that overwrites, misses and pivot hits are frequent
+#[derive(Arbitrary, Debug)]
+enum Op {
+    Insert(u16, u8),
+    Get(u16),
+    Delete(u16),
+    Range(u16, u16),
+    RangeRev(u16, u16),
+    DeleteRange(u16, u16),
+    PopFirst,
+    PopLast,
+}
+
+// Entries of `model` within `lo..hi`, none if `lo` is after `hi` (where
+// `BTreeMap::range` panics)
+fn model_range(model: &BTreeMap<u16, u8>, lo: u16, hi: u16) -> Vec<(u16, u8)> {
+    if lo > hi {
+        return Vec::new();
+    }
+    model.range(lo..hi).map(|(k, v)| (*k, *v)).collect()
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mut btree = BTree::new();
+    let mut model = BTreeMap::new();
+    let key = |k: u16| [k as u128; 1];
+    let entry = |(k, v): ([u128; 1], u8)| (k[0] as u16, v);
+    for op in ops {
+        match op {
+            Op::Insert(k, v) => assert_eq!(btree.insert(key(k), v), model.insert(k, v)),
+            Op::Get(k) => assert_eq!(btree.get(&key(k)), model.get(&k).copied()),
+            Op::Delete(k) => assert_eq!(btree.delete(&key(k)), model.remove(&k)),
+            Op::Range(lo, hi) => assert_eq!(
+                btree.range(key(lo)..key(hi)).map(entry).collect::<Vec<_>>(),
+                model_range(&model, lo, hi)
+            ),
+            Op::RangeRev(lo, hi) => assert!(btree
+                .range(key(lo)..key(hi))
+                .rev()
+                .map(entry)
+                .eq(model_range(&model, lo, hi).into_iter().rev())),
+            Op::DeleteRange(lo, hi) => {
+                let len = model.len();
+                model.retain(|k, _| !(lo..hi).contains(k));
+                assert_eq!(btree.delete_range(key(lo)..key(hi)), len - model.len());
+            }
+            Op::PopFirst => assert_eq!(btree.pop_first().map(entry), model.pop_first()),
+            Op::PopLast => assert_eq!(btree.pop_last().map(entry), model.pop_last()),
+        }
+    }
+    assert_eq!(btree.total_len(), model.len());
+    for (k, v) in &model {
+        assert_eq!(btree.get(&key(*k)), Some(*v));
+    }
+});
@@ -0,0 +1,99 @@
+//! Groups of writes applied all at once. The batch is applied to a clone of
+//! the tree, which shares every node until it is written, and the clone then
+//! replaces the tree. Snapshots see the tree either before or after the whole
+//! batch, and a panic halfway through leaves the tree untouched.
+use super::*;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Write<K, V> {
+    Insert(K, V),
+    Delete(K),
+}
+
+/// Inserts and deletes to apply atomically with `BTree::apply`, in the order
+/// they were added
+#[derive(Clone, Debug)]
+pub struct WriteBatch<K = Key, V = Value> {
+    writes: Vec<Write<K, V>>,
+}
+
+// Not derived, which would require `K: Default`
+impl<K, V> Default for WriteBatch<K, V> {
+    fn default() -> Self {
+        WriteBatch { writes: Vec::new() }
+    }
+}
+
+impl<K, V> WriteBatch<K, V> {
+    pub fn new() -> WriteBatch<K, V> {
+        WriteBatch::default()
+    }
+
+    pub fn insert(&mut self, key: K, val: V) -> &mut WriteBatch<K, V> {
+        self.writes.push(Write::Insert(key, val));
+        self
+    }
+
+    pub fn delete(&mut self, key: K) -> &mut WriteBatch<K, V> {
+        self.writes.push(Write::Delete(key));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> BTree<K, V, LEAF> {
+    /// Apply every write of the batch, in order, as a single change: the new
+    /// root only replaces the current one once the whole batch is in. The
+    /// nodes the batch touches are copied once, even if nothing else shares
+    /// them.
+    pub fn apply(&mut self, batch: &WriteBatch<K, V>) {
+        let mut next = self.clone();
+        for write in &batch.writes {
+            match write {
+                Write::Insert(key, val) => {
+                    next.insert(key.clone(), val.clone());
+                }
+                Write::Delete(key) => {
+                    next.delete(key);
+                }
+            }
+        }
+        self.replace_nodes(next);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let mut tree = BTree::new();
+        (0..1000).for_each(|k| {
+            tree.insert([k; 1], 0);
+        });
+        let before = tree.share();
+
+        let mut batch = WriteBatch::new();
+        batch.insert([1000; 1], 1).delete([0; 1]).insert([0; 1], 2);
+        (1..500).for_each(|k| {
+            batch.delete([k; 1]);
+        });
+        assert_eq!(batch.len(), 502);
+        tree.apply(&batch);
+
+        assert_eq!(tree.total_len(), 502);
+        assert_eq!(tree.get(&[0; 1]), Some(2));
+        assert_eq!(tree.get(&[1000; 1]), Some(1));
+        tree.check_invariants().unwrap();
+        assert_eq!(before.total_len(), 1000);
+        assert_eq!(before.get(&[0; 1]), Some(0));
+    }
+}
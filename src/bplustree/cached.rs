@@ -0,0 +1,174 @@
+//! Small cache of hot keys in front of a tree, for skewed (e.g. zipfian)
+//! lookups. It is an open-addressing table: a key may sit in any of the
+//! `PROBES` slots following its hash, all checked on lookup, so a slot can be
+//! cleared without breaking other probe sequences. A key found in the tree
+//! takes a free slot of its window, or else one whose entry wasn't hit since
+//! the last time the window was scanned (a per-slot CLOCK).
+use super::*;
+
+// Slots checked per lookup, consecutive so they share cache lines
+const PROBES: usize = 4;
+
+#[derive(Clone, Debug)]
+struct Slot<V> {
+    key: Key,
+    val: V,
+    // Hit since the last eviction scan went over the slot
+    hot: bool,
+}
+
+/// Tree of `Key`s to any value type, with a cache of hot keys in front
+#[derive(Clone, Debug)]
+pub struct CachedTree<V = Value> {
+    tree: BTree<Key, V>,
+    slots: Vec<Option<Slot<V>>>,
+    hits: u64,
+    misses: u64,
+}
+
+impl<V: TreeValue> CachedTree<V> {
+    /// Put a cache of about `entries` keys (rounded up to a power of two) in
+    /// front of `tree`
+    pub fn new(tree: BTree<Key, V>, entries: usize) -> CachedTree<V> {
+        CachedTree {
+            tree,
+            slots: vec![None; entries.max(PROBES).next_power_of_two()],
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Look `key` up in the cache, then in the tree, caching what is found
+    pub fn get(&mut self, key: &Key) -> Option<V> {
+        let window = self.window(key);
+        for idx in window {
+            if let Some(slot) = &mut self.slots[idx] {
+                if slot.key == *key {
+                    slot.hot = true;
+                    self.hits += 1;
+                    return Some(slot.val.clone());
+                }
+            }
+        }
+        self.misses += 1;
+        let val = self.tree.get(key)?;
+        let idx = self.victim(window);
+        self.slots[idx] = Some(Slot {
+            key: *key,
+            val: val.clone(),
+            hot: false,
+        });
+        Some(val)
+    }
+
+    /// Insert into the tree, updating the cached value if any. Returns the
+    /// value replaced.
+    pub fn insert(&mut self, key: Key, val: V) -> Option<V> {
+        if let Some(idx) = self.find(&key) {
+            if let Some(slot) = &mut self.slots[idx] {
+                slot.val = val.clone();
+            }
+        }
+        self.tree.insert(key, val)
+    }
+
+    /// Delete from the tree, evicting the key from the cache. Returns the
+    /// removed value.
+    pub fn delete(&mut self, key: &Key) -> Option<V> {
+        if let Some(idx) = self.find(key) {
+            self.slots[idx] = None;
+        }
+        self.tree.delete(key)
+    }
+
+    /// Lookups answered by the cache
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Lookups that went to the tree
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub fn tree(&self) -> &BTree<Key, V> {
+        &self.tree
+    }
+
+    pub fn into_tree(self) -> BTree<Key, V> {
+        self.tree
+    }
+
+    // Indexes of the slots `key` may occupy, wrapping around the table
+    fn window(&self, key: &Key) -> [usize; PROBES] {
+        let k = key[0];
+        let h = ((k as u64) ^ ((k >> 64) as u64)).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        let mask = self.slots.len() - 1;
+        let first = (h >> 32) as usize;
+        std::array::from_fn(|i| (first + i) & mask)
+    }
+
+    fn find(&self, key: &Key) -> Option<usize> {
+        self.window(key)
+            .into_iter()
+            .find(|idx| matches!(&self.slots[*idx], Some(slot) if slot.key == *key))
+    }
+
+    // Slot of the window to fill: a free one, or the first that is not hot,
+    // cooling down the hot ones passed over
+    fn victim(&mut self, window: [usize; PROBES]) -> usize {
+        if let Some(idx) = window.into_iter().find(|idx| self.slots[*idx].is_none()) {
+            return idx;
+        }
+        for idx in window {
+            match &mut self.slots[idx] {
+                Some(slot) if slot.hot => slot.hot = false,
+                _ => return idx,
+            }
+        }
+        window[0]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_coherent_with_writes() {
+        let mut tree = BTree::new();
+        (0..1000).for_each(|k| {
+            tree.insert([k; 1], 1);
+        });
+        let mut cached = CachedTree::new(tree, 64);
+
+        for _ in 0..10 {
+            (0..8).for_each(|k| assert_eq!(cached.get(&[k; 1]), Some(1)));
+        }
+        assert!(cached.hits() >= 72);
+        assert_eq!(cached.get(&[5000; 1]), None);
+
+        assert_eq!(cached.insert([3; 1], 2), Some(1));
+        assert_eq!(cached.get(&[3; 1]), Some(2));
+        assert_eq!(cached.delete(&[4; 1]), Some(1));
+        assert_eq!(cached.delete(&[4; 1]), None);
+        assert_eq!(cached.get(&[4; 1]), None);
+
+        // Far more keys than slots: every answer still comes from the tree
+        for k in 0..1000 {
+            let expected = match k {
+                3 => Some(2),
+                4 => None,
+                _ => Some(1),
+            };
+            assert_eq!(cached.get(&[k; 1]), expected);
+        }
+        assert_eq!(cached.tree().total_len(), 999);
+
+        let mut names = CachedTree::new(BTree::new(), 16);
+        names.insert([1; 1], "one".to_string());
+        assert_eq!(names.get(&[1; 1]).as_deref(), Some("one"));
+        names.insert([1; 1], "uno".to_string());
+        assert_eq!(names.get(&[1; 1]).as_deref(), Some("uno"));
+    }
+}
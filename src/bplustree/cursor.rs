@@ -0,0 +1,424 @@
+//! Mutable cursor, to walk the entries in order and remove or insert entries
+//! at its position as it goes. The cursor holds the path to its leaf, so that
+//! reading the current entry or moving within the leaf costs O(1), and moving
+//! to the next leaf goes up and down the path, without starting over from the
+//! root.
+//!
+//! Without parent links or unsafe code, the nodes on the path can't be
+//! borrowed from the tree while the tree is writable: the cursor holds
+//! pointers to them instead. To read, they are clones of the pointers in the
+//! tree. The first write takes the path out of the tree (a zipper): the root
+//! is moved out of the tree and each node out of its parent, a placeholder
+//! taking its place, so that the nodes are no longer shared with the tree
+//! and can be written in place. Nodes shared with a snapshot are copied then,
+//! by the first write that goes through them. The path is put back into the
+//! tree as the cursor leaves it, and when the cursor is dropped.
+//!
+//! Removing an entry and inserting one before the current entry work in
+//! place in the leaf. Only the writes that change the structure, a removal
+//! that leaves the leaf underfull or an insert into a full leaf, put the path
+//! back and go through the tree, then seek the cursor back to its entry.
+use super::*;
+use std::ops::Bound;
+
+/// Cursor over the entries of a tree in ascending key order, returned by
+/// `BTree::cursor_mut`. It is either at an entry or past the last one.
+pub struct CursorMut<
+    'a,
+    K: TreeKey = Key,
+    V: TreeValue = Value,
+    const LEAF: usize = LEAF_ITEMS_SIZE,
+> {
+    tree: &'a mut BTree<K, V, LEAF>,
+    // Ancestors of the leaf from the root down, with the index of the child
+    // taken in each
+    path: Vec<(NodePtr<K, V, LEAF>, usize)>,
+    leaf: NodePtr<K, V, LEAF>,
+    // Levels taken out of the tree, from the root down, the leaf being the
+    // level after the last ancestor. The others are clones of the pointers
+    // still in the tree.
+    detached: usize,
+    // Index of the current entry in the leaf, its length when past the end
+    pos: usize,
+    // Stands in the tree for the detached nodes
+    placeholder: NodePtr<K, V, LEAF>,
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> BTree<K, V, LEAF> {
+    /// Cursor at the first entry after `start`
+    pub fn cursor_mut(&mut self, start: Bound<&K>) -> CursorMut<'_, K, V, LEAF> {
+        // Not a node of the tree, so not counted as an allocation
+        let placeholder: NodePtr<K, V, LEAF> = Arc::new(LeafNode::new());
+        let mut cursor = CursorMut {
+            tree: self,
+            path: Vec::new(),
+            leaf: placeholder.clone(),
+            detached: 0,
+            pos: 0,
+            placeholder,
+        };
+        cursor.seek(start);
+        cursor
+    }
+}
+
+impl<'a, K: TreeKey, V: TreeValue, const LEAF: usize> CursorMut<'a, K, V, LEAF> {
+    pub fn key(&self) -> Option<&K> {
+        self.leaf().keys.get(self.pos)
+    }
+
+    pub fn value(&self) -> Option<&V> {
+        self.leaf().values.get(self.pos)
+    }
+
+    /// The value of the current entry, copying the nodes on its path that a
+    /// snapshot still shares
+    pub fn value_mut(&mut self) -> Option<&mut V> {
+        self.key()?;
+        self.detach();
+        let pos = self.pos;
+        make_mut(&mut self.leaf)
+            .as_leaf_mut()
+            .unwrap()
+            .values
+            .get_mut(pos)
+    }
+
+    /// Move to the next entry; past the last one, stay there
+    pub fn move_next(&mut self) {
+        if self.key().is_some() {
+            self.pos += 1;
+            self.settle();
+        }
+    }
+
+    /// Remove the current entry and move to the next one
+    pub fn remove_current(&mut self) -> Option<(K, V)> {
+        self.key()?;
+        self.detach();
+        let pos = self.pos;
+        let leaf = make_mut(&mut self.leaf).as_leaf_mut().unwrap();
+        let removed = (leaf.keys.remove(pos), leaf.values.remove(pos));
+        let underfull = leaf.is_underfull();
+        self.update_counts(|len| *len -= 1);
+        if underfull && !self.path.is_empty() {
+            // Entries will move between the leaf and its siblings
+            let path: Vec<usize> = self.path.iter().map(|(_, idx)| *idx).collect();
+            self.release();
+            let hooks = self.tree.hooks.clone();
+            hooks::scoped(hooks, || {
+                rebalance_path(make_mut(&mut self.tree.root), &path);
+                self.tree.collapse_root();
+            });
+            #[cfg(feature = "verify")]
+            self.tree.verify("remove_current", &removed.0);
+            self.seek(Bound::Excluded(&removed.0));
+        } else {
+            #[cfg(feature = "verify")]
+            {
+                self.attach_from(0);
+                self.tree.verify("remove_current", &removed.0);
+            }
+            self.settle();
+        }
+        Some(removed)
+    }
+
+    /// Insert an entry before the current one, or at the end if past the
+    /// last entry, and stay on the current entry. Fails with
+    /// `Error::InvalidInput` if `key` doesn't sort strictly between the
+    /// previous entry and the current one.
+    pub fn insert_before(&mut self, key: K, val: V) -> error::Result<()> {
+        let out_of_order = || {
+            Error::InvalidInput(format!(
+                "{:?} does not sort between the neighbours of the cursor",
+                key
+            ))
+        };
+        if self.key().is_some_and(|current| key >= *current) {
+            return Err(out_of_order());
+        }
+        // Whether the key belongs in this leaf rather than the previous one
+        let in_leaf = match self.pos {
+            0 => match self.leaf_lower_bound() {
+                Some(pivot) => *pivot <= key,
+                None => true,
+            },
+            pos => {
+                if self.leaf().keys[pos - 1] >= key {
+                    return Err(out_of_order());
+                }
+                true
+            }
+        };
+        if !in_leaf {
+            // At the start of the leaf, the previous entry is in another one
+            let current = self.key().unwrap().clone();
+            self.attach_from(0);
+            if self
+                .tree
+                .predecessor(&current)
+                .is_some_and(|(previous, _)| previous >= key)
+            {
+                return Err(out_of_order());
+            }
+        }
+        if in_leaf && !self.leaf().is_full() {
+            self.detach();
+            let pos = self.pos;
+            let leaf = make_mut(&mut self.leaf).as_leaf_mut().unwrap();
+            leaf.keys.insert(pos, key.clone());
+            leaf.values.insert(pos, val);
+            self.update_counts(|len| *len += 1);
+            self.pos += 1;
+            #[cfg(feature = "verify")]
+            {
+                self.attach_from(0);
+                self.tree.verify("insert_before", &key);
+            }
+        } else {
+            self.release();
+            self.tree.insert(key.clone(), val);
+            self.seek(Bound::Excluded(&key));
+        }
+        Ok(())
+    }
+
+    // Position the cursor at the first entry after `start`
+    fn seek(&mut self, start: Bound<&K>) {
+        self.release();
+        let mut node = self.tree.root.clone();
+        while let Some(internal) = node.as_internal() {
+            let idx = match start {
+                Bound::Included(key) | Bound::Excluded(key) => internal.child_idx(key),
+                Bound::Unbounded => 0,
+            };
+            let child = internal.children[idx].clone();
+            self.path.push((node, idx));
+            node = child;
+        }
+        self.pos = node
+            .as_leaf()
+            .unwrap()
+            .keys
+            .partition_point(|k| match start {
+                Bound::Included(key) => k < key,
+                Bound::Excluded(key) => k <= key,
+                Bound::Unbounded => false,
+            });
+        self.leaf = node;
+        self.settle();
+    }
+
+    // Past the end of a leaf, move to the start of the next one if any
+    fn settle(&mut self) {
+        if self.pos < self.leaf().keys.len() {
+            return;
+        }
+        // The deepest ancestor with a child right of the path
+        let Some(level) = self
+            .path
+            .iter()
+            .rposition(|(node, idx)| idx + 1 < node.as_internal().unwrap().children.len())
+        else {
+            return;
+        };
+        self.attach_from(level + 1);
+        self.path.truncate(level + 1);
+        self.path[level].1 += 1;
+        let (parent, idx) = &self.path[level];
+        let mut node = parent.as_internal().unwrap().children[*idx].clone();
+        while let Some(internal) = node.as_internal() {
+            let child = internal.children[0].clone();
+            self.path.push((node, 0));
+            node = child;
+        }
+        self.leaf = node;
+        self.pos = 0;
+    }
+
+    fn leaf(&self) -> &LeafNode<K, V, LEAF> {
+        self.leaf.as_leaf().unwrap()
+    }
+
+    // Take the levels of the path not taken out yet out of the tree, making
+    // each node writable: once its parent no longer holds it, only the
+    // cursor and the snapshots that share it do
+    fn detach(&mut self) {
+        while self.detached <= self.path.len() {
+            let level = self.detached;
+            let placeholder = self.placeholder.clone();
+            let slot = match level {
+                0 => &mut self.tree.root,
+                _ => {
+                    let (parent, idx) = &mut self.path[level - 1];
+                    &mut make_mut(parent).as_internal_mut().unwrap().children[*idx]
+                }
+            };
+            drop(std::mem::replace(slot, placeholder));
+            match self.path.get_mut(level) {
+                Some((node, _)) => make_mut(node),
+                None => make_mut(&mut self.leaf),
+            };
+            self.detached += 1;
+        }
+    }
+
+    // Put the levels from `level` down back into the tree, the cursor keeping
+    // clones of their pointers
+    fn attach_from(&mut self, level: usize) {
+        while self.detached > level {
+            let level = self.detached - 1;
+            let node = match self.path.get(level) {
+                Some((node, _)) => node.clone(),
+                None => self.leaf.clone(),
+            };
+            match level {
+                0 => self.tree.root = node,
+                _ => {
+                    let (parent, idx) = &mut self.path[level - 1];
+                    make_mut(parent).as_internal_mut().unwrap().children[*idx] = node;
+                }
+            }
+            self.detached -= 1;
+        }
+    }
+
+    // Put the path back and let go of it, so that the tree can be written
+    // without copying the nodes the cursor would share
+    fn release(&mut self) {
+        self.attach_from(0);
+        self.path.clear();
+        self.leaf = self.placeholder.clone();
+    }
+
+    // Call `update` on the entry count of each ancestor, once detached
+    fn update_counts(&mut self, update: impl Fn(&mut usize)) {
+        for (node, _) in &mut self.path {
+            update(&mut make_mut(node).as_internal_mut().unwrap().len);
+        }
+    }
+
+    // The pivot before the leaf, which no previous entry reaches; `None` for
+    // the first leaf
+    fn leaf_lower_bound(&self) -> Option<&K> {
+        let mut bound = None;
+        for (node, idx) in &self.path {
+            if *idx > 0 {
+                bound = Some(&node.as_internal().unwrap().pivots[idx - 1]);
+            }
+        }
+        bound
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> Drop for CursorMut<'_, K, V, LEAF> {
+    fn drop(&mut self) {
+        self.attach_from(0);
+    }
+}
+
+// Rebalance the nodes `path` goes through, from the bottom up, after a
+// removal left the leaf it leads to underfull
+fn rebalance_path<K: TreeKey, V: TreeValue, const LEAF: usize>(
+    node: &mut dyn Node<K, V, LEAF>,
+    path: &[usize],
+) {
+    if let Some((&idx, rest)) = path.split_first() {
+        let internal = node.as_internal_mut().unwrap();
+        rebalance_path(make_mut(&mut internal.children[idx]), rest);
+        internal.rebalance_child(idx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_mut() {
+        let mut btree = BTree::new();
+        let mut reference = std::collections::BTreeMap::new();
+        for k in 0..5000 {
+            btree.insert([k; 1], (k % 7) as Value);
+            reference.insert([k; 1], (k % 7) as Value);
+        }
+        let snapshot = btree.share();
+
+        // Reading doesn't copy what the snapshot shares, and the first write
+        // copies the path to the leaf only
+        let height = btree.stats().height as u64;
+        let (_, metrics) = metrics::measure(|| {
+            let mut cursor = btree.cursor_mut(Bound::Unbounded);
+            while cursor.key().is_some() {
+                cursor.move_next();
+            }
+        });
+        assert_eq!(metrics.nodes_copied, 0);
+        let (_, metrics) = metrics::measure(|| {
+            let mut cursor = btree.cursor_mut(Bound::Included(&[2500; 1]));
+            *cursor.value_mut().unwrap() += 0;
+            cursor.move_next();
+            *cursor.value_mut().unwrap() += 0;
+        });
+        assert_eq!(metrics.nodes_copied, height);
+
+        // Walk a range, removing some entries and updating others
+        let mut cursor = btree.cursor_mut(Bound::Included(&[1000; 1]));
+        while cursor.key().is_some_and(|k| k[0] < 4000) {
+            if *cursor.value().unwrap() < 3 {
+                let (key, _) = cursor.remove_current().unwrap();
+                reference.remove(&key);
+            } else {
+                *cursor.value_mut().unwrap() += 10;
+                *reference.get_mut(cursor.key().unwrap()).unwrap() += 10;
+                cursor.move_next();
+            }
+        }
+        assert_eq!(cursor.key(), Some(&[4000; 1]));
+        // Dropping the cursor puts its path back into the tree
+        drop(cursor);
+        btree.check_invariants().unwrap();
+        assert!(btree.iter().eq(reference.iter().map(|(k, v)| (*k, *v))));
+        assert_eq!(snapshot.len(), 5000);
+        assert_eq!(snapshot.get(&[1003; 1]), Some(2));
+
+        // Fill the gaps back in, then append past the end
+        let mut cursor = btree.cursor_mut(Bound::Unbounded);
+        for k in 0..5000 {
+            if cursor.key() == Some(&[k; 1]) {
+                cursor.move_next();
+            } else {
+                cursor.insert_before([k; 1], 0).unwrap();
+                reference.insert([k; 1], 0);
+            }
+        }
+        assert_eq!(cursor.key(), None);
+        cursor.insert_before([6000; 1], 1).unwrap();
+        assert!(cursor.insert_before([5500; 1], 1).is_err());
+        reference.insert([6000; 1], 1);
+        drop(cursor);
+        btree.check_invariants().unwrap();
+        assert_eq!(btree.len(), 5001);
+        assert!(btree.iter().eq(reference.iter().map(|(k, v)| (*k, *v))));
+
+        // Keys that don't sort before the current entry and after the
+        // previous one are refused
+        let mut cursor = btree.cursor_mut(Bound::Excluded(&[10; 1]));
+        assert_eq!(cursor.key(), Some(&[11; 1]));
+        assert!(cursor.insert_before([11; 1], 0).is_err());
+        assert!(cursor.insert_before([10; 1], 0).is_err());
+        assert_eq!(cursor.remove_current(), Some(([11; 1], 4)));
+        assert!(cursor.insert_before([11; 1], 0).is_ok());
+        assert_eq!(cursor.key(), Some(&[12; 1]));
+        drop(cursor);
+
+        // Removing every entry empties the tree level by level
+        let mut cursor = btree.cursor_mut(Bound::Unbounded);
+        while cursor.remove_current().is_some() {}
+        drop(cursor);
+        assert!(btree.is_empty());
+        assert_eq!(btree.stats().height, 1);
+        btree.check_invariants().unwrap();
+    }
+}
@@ -0,0 +1,274 @@
+//! How the entries spread over the key space: counts per key prefix, or
+//! between chosen boundaries, to pick shard split points or find the
+//! prefixes (tenants) holding most of the entries. The size of a single range
+//! comes from the entry counts of the internal nodes, without a scan.
+use super::*;
+use std::ops::{Bound, RangeBounds};
+
+/// Size of a key range as reported by `BTree::estimate_range_size`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RangeSize {
+    pub entries: usize,
+    /// Bytes of the keys and values, the node overhead and what they own on
+    /// the heap being left out
+    pub bytes: usize,
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> BTree<K, V, LEAF> {
+    /// Number of entries within `range`, in O(height * fanout): the children
+    /// entirely within the range are counted from their entry count, only
+    /// the nodes holding its bounds are searched. A range whose start is
+    /// after its end holds no entries.
+    pub fn count_range<R: RangeBounds<K>>(&self, range: R) -> usize {
+        // Entries before the range, then up to its end
+        let start = match range.start_bound() {
+            Bound::Included(key) => rank(&*self.root, key, false),
+            Bound::Excluded(key) => rank(&*self.root, key, true),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => rank(&*self.root, key, true),
+            Bound::Excluded(key) => rank(&*self.root, key, false),
+            Bound::Unbounded => self.len(),
+        };
+        end.saturating_sub(start)
+    }
+
+    /// Number of entries with a key below `key`, whether `key` is in the tree
+    /// or not: the position it has or would have in key order. O(height *
+    /// fanout), like `count_range`.
+    pub fn rank<Q: Ord + ?Sized>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+    {
+        rank(&*self.root, key, false)
+    }
+
+    /// Entry at position `n` in key order, the smallest being at 0, e.g. the
+    /// `len() / 2`-th for the median. Whole subtrees are stepped over by
+    /// their entry count, in O(height * fanout).
+    pub fn select(&self, n: usize) -> Option<(K, V)> {
+        self.iter().nth(n)
+    }
+
+    /// Number of entries within `range`, as counted by `count_range`, and
+    /// the bytes they take
+    pub fn estimate_range_size<R: RangeBounds<K>>(&self, range: R) -> RangeSize {
+        let entries = self.count_range(range);
+        RangeSize {
+            entries,
+            bytes: entries * (std::mem::size_of::<K>() + std::mem::size_of::<V>()),
+        }
+    }
+}
+
+impl BTree {
+    /// Number of entries for each value of the first `bits` bits of the keys,
+    /// in ascending prefix order, leaving out the prefixes without entries.
+    /// Walks every entry. Fails with `Error::InvalidInput` if `bits` is over
+    /// 128.
+    pub fn prefix_counts(&self, bits: u32) -> error::Result<Vec<(u128, usize)>> {
+        if bits > u128::BITS {
+            return Err(Error::InvalidInput(format!(
+                "prefixes are at most 128 bits long, not {}",
+                bits
+            )));
+        }
+        let mut counts: Vec<(u128, usize)> = Vec::new();
+        for (key, _) in self.iter() {
+            let prefix = key[0].checked_shr(u128::BITS - bits).unwrap_or(0);
+            match counts.last_mut() {
+                Some((last, count)) if *last == prefix => *count += 1,
+                _ => counts.push((prefix, 1)),
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Number of entries in each of the ranges delimited by `boundaries`,
+    /// which must be ascending: `..boundaries[0]`, `boundaries[0]..boundaries[1]`
+    /// and so on up to `boundaries[n - 1]..`, so n + 1 counts. Walks every
+    /// entry. Fails with `Error::InvalidInput` if the boundaries are not
+    /// ascending.
+    pub fn boundary_counts(&self, boundaries: &[Key]) -> error::Result<Vec<usize>> {
+        if let Some(idx) = boundaries.windows(2).position(|w| w[0] > w[1]) {
+            return Err(Error::InvalidInput(format!(
+                "boundaries must be ascending, {:?} comes after {:?}",
+                boundaries[idx + 1],
+                boundaries[idx]
+            )));
+        }
+        let mut counts = vec![0; boundaries.len() + 1];
+        let mut idx = 0;
+        for (key, _) in self.iter() {
+            while idx < boundaries.len() && key >= boundaries[idx] {
+                idx += 1;
+            }
+            counts[idx] += 1;
+        }
+        Ok(counts)
+    }
+}
+
+// Number of entries of the subtree below `key`, or up to it if `inclusive`
+pub(super) fn rank<K, V, Q, const LEAF: usize>(
+    mut node: &dyn Node<K, V, LEAF>,
+    key: &Q,
+    inclusive: bool,
+) -> usize
+where
+    K: TreeKey + Borrow<Q>,
+    V: TreeValue,
+    Q: Ord + ?Sized,
+{
+    let mut rank = 0;
+    while let Some(internal) = node.as_internal() {
+        let idx = internal.child_idx(key);
+        rank += internal.children[..idx]
+            .iter()
+            .map(|child| child.total_len())
+            .sum::<usize>();
+        node = &*internal.children[idx];
+    }
+    let keys = &node.as_leaf().unwrap().keys;
+    rank + keys.partition_point(|k| k.borrow() < key || (inclusive && k.borrow() == key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_and_boundary_counts() {
+        let mut tree = BTree::new();
+        // 3 tenants in the top byte, with 10, 500 and 3 entries
+        for (tenant, n) in [(1u128, 10), (7, 500), (200, 3)] {
+            (0..n).for_each(|k| {
+                tree.insert([tenant << 120 | k; 1], 0);
+            });
+        }
+        assert_eq!(
+            tree.prefix_counts(8).unwrap(),
+            vec![(1, 10), (7, 500), (200, 3)]
+        );
+        assert_eq!(tree.prefix_counts(0).unwrap(), vec![(0, 513)]);
+        assert_eq!(tree.prefix_counts(128).unwrap().len(), 513);
+        assert_eq!(BTree::new().prefix_counts(8).unwrap(), vec![]);
+        assert!(matches!(
+            tree.prefix_counts(129),
+            Err(Error::InvalidInput(_))
+        ));
+
+        let boundaries = [[7 << 120; 1], [7 << 120 | 100; 1], [255 << 120; 1]];
+        assert_eq!(
+            tree.boundary_counts(&boundaries).unwrap(),
+            vec![10, 100, 403, 0]
+        );
+        assert_eq!(tree.boundary_counts(&[]).unwrap(), vec![513]);
+        assert!(matches!(
+            tree.boundary_counts(&[[2; 1], [1; 1]]),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_count_range() {
+        let mut tree: BTree<u64, ()> = BTree::new();
+        (0..10_000).for_each(|k| {
+            tree.insert(k * 2, ());
+        });
+        tree.delete_range(5000..7000);
+        let reference: Vec<u64> = tree.keys().copied().collect();
+        let count = |range: (Bound<u64>, Bound<u64>)| {
+            reference.iter().filter(|k| range.contains(k)).count()
+        };
+        let bounds = [0, 1, 2, 4999, 5000, 7000, 7001, 12_345, 19_998, 30_000];
+        for lo in bounds {
+            for hi in bounds {
+                for range in [
+                    (Bound::Included(lo), Bound::Excluded(hi)),
+                    (Bound::Excluded(lo), Bound::Included(hi)),
+                    (Bound::Unbounded, Bound::Included(hi)),
+                    (Bound::Excluded(lo), Bound::Unbounded),
+                ] {
+                    assert_eq!(tree.count_range(range), count(range), "{:?}", range);
+                }
+            }
+        }
+        assert_eq!(tree.count_range(..), 9000);
+        assert_eq!(tree.share().count_range(5000..=7000), 1);
+    }
+
+    #[test]
+    fn test_rank_select() {
+        let mut tree: BTree<u64, u64> = BTree::new();
+        (0..5000).for_each(|k| {
+            tree.insert(k * 3, k);
+        });
+        tree.retain(|k, _| k % 2 == 0);
+        let keys: Vec<u64> = tree.keys().copied().collect();
+        for (n, key) in keys.iter().enumerate() {
+            assert_eq!(tree.rank(key), n);
+            assert_eq!(tree.rank(&(key + 1)), n + 1);
+            assert_eq!(tree.select(n), Some((*key, key / 3)));
+        }
+        assert_eq!(tree.rank(&0), 0);
+        assert_eq!(tree.rank(&u64::MAX), tree.len());
+        assert_eq!(tree.select(tree.len()), None);
+
+        // Percentiles, and the position of a page of results
+        assert_eq!(tree.select(tree.len() / 2), Some((7500, 2500)));
+        let snapshot = tree.share();
+        let page = snapshot.rank(&3000)..snapshot.rank(&3000) + 10;
+        assert_eq!(snapshot.select(page.start).map(|(k, _)| k), Some(3000));
+        assert_eq!(
+            tree.iter().skip(page.start).take(page.len()).last(),
+            snapshot.select(page.end - 1)
+        );
+    }
+
+    #[test]
+    fn test_estimate_range_size() {
+        let mut tree: BTree = BTree::new();
+        (0..10_000).for_each(|k| {
+            tree.insert([k * 2; 1], 0);
+        });
+        (0..10_000).step_by(3).for_each(|k| {
+            tree.delete(&[k * 2; 1]);
+        });
+        let count = |lo: u128, hi: u128| {
+            tree.iter()
+                .filter(|(k, _)| (lo..hi).contains(&k[0]))
+                .count()
+        };
+
+        for (lo, hi) in [
+            (0, 20_000),
+            (1, 7),
+            (100, 101),
+            (5000, 15_001),
+            (30_000, 40_000),
+        ] {
+            assert_eq!(
+                tree.estimate_range_size([lo; 1]..[hi; 1]).entries,
+                count(lo, hi)
+            );
+        }
+        assert_eq!(tree.estimate_range_size([2; 1]..=[4; 1]).entries, 2);
+        assert_eq!(tree.estimate_range_size(..[4; 1]).entries, 1);
+        assert_eq!(tree.estimate_range_size(..).entries, tree.total_len());
+        assert_eq!(
+            tree.estimate_range_size((Bound::Excluded([2; 1]), Bound::Unbounded))
+                .entries,
+            tree.total_len() - 1
+        );
+        assert_eq!(tree.estimate_range_size([10; 1]..[2; 1]).entries, 0);
+        assert_eq!(tree.estimate_range_size(..[2; 1]).bytes, 0);
+        assert_eq!(
+            tree.estimate_range_size(..=[2; 1]).bytes,
+            std::mem::size_of::<Key>() + std::mem::size_of::<Value>()
+        );
+        let offsets: BTree<u64, u64> = (0..100).map(|k| (k, k)).collect();
+        assert_eq!(offsets.estimate_range_size(10..20).bytes, 160);
+    }
+}
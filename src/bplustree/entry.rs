@@ -0,0 +1,200 @@
+//! `BTreeMap`-style entries: a single descent finds the place of a key, and
+//! the entry then reads, updates or inserts the value there. The descent is
+//! the one of `insert`: nodes shared with a snapshot are copied and full
+//! nodes split on the way down, so the leaf reached always has room for the
+//! key. The entry counts of the nodes on the path are borrowed along with
+//! the leaf, and only bumped if a new key is inserted.
+use super::*;
+
+/// View of a key of the tree, occupied or not, returned by `BTree::entry`
+// The vacant entry holds the counts of the path inline, so that taking an
+// entry never allocates
+#[allow(clippy::large_enum_variant)]
+pub enum Entry<'a, K = Key, V = Value, const LEAF: usize = LEAF_ITEMS_SIZE> {
+    Occupied(OccupiedEntry<'a, K, V, LEAF>),
+    Vacant(VacantEntry<'a, K, V, LEAF>),
+}
+
+/// Entry of a key present in the tree
+pub struct OccupiedEntry<'a, K = Key, V = Value, const LEAF: usize = LEAF_ITEMS_SIZE> {
+    leaf: &'a mut LeafNode<K, V, LEAF>,
+    idx: usize,
+}
+
+/// Entry of a key absent from the tree
+pub struct VacantEntry<'a, K = Key, V = Value, const LEAF: usize = LEAF_ITEMS_SIZE> {
+    key: K,
+    leaf: &'a mut LeafNode<K, V, LEAF>,
+    idx: usize,
+    // Entry counts of the internal nodes on the path to the leaf
+    counts: ArrayVec<&'a mut usize, MAX_HEIGHT>,
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> BTree<K, V, LEAF> {
+    /// Entry of `key`, to read, update or insert its value with a single
+    /// descent
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, LEAF> {
+        let policy = self.split_policy;
+        let hooks = self.hooks.clone();
+        hooks::scoped(hooks, move || {
+            self.split_root_if_full(&key);
+            let mut counts = ArrayVec::new();
+            let mut node = make_mut(&mut self.root);
+            while node.as_internal().is_some() {
+                cost!(nodes_visited);
+                let internal = node.as_internal_mut().unwrap();
+                let mut idx = internal.child_idx(&key);
+                internal.try_split(idx, policy, &key);
+                if idx < internal.pivots.len() && key >= internal.pivots[idx] {
+                    idx += 1; // Might be in right sibling
+                }
+                let InternalNode { len, children, .. } = internal;
+                counts.push(len);
+                node = make_mut(&mut children[idx]);
+            }
+            cost!(nodes_visited);
+            let leaf = node.as_leaf_mut().unwrap();
+            match search(&leaf.keys, &key) {
+                Ok(idx) => Entry::Occupied(OccupiedEntry { leaf, idx }),
+                Err(idx) => Entry::Vacant(VacantEntry {
+                    key,
+                    leaf,
+                    idx,
+                    counts,
+                }),
+            }
+        })
+    }
+}
+
+impl<'a, K: TreeKey, V: TreeValue, const LEAF: usize> Entry<'a, K, V, LEAF> {
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// The value of the key, inserting `default` first if it is absent
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        self.or_insert_with(|| default)
+    }
+
+    /// The value of the key, inserting the result of `default` first if it is
+    /// absent
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Call `f` on the value if the key is present
+    pub fn and_modify<F: FnOnce(&mut V)>(mut self, f: F) -> Self {
+        if let Entry::Occupied(entry) = &mut self {
+            f(entry.get_mut());
+        }
+        self
+    }
+}
+
+impl<'a, K: TreeKey, V: TreeValue + Default, const LEAF: usize> Entry<'a, K, V, LEAF> {
+    pub fn or_default(self) -> &'a mut V {
+        self.or_insert_with(V::default)
+    }
+}
+
+impl<'a, K: TreeKey, V: TreeValue, const LEAF: usize> OccupiedEntry<'a, K, V, LEAF> {
+    pub fn key(&self) -> &K {
+        &self.leaf.keys[self.idx]
+    }
+
+    pub fn get(&self) -> &V {
+        &self.leaf.values[self.idx]
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.leaf.values[self.idx]
+    }
+
+    /// The value, borrowed for as long as the entry was
+    pub fn into_mut(self) -> &'a mut V {
+        &mut self.leaf.values[self.idx]
+    }
+
+    /// Replace the value, returning the previous one
+    pub fn insert(&mut self, val: V) -> V {
+        std::mem::replace(self.get_mut(), val)
+    }
+}
+
+impl<'a, K: TreeKey, V: TreeValue, const LEAF: usize> VacantEntry<'a, K, V, LEAF> {
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Insert the key with `val`, returning the value in the tree
+    pub fn insert(self, val: V) -> &'a mut V {
+        self.counts.into_iter().for_each(|count| *count += 1);
+        self.leaf.keys.insert(self.idx, self.key);
+        self.leaf.values.insert(self.idx, val);
+        // The rest of the tree is borrowed through the leaf and the counts,
+        // and checked by the next mutation
+        #[cfg(feature = "verify")]
+        if let Err(e) = self.leaf.check(None, None, false) {
+            panic!(
+                "invariant violated after entry insert of {:?}: {}",
+                self.leaf.keys[self.idx], e
+            );
+        }
+        &mut self.leaf.values[self.idx]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry() {
+        let mut tree: BTree<u64, u64> = BTree::new();
+        // Word-count style read-modify-write
+        for k in (0..5000).chain(0..1000).chain(0..10) {
+            *tree.entry(k).or_insert(0) += 1;
+        }
+        tree.check_invariants().unwrap();
+        assert_eq!(tree.len(), 5000);
+        assert_eq!(tree.get(&5), Some(3));
+        assert_eq!(tree.get(&500), Some(2));
+        assert_eq!(tree.get(&4999), Some(1));
+
+        tree.entry(7).and_modify(|v| *v = 100).or_default();
+        tree.entry(9000).and_modify(|v| *v = 100).or_default();
+        assert_eq!(tree.get(&7), Some(100));
+        assert_eq!(tree.get(&9000), Some(0));
+        match tree.entry(7) {
+            Entry::Occupied(mut entry) => assert_eq!(entry.insert(1), 100),
+            Entry::Vacant(_) => panic!("7 is in the tree"),
+        }
+        assert_eq!(tree.get(&7), Some(1));
+
+        // A vacant entry dropped without inserting leaves the counts alone
+        assert_eq!(tree.entry(6000).key(), &6000);
+        tree.check_invariants().unwrap();
+        assert_eq!(tree.len(), 5001);
+
+        // Entries copy the nodes they write that a snapshot shares
+        let snapshot = tree.share();
+        *tree.entry(0).or_insert(0) += 10;
+        tree.entry(10_000).or_insert(1);
+        assert_eq!(snapshot.get(&0), Some(3));
+        assert_eq!(snapshot.get(&10_000), None);
+        assert_eq!(tree.get(&0), Some(13));
+        assert_eq!(tree.len(), 5002);
+        tree.check_invariants().unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+//! CSB+ layout (cache-sensitive B+tree): an internal node holds its pivots and
+//! the index of its first child only, its children being contiguous in the
+//! level below. Without a pointer per child, a node of `NODE_SIZE` bytes
+//! holds about twice as many pivots as a node of the writable tree, which
+//! makes the tree shallower and each level a single cache-aligned block.
+use super::*;
+
+const CSB_PIVOTS: usize = (NODE_SIZE - 8) / std::mem::size_of::<Key>();
+const CSB_FANOUT: usize = CSB_PIVOTS + 1;
+
+#[derive(Clone, Debug)]
+#[repr(align(64))]
+struct CsbNode {
+    pivots: [Key; CSB_PIVOTS],
+    len: u32,
+    first_child: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub(super) struct CsbIndex {
+    // Internal levels from the root down
+    levels: Vec<Vec<CsbNode>>,
+}
+
+impl CsbIndex {
+    pub(super) fn build(mut fences: Vec<Key>) -> CsbIndex {
+        let mut levels = Vec::new();
+        while fences.len() > 1 {
+            let level = fences
+                .chunks(CSB_FANOUT)
+                .enumerate()
+                .map(|(i, children)| {
+                    let mut node = CsbNode {
+                        pivots: [[0; 1]; CSB_PIVOTS],
+                        len: (children.len() - 1) as u32,
+                        first_child: (i * CSB_FANOUT) as u32,
+                    };
+                    node.pivots[..children.len() - 1].copy_from_slice(&children[1..]);
+                    node
+                })
+                .collect();
+            levels.push(level);
+            fences = fences.iter().step_by(CSB_FANOUT).copied().collect();
+        }
+        levels.reverse();
+        CsbIndex { levels }
+    }
+
+    pub(super) fn leaf(&self, key: &Key) -> usize {
+        let mut node = 0;
+        for level in &self.levels {
+            let n = &level[node];
+            node =
+                n.first_child as usize + n.pivots[..n.len as usize].partition_point(|p| p <= key);
+        }
+        node
+    }
+
+    pub(super) fn depth(&self) -> usize {
+        self.levels.len()
+    }
+}
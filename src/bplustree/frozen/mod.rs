@@ -0,0 +1,170 @@
+//! Compact read-only form of a tree, for data that is loaded once and never
+//! written again. Entries are stored in two flat sorted arrays, cut into
+//! implicit leaves of `LEAF_ITEMS_SIZE` entries. The internal levels leading
+//! to a leaf are laid out according to the `FrozenLayout` picked when
+//! freezing. Nodes are full (but the last of each level) and there are no
+//! reference counts to maintain.
+use super::*;
+use std::iter::{Copied, Zip};
+use std::slice;
+
+mod csb;
+mod sorted;
+mod veb;
+
+use csb::CsbIndex;
+use sorted::SortedIndex;
+use veb::VebIndex;
+
+/// Layout of the internal levels of a frozen tree
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FrozenLayout {
+    /// Arrays of first keys grouped by the fanout of the writable tree, with
+    /// children found by position
+    #[default]
+    Sorted,
+    /// CSB+ nodes of `NODE_SIZE` bytes, holding pivots and a single child
+    /// index, for a higher fanout
+    Csb,
+    /// Binary search tree in van Emde Boas order, efficient at every level of
+    /// the memory hierarchy without depending on `NODE_SIZE`
+    Veb,
+}
+
+#[derive(Clone, Debug)]
+enum Index {
+    Sorted(SortedIndex),
+    Csb(CsbIndex),
+    Veb(VebIndex),
+}
+
+impl Default for Index {
+    fn default() -> Index {
+        Index::Sorted(SortedIndex::default())
+    }
+}
+
+/// Immutable tree built by `BTree::freeze`
+#[derive(Clone, Debug, Default)]
+pub struct FrozenTree {
+    keys: Vec<Key>,
+    values: Vec<Value>,
+    index: Index,
+}
+
+pub type FrozenIter<'a> = Zip<Copied<slice::Iter<'a, Key>>, Copied<slice::Iter<'a, Value>>>;
+
+impl FrozenTree {
+    // Entries must be sorted by strictly ascending key
+    pub(in crate::bplustree) fn from_sorted(
+        keys: Vec<Key>,
+        values: Vec<Value>,
+        layout: FrozenLayout,
+    ) -> FrozenTree {
+        // First key of every leaf
+        let fences = keys.iter().step_by(LEAF_ITEMS_SIZE).copied().collect();
+        let index = match layout {
+            FrozenLayout::Sorted => Index::Sorted(SortedIndex::build(fences)),
+            FrozenLayout::Csb => Index::Csb(CsbIndex::build(fences)),
+            FrozenLayout::Veb => Index::Veb(VebIndex::build(fences)),
+        };
+        FrozenTree {
+            keys,
+            values,
+            index,
+        }
+    }
+
+    pub fn get(&self, key: &Key) -> Option<Value> {
+        let leaf = match &self.index {
+            Index::Sorted(index) => index.leaf(key),
+            Index::Csb(index) => index.leaf(key),
+            Index::Veb(index) => index.leaf(key),
+        };
+        let first = leaf * LEAF_ITEMS_SIZE;
+        let leaf = &self.keys[first..self.keys.len().min(first + LEAF_ITEMS_SIZE)];
+        leaf.binary_search(key)
+            .ok()
+            .map(|idx| self.values[first + idx])
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Iterate over all entries in ascending key order
+    pub fn iter(&self) -> FrozenIter<'_> {
+        self.keys.iter().copied().zip(self.values.iter().copied())
+    }
+
+    /// Number of levels, a lone leaf being height 1. The internal levels of
+    /// the vEB layout are binary.
+    pub fn height(&self) -> usize {
+        let depth = match &self.index {
+            Index::Sorted(index) => index.depth(),
+            Index::Csb(index) => index.depth(),
+            Index::Veb(index) => index.depth(),
+        };
+        depth + 1
+    }
+}
+
+impl<'a> IntoIterator for &'a FrozenTree {
+    type Item = (Key, Value);
+    type IntoIter = FrozenIter<'a>;
+
+    fn into_iter(self) -> FrozenIter<'a> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_freeze() {
+        for layout in [FrozenLayout::Sorted, FrozenLayout::Csb, FrozenLayout::Veb] {
+            check_freeze(layout);
+        }
+    }
+
+    fn check_freeze(layout: FrozenLayout) {
+        assert_eq!(BTree::new().freeze_with(layout).get(&[0; 1]), None);
+        assert_eq!(BTree::new().freeze_with(layout).height(), 1);
+
+        for n in [
+            1,
+            LEAF_ITEMS_SIZE,
+            LEAF_ITEMS_SIZE * CHILDREN_SIZE + 1,
+            10_000,
+        ] {
+            let mut tree = BTree::new();
+            (0..n as u128).for_each(|k| {
+                tree.insert([k * 2; 1], k as Value);
+            });
+            let expected: Vec<(Key, Value)> = tree.iter().collect();
+            let frozen = tree.freeze_with(layout);
+            assert_eq!(frozen.len(), n);
+            assert!(frozen.iter().eq(expected.iter().copied()));
+            for k in 0..n as u128 {
+                assert_eq!(frozen.get(&[k * 2; 1]), Some(k as Value));
+                assert_eq!(frozen.get(&[k * 2 + 1; 1]), None);
+            }
+        }
+
+        // The largest key compares equal to the padding of the vEB layout
+        let mut tree = BTree::new();
+        (0..1000).for_each(|k| {
+            tree.insert([u128::MAX - k; 1], 1);
+        });
+        let frozen = tree.freeze_with(layout);
+        assert_eq!(frozen.get(&[u128::MAX; 1]), Some(1));
+        assert_eq!(frozen.get(&[u128::MAX - 1000; 1]), None);
+        assert_eq!(frozen.get(&[0; 1]), None);
+    }
+}
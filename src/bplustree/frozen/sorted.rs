@@ -0,0 +1,39 @@
+//! Default layout: every internal level is an array of the first keys of the
+//! nodes below it, grouped by `CHILDREN_SIZE`. The children of node i are
+//! nodes `i * CHILDREN_SIZE..` of the level below, so there are no pointers.
+use super::*;
+
+#[derive(Clone, Debug, Default)]
+pub(super) struct SortedIndex {
+    // Internal levels from the root down. Entry i of a level is the first key
+    // of node i of the level below, the leaves being below the last level.
+    levels: Vec<Vec<Key>>,
+}
+
+impl SortedIndex {
+    pub(super) fn build(mut fences: Vec<Key>) -> SortedIndex {
+        let mut levels = Vec::new();
+        while fences.len() > 1 {
+            let parent = fences.iter().step_by(CHILDREN_SIZE).copied().collect();
+            levels.push(fences);
+            fences = parent;
+        }
+        levels.reverse();
+        SortedIndex { levels }
+    }
+
+    pub(super) fn leaf(&self, key: &Key) -> usize {
+        let mut node = 0;
+        for level in &self.levels {
+            let first = node * CHILDREN_SIZE;
+            let children = &level[first..level.len().min(first + CHILDREN_SIZE)];
+            // The first child is taken for any key, even below its first key
+            node = first + children[1..].partition_point(|k| k <= key);
+        }
+        node
+    }
+
+    pub(super) fn depth(&self) -> usize {
+        self.levels.len()
+    }
+}
@@ -0,0 +1,109 @@
+//! Van Emde Boas layout: the first keys of the leaves (but the first one)
+//! form a complete binary search tree, stored recursively as its top half followed by each of its
+//! bottom subtrees. Any subtree of height up to 2^k sits in a contiguous block,
+//! so a lookup touches O(log_B n) cache lines for every cache line size B at
+//! once, without tuning the node size to the machine.
+//!
+//! Positions are not stored: they are computed during the descent from
+//! per-depth tables, as described by Brodal, Fagerberg and Jacob in "Cache
+//! oblivious search trees via binary trees of small height".
+use super::*;
+
+// Stands in for the missing keys of the last, incomplete level
+const PADDING: Key = [u128::MAX; 1];
+
+#[derive(Clone, Debug, Default)]
+pub(super) struct VebIndex {
+    // Nodes of the binary tree in vEB order
+    nodes: Vec<Key>,
+    // Number of actual keys, the rest being padding
+    len: usize,
+    height: usize,
+    // Indexed by depth, the root being at depth 1: the size of the top tree
+    // ending just above that depth, the size of the bottom trees starting at
+    // it and the depth of the root of that top tree
+    top_size: Vec<usize>,
+    bottom_size: Vec<usize>,
+    top_depth: Vec<usize>,
+}
+
+impl VebIndex {
+    pub(super) fn build(fences: Vec<Key>) -> VebIndex {
+        // The first leaf is taken for any key, even below its first key
+        let fences = fences.get(1..).unwrap_or_default();
+        let height = (usize::BITS - fences.len().leading_zeros()) as usize;
+        let mut index = VebIndex {
+            nodes: vec![PADDING; (1 << height) - 1],
+            len: fences.len(),
+            height,
+            top_size: vec![0; height + 1],
+            bottom_size: vec![0; height + 1],
+            top_depth: vec![0; height + 1],
+        };
+        index.split(1, height);
+
+        // Walk the nodes in breadth-first order (1-based index i), computing
+        // each position from the one of the root of its top tree
+        let mut pos = vec![0; 1 << height];
+        for i in 2usize..1 << height {
+            let depth = (usize::BITS - i.leading_zeros()) as usize;
+            let ancestor = i >> (depth - index.top_depth[depth]);
+            pos[i] = pos[ancestor]
+                + index.top_size[depth]
+                + (i & index.top_size[depth]) * index.bottom_size[depth];
+        }
+        // The fences go to the nodes in in-order, padding the last ones
+        let mut rank = 0;
+        index.fill(1, &pos, fences, &mut rank);
+        index
+    }
+
+    // Record how the subtree of `height` levels rooted at `depth` is cut in
+    // a top tree and bottom trees, then recurse into both
+    fn split(&mut self, depth: usize, height: usize) {
+        if height <= 1 {
+            return;
+        }
+        let top = height / 2;
+        let bottom = height - top;
+        self.top_size[depth + top] = (1 << top) - 1;
+        self.bottom_size[depth + top] = (1 << bottom) - 1;
+        self.top_depth[depth + top] = depth;
+        self.split(depth, top);
+        self.split(depth + top, bottom);
+    }
+
+    // In-order walk of the breadth-first indices, handing out the fences
+    fn fill(&mut self, i: usize, pos: &[usize], fences: &[Key], rank: &mut usize) {
+        if i >= 1 << self.height {
+            return;
+        }
+        self.fill(2 * i, pos, fences, rank);
+        if let Some(fence) = fences.get(*rank) {
+            self.nodes[pos[i]] = *fence;
+        }
+        *rank += 1;
+        self.fill(2 * i + 1, pos, fences, rank);
+    }
+
+    pub(super) fn leaf(&self, key: &Key) -> usize {
+        let mut pos = [0; usize::BITS as usize + 1];
+        let mut i = 1;
+        for depth in 1..=self.height {
+            if depth > 1 {
+                pos[depth] = pos[self.top_depth[depth]]
+                    + self.top_size[depth]
+                    + (i & self.top_size[depth]) * self.bottom_size[depth];
+            }
+            i = 2 * i + (self.nodes[pos[depth]] <= *key) as usize;
+        }
+        // Below the last level, i counts the fences that are <= key, which is
+        // the index of the leaf. Padding only counts for the largest key,
+        // which also counts every fence.
+        (i - (1 << self.height)).min(self.len)
+    }
+
+    pub(super) fn depth(&self) -> usize {
+        self.height
+    }
+}
@@ -0,0 +1,149 @@
+//! Callbacks on the structural changes of a tree, for code that keeps its own
+//! per-subtree data aligned with the nodes. The nodes don't know which tree
+//! they belong to, so the hooks of the tree being written are made current on
+//! the thread for the duration of the write, the same way the metrics are
+//! credited to the thread doing the work.
+use super::*;
+use std::any::Any;
+use std::cell::RefCell;
+
+/// Structural change reported to the hooks registered with
+/// `BTree::on_structure_change`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StructureEvent<K = Key> {
+    /// A node split: the keys from `pivot` on moved to a new right sibling
+    Split { pivot: K, leaf: bool },
+    /// An underfull node and a sibling were merged into one: `pivot` no
+    /// longer separates two nodes
+    Merge { pivot: K },
+    /// An underfull node took an entry or a child from a sibling, moving the
+    /// boundary between them from `old_pivot` to `new_pivot`
+    Shift { old_pivot: K, new_pivot: K },
+    /// The tree grew a level (after a root split) or lost one
+    RootChange { height_grew: bool },
+}
+
+type Hook<K> = Arc<dyn Fn(&StructureEvent<K>) + Send + Sync>;
+
+pub(super) struct Hooks<K = Key>(Vec<Hook<K>>);
+
+// Not derived, which would require `K: Clone + Default`
+impl<K> Clone for Hooks<K> {
+    fn clone(&self) -> Self {
+        Hooks(self.0.clone())
+    }
+}
+
+impl<K> Default for Hooks<K> {
+    fn default() -> Self {
+        Hooks(Vec::new())
+    }
+}
+
+impl<K> Debug for Hooks<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} hooks", self.0.len())
+    }
+}
+
+// Type-erased as the thread may be writing trees of any key type, the
+// events being built with the key type of the tree
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<dyn Any + Send + Sync>>> = const { RefCell::new(None) };
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> BTree<K, V, LEAF> {
+    /// Call `hook` on every split, merge, shift and root change of this tree,
+    /// after it happened. Hooks run in the middle of the write and must not
+    /// expect the tree to be consistent yet. Clones of the tree, snapshots
+    /// included, keep the hooks registered so far.
+    pub fn on_structure_change<F>(&mut self, hook: F)
+    where
+        F: Fn(&StructureEvent<K>) + Send + Sync + 'static,
+    {
+        Arc::make_mut(self.hooks.get_or_insert_with(Default::default))
+            .0
+            .push(Arc::new(hook));
+    }
+}
+
+// Run `op` with `hooks` as the ones receiving the events of the thread
+pub(super) fn scoped<K: TreeKey, T>(hooks: Option<Arc<Hooks<K>>>, op: impl FnOnce() -> T) -> T {
+    struct Restore(Option<Arc<dyn Any + Send + Sync>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT.set(self.0.take());
+        }
+    }
+    let hooks = hooks.map(|hooks| hooks as Arc<dyn Any + Send + Sync>);
+    let _restore = Restore(CURRENT.replace(hooks));
+    op()
+}
+
+// Report the event built by `event`, only built if there are hooks
+pub(super) fn emit<K: TreeKey>(event: impl FnOnce() -> StructureEvent<K>) {
+    // Not borrowed while the hooks run, which may write to other trees
+    let Some(hooks) = CURRENT.with_borrow(|hooks| hooks.clone()) else {
+        return;
+    };
+    if let Ok(hooks) = hooks.downcast::<Hooks<K>>() {
+        let event = event();
+        hooks.0.iter().for_each(|hook| hook(&event));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_structure_events() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut tree = BTree::new();
+        let recorded = events.clone();
+        tree.on_structure_change(move |event| recorded.lock().unwrap().push(*event));
+
+        (0..LEAF_ITEMS_SIZE as u128).for_each(|k| {
+            tree.insert([k; 1], 0);
+        });
+        assert!(events.lock().unwrap().is_empty());
+        tree.insert([LEAF_ITEMS_SIZE as u128; 1], 0);
+        let pivot = [LEAF_ITEMS_SIZE as u128 / 2; 1];
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                StructureEvent::Split { pivot, leaf: true },
+                StructureEvent::RootChange { height_grew: true },
+            ]
+        );
+
+        // A tree without hooks written from a hook's thread reports nothing
+        let mut other = BTree::new();
+        (0..1000).for_each(|k| {
+            other.insert([k; 1], 0);
+        });
+        assert_eq!(events.lock().unwrap().len(), 2);
+
+        // The left leaf borrows from the right one until neither can spare
+        // an entry, then they are merged
+        events.lock().unwrap().clear();
+        (0..3).for_each(|k| assert!(tree.delete(&[k; 1]).is_some()));
+        let shifted = |k: u128| [pivot[0] + k; 1];
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                StructureEvent::Shift {
+                    old_pivot: pivot,
+                    new_pivot: shifted(1),
+                },
+                StructureEvent::Shift {
+                    old_pivot: shifted(1),
+                    new_pivot: shifted(2),
+                },
+                StructureEvent::Merge { pivot: shifted(2) },
+                StructureEvent::RootChange { height_grew: false },
+            ]
+        );
+    }
+}
@@ -0,0 +1,574 @@
+//! In-order iteration. The borrowing iterator keeps the path from the root
+//! to the current leaf in a fixed-size stack of node references, so a full
+//! scan performs no heap allocation. The owning
+//! iterator takes the nodes apart as it walks them.
+//!
+//! Both report their exact length, from the entry counts of the subtrees
+//! right of the current path; range scans subtract the entries after their
+//! end, counted the same way. The borrowing iterator also skips entries
+//! (`nth`, `step_by`) by stepping over whole subtrees, and range scans start
+//! with a descent to their first key. Range scans also run backwards: a
+//! second cursor, set up by the first `next_back`, walks down to the end of
+//! the range and then moves left.
+//!
+//! Leaves have no sibling links. Moving to the next leaf pops the path only
+//! up to the first ancestor with a child left, which is O(1) amortized over
+//! a scan, without re-descending from the root. Links would also defeat the
+//! copy-on-write: copying a leaf for a write would mean copying its
+//! neighbours to point to the copy, and theirs, down the whole chain.
+use super::*;
+use std::iter::Zip;
+use std::mem;
+use std::ops::{Bound, RangeBounds};
+
+/// Iterator over the entries of a tree in ascending key order
+pub struct Iter<'a, K = Key, V = Value, const LEAF: usize = LEAF_ITEMS_SIZE> {
+    // Ancestors of the current leaf, from the root down, each with the index
+    // of the child being visited
+    path: ArrayVec<(&'a InternalNode<K, V, LEAF>, usize), MAX_HEIGHT>,
+    leaf: Option<&'a LeafNode<K, V, LEAF>>,
+    pos: usize,
+}
+
+impl<'a, K: TreeKey, V: TreeValue, const LEAF: usize> Iter<'a, K, V, LEAF> {
+    pub(super) fn new(root: &'a NodePtr<K, V, LEAF>) -> Iter<'a, K, V, LEAF> {
+        let mut iter = Iter {
+            path: ArrayVec::new(),
+            leaf: None,
+            pos: 0,
+        };
+        iter.descend(&**root, 0);
+        iter
+    }
+
+    // Start at the first entry after `start`
+    pub(super) fn seek<Q: Ord + ?Sized>(
+        root: &'a NodePtr<K, V, LEAF>,
+        start: Bound<&Q>,
+    ) -> Iter<'a, K, V, LEAF>
+    where
+        K: Borrow<Q>,
+    {
+        let mut iter = Iter {
+            path: ArrayVec::new(),
+            leaf: None,
+            pos: 0,
+        };
+        let key = match start {
+            Bound::Included(key) | Bound::Excluded(key) => key,
+            Bound::Unbounded => {
+                iter.descend(&**root, 0);
+                return iter;
+            }
+        };
+        let mut node = &**root;
+        while let Some(internal) = node.as_internal() {
+            let idx = internal.child_idx(key);
+            iter.path.push((internal, idx));
+            node = &*internal.children[idx];
+        }
+        let leaf = node.as_leaf().unwrap();
+        iter.leaf = Some(leaf);
+        // Past the end of the leaf if every key is before the start, in which
+        // case `next` moves on to the next leaf
+        iter.pos = leaf.keys.partition_point(|k| match start {
+            Bound::Excluded(key) => k.borrow() <= key,
+            _ => k.borrow() < key,
+        });
+        iter
+    }
+
+    // Start after the last entry before `end`, to walk backwards
+    fn seek_back(root: &'a NodePtr<K, V, LEAF>, end: Bound<&K>) -> Iter<'a, K, V, LEAF> {
+        let mut iter = Iter {
+            path: ArrayVec::new(),
+            leaf: None,
+            pos: 0,
+        };
+        let key = match end {
+            Bound::Included(key) | Bound::Excluded(key) => key,
+            Bound::Unbounded => {
+                iter.descend_last(&**root);
+                return iter;
+            }
+        };
+        let mut node = &**root;
+        while let Some(internal) = node.as_internal() {
+            let idx = internal.child_idx(key);
+            iter.path.push((internal, idx));
+            node = &*internal.children[idx];
+        }
+        let leaf = node.as_leaf().unwrap();
+        iter.leaf = Some(leaf);
+        // At the start of the leaf if every key is after the end, in which
+        // case `peek_back_key` moves on to the previous leaf
+        iter.pos = leaf.keys.partition_point(|k| match end {
+            Bound::Included(key) => k <= key,
+            _ => k < key,
+        });
+        iter
+    }
+
+    // Key of the next entry, moving to the next leaf if needed
+    fn peek_key(&mut self) -> Option<&'a K> {
+        loop {
+            let leaf = self.leaf?;
+            if self.pos < leaf.keys.len() {
+                return Some(&leaf.keys[self.pos]);
+            }
+            self.next_leaf();
+        }
+    }
+
+    // Key of the entry before the position, moving to the previous leaf if
+    // needed
+    fn peek_back_key(&mut self) -> Option<&'a K> {
+        loop {
+            let leaf = self.leaf?;
+            if self.pos > 0 {
+                return Some(&leaf.keys[self.pos - 1]);
+            }
+            self.prev_leaf();
+        }
+    }
+
+    // Walk down the rightmost path of `node`, to after its last entry
+    fn descend_last(&mut self, mut node: &'a dyn Node<K, V, LEAF>) {
+        while let Some(internal) = node.as_internal() {
+            let idx = internal.children.len() - 1;
+            self.path.push((internal, idx));
+            node = &*internal.children[idx];
+        }
+        self.leaf = node.as_leaf();
+        self.pos = self.leaf.map_or(0, |leaf| leaf.keys.len());
+    }
+
+    // Move to the rightmost leaf of the previous subtree, if any
+    fn prev_leaf(&mut self) {
+        self.leaf = None;
+        while let Some((node, idx)) = self.path.pop() {
+            if idx > 0 {
+                self.path.push((node, idx - 1));
+                self.descend_last(&*node.children[idx - 1]);
+                return;
+            }
+        }
+    }
+
+    // Walk down to the entry `n` entries into the subtree of `node`, stepping
+    // over the children before it. `n` must be below the subtree length.
+    fn descend(&mut self, mut node: &'a dyn Node<K, V, LEAF>, mut n: usize) {
+        while let Some(internal) = node.as_internal() {
+            let mut idx = 0;
+            while idx + 1 < internal.children.len() && internal.children[idx].total_len() <= n {
+                n -= internal.children[idx].total_len();
+                idx += 1;
+            }
+            self.path.push((internal, idx));
+            node = &*internal.children[idx];
+        }
+        self.leaf = node.as_leaf();
+        self.pos = n;
+    }
+
+    // Move `n` entries forward without visiting them
+    fn skip(&mut self, mut n: usize) {
+        let left = self.leaf.map_or(0, |leaf| leaf.keys.len() - self.pos);
+        if n < left {
+            self.pos += n;
+            return;
+        }
+        n -= left;
+        self.leaf = None;
+        while let Some((node, idx)) = self.path.pop() {
+            for idx in idx + 1..node.children.len() {
+                let len = node.children[idx].total_len();
+                if n < len {
+                    self.path.push((node, idx));
+                    self.descend(&*node.children[idx], n);
+                    return;
+                }
+                n -= len;
+            }
+        }
+    }
+
+    // Entries left in the current leaf and in the subtrees right of the path
+    fn count_remaining(&self) -> usize {
+        let in_leaf = self.leaf.map_or(0, |leaf| leaf.keys.len() - self.pos);
+        let right: usize = self
+            .path
+            .iter()
+            .flat_map(|(node, idx)| &node.children[idx + 1..])
+            .map(|child| child.total_len())
+            .sum();
+        in_leaf + right
+    }
+
+    // Next entry, borrowed from its leaf
+    fn next_ref(&mut self) -> Option<(&'a K, &'a V)> {
+        loop {
+            let leaf = self.leaf?;
+            if self.pos < leaf.keys.len() {
+                self.pos += 1;
+                return Some((&leaf.keys[self.pos - 1], &leaf.values[self.pos - 1]));
+            }
+            self.next_leaf();
+        }
+    }
+
+    // Move to the leftmost leaf of the next subtree, if any
+    fn next_leaf(&mut self) {
+        self.leaf = None;
+        while let Some((node, idx)) = self.path.pop() {
+            if idx + 1 < node.children.len() {
+                self.path.push((node, idx + 1));
+                self.descend(&*node.children[idx + 1], 0);
+                return;
+            }
+        }
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> Iterator for Iter<'_, K, V, LEAF> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_ref().map(|(key, val)| (key.clone(), val.clone()))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.count_remaining();
+        (n, Some(n))
+    }
+
+    fn nth(&mut self, n: usize) -> Option<Self::Item> {
+        self.skip(n);
+        self.next()
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> ExactSizeIterator for Iter<'_, K, V, LEAF> {}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> std::iter::FusedIterator
+    for Iter<'_, K, V, LEAF>
+{
+}
+
+/// Iterator over the keys of a tree in ascending order, returned by
+/// `BTree::keys`. The keys are borrowed, and the values not read.
+pub struct Keys<'a, K = Key, V = Value, const LEAF: usize = LEAF_ITEMS_SIZE> {
+    pub(super) iter: Iter<'a, K, V, LEAF>,
+}
+
+impl<'a, K: TreeKey, V: TreeValue, const LEAF: usize> Iterator for Keys<'a, K, V, LEAF> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<&'a K> {
+        self.iter.next_ref().map(|(key, _)| key)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+
+    fn nth(&mut self, n: usize) -> Option<&'a K> {
+        Iter::skip(&mut self.iter, n);
+        self.next()
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> ExactSizeIterator for Keys<'_, K, V, LEAF> {}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> std::iter::FusedIterator
+    for Keys<'_, K, V, LEAF>
+{
+}
+
+/// Iterator over the values of a tree in ascending key order, returned by
+/// `BTree::values`. The values are borrowed, not cloned.
+pub struct Values<'a, K = Key, V = Value, const LEAF: usize = LEAF_ITEMS_SIZE> {
+    pub(super) iter: Iter<'a, K, V, LEAF>,
+}
+
+impl<'a, K: TreeKey, V: TreeValue, const LEAF: usize> Iterator for Values<'a, K, V, LEAF> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<&'a V> {
+        self.iter.next_ref().map(|(_, val)| val)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+
+    fn nth(&mut self, n: usize) -> Option<&'a V> {
+        Iter::skip(&mut self.iter, n);
+        self.next()
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> ExactSizeIterator for Values<'_, K, V, LEAF> {}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> std::iter::FusedIterator
+    for Values<'_, K, V, LEAF>
+{
+}
+
+/// Iterator over mutable references to the values of a tree, in ascending
+/// key order, returned by `BTree::values_mut`. The nodes that a snapshot
+/// still shares are copied as they are reached.
+pub struct ValuesMut<'a, K = Key, V = Value, const LEAF: usize = LEAF_ITEMS_SIZE> {
+    // Children left to visit of each ancestor of the current leaf, from the
+    // root down
+    path: Vec<std::slice::IterMut<'a, NodePtr<K, V, LEAF>>>,
+    leaf: std::slice::IterMut<'a, V>,
+    remaining: usize,
+}
+
+impl<'a, K: TreeKey, V: TreeValue, const LEAF: usize> ValuesMut<'a, K, V, LEAF> {
+    pub(super) fn new(root: &'a mut NodePtr<K, V, LEAF>) -> ValuesMut<'a, K, V, LEAF> {
+        let remaining = root.total_len();
+        let mut iter = ValuesMut {
+            path: Vec::new(),
+            leaf: [].iter_mut(),
+            remaining,
+        };
+        iter.descend(root);
+        iter
+    }
+
+    // Walk down the leftmost path of `node`, making each node writable
+    fn descend(&mut self, mut node: &'a mut NodePtr<K, V, LEAF>) {
+        loop {
+            let node_mut = make_mut(node);
+            if node_mut.as_leaf().is_some() {
+                self.leaf = node_mut.as_leaf_mut().unwrap().values.iter_mut();
+                return;
+            }
+            let mut children = node_mut.as_internal_mut().unwrap().children.iter_mut();
+            node = children.next().expect("internal node without children");
+            self.path.push(children);
+        }
+    }
+}
+
+impl<'a, K: TreeKey, V: TreeValue, const LEAF: usize> Iterator for ValuesMut<'a, K, V, LEAF> {
+    type Item = &'a mut V;
+
+    fn next(&mut self) -> Option<&'a mut V> {
+        loop {
+            if let Some(val) = self.leaf.next() {
+                self.remaining -= 1;
+                return Some(val);
+            }
+            let next = self.path.last_mut()?.next();
+            match next {
+                Some(child) => self.descend(child),
+                None => {
+                    self.path.pop();
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> ExactSizeIterator for ValuesMut<'_, K, V, LEAF> {}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> std::iter::FusedIterator
+    for ValuesMut<'_, K, V, LEAF>
+{
+}
+
+/// Iterator over the entries of a consumed tree in ascending key order
+pub struct IntoIter<K = Key, V = Value, const LEAF: usize = LEAF_ITEMS_SIZE> {
+    // Children left to visit of each ancestor of the current leaf, from the
+    // root down. The ancestors themselves are already freed.
+    path: Vec<arrayvec::IntoIter<NodePtr<K, V, LEAF>, CHILDREN_SIZE>>,
+    leaf: Zip<arrayvec::IntoIter<K, LEAF>, arrayvec::IntoIter<V, LEAF>>,
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> IntoIter<K, V, LEAF> {
+    pub(super) fn new(root: NodePtr<K, V, LEAF>) -> IntoIter<K, V, LEAF> {
+        let mut iter = IntoIter {
+            path: Vec::new(),
+            leaf: ArrayVec::new().into_iter().zip(ArrayVec::new()),
+        };
+        iter.descend(root);
+        iter
+    }
+
+    // Entries left in the current leaf and in the children not yet visited
+    fn count_remaining(&self) -> usize {
+        let right: usize = self
+            .path
+            .iter()
+            .flat_map(|children| children.as_slice())
+            .map(|child| child.total_len())
+            .sum();
+        self.leaf.len() + right
+    }
+
+    // Walk down the leftmost path of `node`, moving the children out of each
+    // internal node so that it is dropped as soon as we leave it
+    fn descend(&mut self, mut node: NodePtr<K, V, LEAF>) {
+        loop {
+            let node_mut = make_mut(&mut node);
+            if let Some(leaf) = node_mut.as_leaf_mut() {
+                let keys = mem::take(&mut leaf.keys);
+                let values = mem::take(&mut leaf.values);
+                self.leaf = keys.into_iter().zip(values);
+                return;
+            }
+            let internal = node_mut.as_internal_mut().unwrap();
+            let mut children = mem::take(&mut internal.children).into_iter();
+            let first = children.next().expect("internal node without children");
+            self.path.push(children);
+            node = first;
+        }
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> Iterator for IntoIter<K, V, LEAF> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.leaf.next() {
+                return Some(item);
+            }
+            let next = self.path.last_mut()?.next();
+            match next {
+                Some(child) => self.descend(child),
+                None => {
+                    self.path.pop();
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.count_remaining();
+        (n, Some(n))
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> ExactSizeIterator for IntoIter<K, V, LEAF> {}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> std::iter::FusedIterator
+    for IntoIter<K, V, LEAF>
+{
+}
+
+/// Iterator over the entries of a tree within a key range, in ascending key
+/// order, returned by `BTree::range`. Double-ended: `rev()` scans the range
+/// in descending order.
+pub struct Range<'a, K = Key, V = Value, const LEAF: usize = LEAF_ITEMS_SIZE> {
+    root: &'a NodePtr<K, V, LEAF>,
+    iter: Iter<'a, K, V, LEAF>,
+    // Walks backwards from the end, once `next_back` has been called
+    back: Option<Iter<'a, K, V, LEAF>>,
+    // Bounds of the entries left: the end moves down as `next_back` yields
+    // entries, and the start, only tracked once walking backwards, moves up
+    // as `next` does
+    start: Bound<K>,
+    end: Bound<K>,
+}
+
+impl<'a, K: TreeKey, V: TreeValue, const LEAF: usize> Range<'a, K, V, LEAF> {
+    pub(super) fn new<R: RangeBounds<K>>(
+        root: &'a NodePtr<K, V, LEAF>,
+        range: R,
+    ) -> Range<'a, K, V, LEAF> {
+        Range {
+            root,
+            iter: Iter::seek(root, range.start_bound()),
+            back: None,
+            start: Bound::Unbounded,
+            end: range.end_bound().cloned(),
+        }
+    }
+
+    // Entries left: those from the next one of the forward walk up to the
+    // end, counted from the subtree entry counts as in `count_range`
+    fn count_remaining(&self) -> usize {
+        let total = self.root.total_len();
+        let up_to_end = match self.end.as_ref() {
+            Bound::Included(key) => distribution::rank(&**self.root, key, true),
+            Bound::Excluded(key) => distribution::rank(&**self.root, key, false),
+            Bound::Unbounded => total,
+        };
+        // Past the end if the range starts after it
+        up_to_end.saturating_sub(total - self.iter.count_remaining())
+    }
+
+    // Stays exhausted in both directions
+    fn exhaust(&mut self) {
+        for iter in std::iter::once(&mut self.iter).chain(&mut self.back) {
+            iter.leaf = None;
+            iter.path.clear();
+        }
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> Iterator for Range<'_, K, V, LEAF> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let key = self.iter.peek_key()?;
+        let before_end = match &self.end {
+            Bound::Included(end) => key <= end,
+            Bound::Excluded(end) => key < end,
+            Bound::Unbounded => true,
+        };
+        if !before_end {
+            self.exhaust();
+            return None;
+        }
+        if self.back.is_some() {
+            self.start = Bound::Excluded(key.clone());
+        }
+        self.iter.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let n = self.count_remaining();
+        (n, Some(n))
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> ExactSizeIterator for Range<'_, K, V, LEAF> {}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> DoubleEndedIterator for Range<'_, K, V, LEAF> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.back.is_none() {
+            // The entries before the next one of the forward walk are gone
+            self.start = Bound::Included(self.iter.peek_key()?.clone());
+            self.back = Some(Iter::seek_back(self.root, self.end.as_ref()));
+        }
+        let back = self.back.as_mut().unwrap();
+        let key = back.peek_back_key()?;
+        let after_start = match &self.start {
+            Bound::Included(start) => key >= start,
+            Bound::Excluded(start) => key > start,
+            Bound::Unbounded => true,
+        };
+        if !after_start {
+            self.exhaust();
+            return None;
+        }
+        self.end = Bound::Excluded(key.clone());
+        let leaf = back.leaf.unwrap();
+        back.pos -= 1;
+        Some((leaf.keys[back.pos].clone(), leaf.values[back.pos].clone()))
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> std::iter::FusedIterator
+    for Range<'_, K, V, LEAF>
+{
+}
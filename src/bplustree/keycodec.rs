@@ -0,0 +1,350 @@
+//! Order-preserving encodings of application keys into bytes: the encodings
+//! of two values compare, byte by byte, like the values do. Keys of any of
+//! these types, or tuples of them, can then be stored in a tree of byte
+//! strings (`BTree<Vec<u8>, V>`), or in the default `Key` once short enough,
+//! and range scans over the encodings are range scans over the values.
+//!
+//! Integers are written big-endian, the sign bit of signed ones flipped so
+//! that negative numbers come first. Floats follow `f64::total_cmp`: -NaN,
+//! -inf, the negative numbers, -0.0, 0.0, the positive ones, inf, NaN.
+//! Strings are escaped and terminated, so that no encoding is a prefix of
+//! another and a tuple field ends where its encoding does.
+//!
+//! The keys of a composite key that share their first fields are then the
+//! byte strings starting with the encoding of those fields: scanning a
+//! tenant of `(tenant, timestamp)` keys is a prefix scan.
+use super::*;
+use std::ops::Bound;
+
+// Strings end with 0x00 0x01; a 0x00 within them is written 0x00 0xff
+const ESCAPE: u8 = 0x00;
+const ESCAPED_NUL: u8 = 0xff;
+const TERMINATOR: u8 = 0x01;
+
+/// Type with an order-preserving encoding
+pub trait KeyCodec: Sized {
+    /// Append the encoding of the value to `out`
+    fn encode_to(&self, out: &mut Vec<u8>);
+    /// Read a value from the start of `input`, moving `input` past its
+    /// encoding. Fails with `Error::Serialization` if it doesn't start with
+    /// a valid encoding.
+    fn decode_from(input: &mut &[u8]) -> error::Result<Self>;
+}
+
+/// Encoding of `value`
+pub fn encode<T: KeyCodec>(value: &T) -> Vec<u8> {
+    let mut out = Vec::new();
+    value.encode_to(&mut out);
+    out
+}
+
+/// Value encoded in `bytes`, which must hold nothing else
+pub fn decode<T: KeyCodec>(mut bytes: &[u8]) -> error::Result<T> {
+    let value = T::decode_from(&mut bytes)?;
+    if !bytes.is_empty() {
+        return Err(Error::Serialization(format!(
+            "{} bytes left after the encoded key",
+            bytes.len()
+        )));
+    }
+    Ok(value)
+}
+
+/// The encoding as a default `Key`, padded with zeros; `None` if it is
+/// longer than the key. Padding keeps the order as long as the encodings
+/// compared are not prefixes of one another, as is the case for the
+/// encodings of values of a same type.
+pub fn to_key(bytes: &[u8]) -> Option<Key> {
+    let mut padded = [0; 16];
+    padded.get_mut(..bytes.len())?.copy_from_slice(bytes);
+    Some([u128::from_be_bytes(padded)])
+}
+
+/// Range of the byte strings starting with `prefix`, e.g. the encoding of
+/// the first fields of a composite key. It ends before the next string of
+/// the same length as `prefix` once its trailing 0xff bytes are dropped, or
+/// is unbounded if there are only 0xff bytes.
+pub fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let start = Bound::Included(prefix.to_vec());
+    let mut end = prefix.to_vec();
+    while end.last() == Some(&u8::MAX) {
+        end.pop();
+    }
+    match end.last_mut() {
+        Some(last) => {
+            *last += 1;
+            (start, Bound::Excluded(end))
+        }
+        None => (start, Bound::Unbounded),
+    }
+}
+
+impl<V: TreeValue, const LEAF: usize> BTree<Vec<u8>, V, LEAF> {
+    /// Entries whose key starts with `prefix`, in ascending key order
+    pub fn range_prefix(&self, prefix: &[u8]) -> Range<'_, Vec<u8>, V, LEAF> {
+        self.range(prefix_range(prefix))
+    }
+}
+
+// The first `N` bytes of `input`, moving past them
+fn take<const N: usize>(input: &mut &[u8]) -> error::Result<[u8; N]> {
+    if input.len() < N {
+        return Err(Error::Serialization(format!(
+            "expected {} bytes, {} left",
+            N,
+            input.len()
+        )));
+    }
+    let (bytes, rest) = input.split_at(N);
+    *input = rest;
+    Ok(bytes.try_into().unwrap())
+}
+
+impl KeyCodec for u64 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_be_bytes());
+    }
+
+    fn decode_from(input: &mut &[u8]) -> error::Result<u64> {
+        Ok(u64::from_be_bytes(take(input)?))
+    }
+}
+
+impl KeyCodec for i64 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        (*self as u64 ^ 1 << 63).encode_to(out);
+    }
+
+    fn decode_from(input: &mut &[u8]) -> error::Result<i64> {
+        Ok((u64::decode_from(input)? ^ 1 << 63) as i64)
+    }
+}
+
+impl KeyCodec for f64 {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        // Negative numbers have all their bits flipped, so that larger
+        // magnitudes come first
+        let bits = self.to_bits();
+        let flipped = if bits >> 63 == 1 {
+            !bits
+        } else {
+            bits ^ 1 << 63
+        };
+        flipped.encode_to(out);
+    }
+
+    fn decode_from(input: &mut &[u8]) -> error::Result<f64> {
+        let flipped = u64::decode_from(input)?;
+        let bits = if flipped >> 63 == 1 {
+            flipped ^ 1 << 63
+        } else {
+            !flipped
+        };
+        Ok(f64::from_bits(bits))
+    }
+}
+
+impl KeyCodec for String {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        for &byte in self.as_bytes() {
+            out.push(byte);
+            if byte == ESCAPE {
+                out.push(ESCAPED_NUL);
+            }
+        }
+        out.extend_from_slice(&[ESCAPE, TERMINATOR]);
+    }
+
+    fn decode_from(input: &mut &[u8]) -> error::Result<String> {
+        let mut bytes = Vec::new();
+        loop {
+            match take(input)? {
+                [ESCAPE] => match take(input)? {
+                    [ESCAPED_NUL] => bytes.push(ESCAPE),
+                    [TERMINATOR] => break,
+                    [byte] => {
+                        return Err(Error::Serialization(format!(
+                            "invalid escape {:#04x} in an encoded string",
+                            byte
+                        )))
+                    }
+                },
+                [byte] => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).map_err(|e| Error::Serialization(e.to_string()))
+    }
+}
+
+// Tuples compare field by field, like the concatenation of their encodings
+macro_rules! tuple_codec {
+    ($($field:ident: $idx:tt),+) => {
+        impl<$($field: KeyCodec),+> KeyCodec for ($($field,)+) {
+            fn encode_to(&self, out: &mut Vec<u8>) {
+                $(self.$idx.encode_to(out);)+
+            }
+
+            fn decode_from(input: &mut &[u8]) -> error::Result<Self> {
+                Ok(($($field::decode_from(input)?,)+))
+            }
+        }
+    };
+}
+
+tuple_codec!(A: 0, B: 1);
+tuple_codec!(A: 0, B: 1, C: 2);
+tuple_codec!(A: 0, B: 1, C: 2, D: 3);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp::Ordering;
+
+    // Sorting by encoding must give the order of the values
+    fn check_order<T: KeyCodec + Debug>(sorted: &[T], cmp: impl Fn(&T, &T) -> Ordering) {
+        assert!(sorted.windows(2).all(|w| cmp(&w[0], &w[1]).is_le()));
+        for a in sorted {
+            for b in sorted {
+                assert_eq!(encode(a).cmp(&encode(b)), cmp(a, b), "{:?} {:?}", a, b);
+            }
+            assert_eq!(cmp(&decode::<T>(&encode(a)).unwrap(), a), Ordering::Equal);
+        }
+    }
+
+    #[test]
+    fn test_order_preserved() {
+        check_order(&[0, 1, 255, 256, 1 << 32, u64::MAX - 1, u64::MAX], u64::cmp);
+        check_order(
+            &[i64::MIN, i64::MIN + 1, -256, -1, 0, 1, 255, i64::MAX],
+            i64::cmp,
+        );
+        check_order(
+            &[
+                -f64::NAN,
+                f64::NEG_INFINITY,
+                f64::MIN,
+                -1.5,
+                -f64::MIN_POSITIVE / 2.0,
+                -0.0,
+                0.0,
+                f64::MIN_POSITIVE / 2.0,
+                1.0,
+                1.5,
+                f64::MAX,
+                f64::INFINITY,
+                f64::NAN,
+            ],
+            f64::total_cmp,
+        );
+        let strings = [
+            "", "\0", "\0\0", "\0a", "a", "a\0", "a\0b", "ab", "b", "é", "日本",
+        ];
+        check_order(&strings.map(String::from), String::cmp);
+        // The terminator keeps "a" before "a\0" within a tuple, whatever follows
+        check_order(
+            &[
+                ("".to_string(), 5u64),
+                ("a".to_string(), u64::MAX),
+                ("a\0".to_string(), 0),
+                ("a\0".to_string(), 1),
+                ("b".to_string(), 0),
+            ],
+            Ord::cmp,
+        );
+        check_order(
+            &[
+                (-1i64, 2.5f64, 0u64),
+                (0, -1.0, 1),
+                (0, 0.0, 0),
+                (0, 0.0, 1),
+            ],
+            |a, b| a.0.cmp(&b.0).then(a.1.total_cmp(&b.1)).then(a.2.cmp(&b.2)),
+        );
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert!(decode::<u64>(&[1, 2, 3]).is_err());
+        assert!(decode::<u64>(&[0; 9]).is_err());
+        assert!(decode::<String>(b"abc").is_err());
+        assert!(decode::<String>(&[b'a', 0, 7, 0, 1]).is_err());
+        assert!(decode::<String>(&[0xc3, 0, 1]).is_err());
+        assert!(decode::<(u64, String)>(&encode(&7u64)).is_err());
+    }
+
+    #[test]
+    // Signed timestamps per tenant, stored in a tree of byte strings and, the
+    // names being short enough, in the default key type
+    fn test_tree_keys() {
+        let mut bytes: BTree<Vec<u8>, Value> = BTree::new();
+        let mut packed = BTree::new();
+        for tenant in ["acme", "globex", "hooli"] {
+            for ts in -500i64..500 {
+                let key = encode(&(tenant.to_string(), ts));
+                packed.insert(to_key(&key).unwrap(), 0);
+                bytes.insert(key, 0);
+            }
+        }
+        let start = encode(&("globex".to_string(), -10i64));
+        let end = encode(&("globex".to_string(), 10i64));
+        let decoded: Vec<(String, i64)> = bytes
+            .range(start.clone()..end.clone())
+            .map(|(key, _)| decode(&key).unwrap())
+            .collect();
+        assert_eq!(decoded.len(), 20);
+        assert_eq!(decoded[0], ("globex".to_string(), -10));
+        assert_eq!(
+            packed.count_range(to_key(&start).unwrap()..to_key(&end).unwrap()),
+            20
+        );
+        assert!(bytes
+            .keys()
+            .map(|k| to_key(k).unwrap())
+            .eq(packed.keys().copied()));
+        assert_eq!(to_key(&[1; 17]), None);
+    }
+
+    #[test]
+    // Composite keys, compared field by field: natively as tuples, or
+    // encoded, which allows scans by leading fields
+    fn test_composite_keys() {
+        let mut native: BTree<(u64, i64), u64> = BTree::new();
+        let mut encoded: BTree<Vec<u8>, u64> = BTree::new();
+        for tenant in ["acme", "acme2", "globex"] {
+            for ts in -100i64..100 {
+                let tenant_id = tenant.len() as u64;
+                native.insert((tenant_id, ts), ts as u64);
+                encoded.insert(encode(&(tenant.to_string(), ts)), ts as u64);
+            }
+        }
+        assert_eq!(native.count_range((4, i64::MIN)..=(4, i64::MAX)), 200);
+        assert_eq!(native.range((5, 0)..(6, 0)).next(), Some(((5, 0), 0)));
+
+        // "acme" is not a prefix of "acme2" once terminated
+        let acme = encode(&"acme".to_string());
+        assert_eq!(encoded.range_prefix(&acme).count(), 200);
+        assert!(encoded
+            .range_prefix(&acme)
+            .map(|(key, _)| decode::<(String, i64)>(&key).unwrap().1)
+            .eq(-100..100));
+        let mut prefix = acme.clone();
+        (-1i64).encode_to(&mut prefix);
+        assert_eq!(encoded.range_prefix(&prefix).count(), 1);
+        assert_eq!(
+            encoded.count_range(prefix_range(&encode(&"none".to_string()))),
+            0
+        );
+        assert_eq!(encoded.range_prefix(b"acme").count(), 400);
+        assert_eq!(encoded.range_prefix(&[]).count(), 600);
+
+        // Trailing 0xff bytes carry over to the previous byte
+        assert_eq!(
+            prefix_range(&[1, 0xff, 0xff]),
+            (
+                Bound::Included(vec![1, 0xff, 0xff]),
+                Bound::Excluded(vec![2])
+            )
+        );
+        assert_eq!(prefix_range(&[0xff]).1, Bound::Unbounded);
+    }
+}
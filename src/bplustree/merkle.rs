@@ -0,0 +1,136 @@
+//! Merkle hashes of the nodes, for anti-entropy between replicas. The hash of
+//! an internal node covers its pivots and the hashes of its children, and is
+//! cached in the node until a write copies or modifies it (see `make_mut`).
+//! Leaves are small enough to be hashed when asked.
+//!
+//! Two trees are compared top-down: equal hashes mean equal subtrees, and
+//! nodes with the same pivots are compared child by child. Trees built by the
+//! same writes, or derived from the same snapshot, share their shape, so only
+//! the paths to the differences are visited. Where the shapes part, the key
+//! range of the node is reported as a whole, to be compared by scanning.
+use super::digest::{fnv, FNV_OFFSET};
+use super::*;
+
+/// Key range `[lower, upper)`, `None` meaning unbounded
+pub type KeyRange = (Option<Key>, Option<Key>);
+
+impl BTree {
+    /// Hash of the whole tree: equal for trees with the same entries and the
+    /// same shape. O(1) on a tree whose hashes are cached, otherwise hashes
+    /// the nodes written since the last call.
+    pub fn merkle_hash(&self) -> u64 {
+        node_hash(&*self.root)
+    }
+
+    /// Key ranges in which the entries of the two trees may differ, in
+    /// ascending order and without overlap; the trees hold the same entries
+    /// outside of them. Empty if the trees are equal.
+    pub fn diff_ranges(&self, other: &BTree) -> Vec<KeyRange> {
+        let mut ranges = Vec::new();
+        diff(&*self.root, &*other.root, None, None, &mut ranges);
+        ranges
+    }
+}
+
+impl InternalNode {
+    fn merkle_hash(&self) -> u64 {
+        *self.hash.get_or_init(|| {
+            let hash = self
+                .pivots
+                .iter()
+                .fold(fnv(FNV_OFFSET, &[1]), |hash, pivot| {
+                    fnv(hash, &pivot[0].to_le_bytes())
+                });
+            self.children.iter().fold(hash, |hash, child| {
+                fnv(hash, &node_hash(&**child).to_le_bytes())
+            })
+        })
+    }
+}
+
+fn node_hash(node: &dyn Node) -> u64 {
+    if let Some(internal) = node.as_internal() {
+        return internal.merkle_hash();
+    }
+    let leaf = node.as_leaf().unwrap();
+    leaf.keys
+        .iter()
+        .zip(leaf.values.iter())
+        .fold(fnv(FNV_OFFSET, &[0]), |hash, (key, val)| {
+            fnv(fnv(hash, &key[0].to_le_bytes()), &val.to_le_bytes())
+        })
+}
+
+fn diff(
+    a: &dyn Node,
+    b: &dyn Node,
+    lower: Option<Key>,
+    upper: Option<Key>,
+    out: &mut Vec<KeyRange>,
+) {
+    if node_hash(a) == node_hash(b) {
+        return;
+    }
+    match (a.as_internal(), b.as_internal()) {
+        (Some(a), Some(b)) if a.pivots == b.pivots => {
+            for (idx, (child_a, child_b)) in a.children.iter().zip(b.children.iter()).enumerate() {
+                let child_lower = if idx == 0 {
+                    lower
+                } else {
+                    Some(a.pivots[idx - 1])
+                };
+                let child_upper = a.pivots.get(idx).copied().or(upper);
+                diff(&**child_a, &**child_b, child_lower, child_upper, out);
+            }
+        }
+        _ => push_range(out, (lower, upper)),
+    }
+}
+
+// Append a range, merging it with the previous one if they touch
+fn push_range(out: &mut Vec<KeyRange>, range: KeyRange) {
+    match out.last_mut() {
+        Some(last) if last.1.is_some() && last.1 == range.0 => last.1 = range.1,
+        _ => out.push(range),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_ranges() {
+        let mut primary = BTree::new();
+        (0..10_000).for_each(|k| {
+            primary.insert([k; 1], 0);
+        });
+        let mut replica = primary.clone();
+        assert_eq!(primary.merkle_hash(), replica.merkle_hash());
+        assert!(primary.diff_ranges(&replica).is_empty());
+
+        replica.insert([500; 1], 1);
+        replica.delete(&[7000; 1]);
+        let ranges = primary.diff_ranges(&replica);
+        assert_eq!(ranges.len(), 2);
+        let contains = |(lower, upper): &KeyRange, key: u128| {
+            lower.is_none_or(|l| l[0] <= key) && upper.is_none_or(|u| key < u[0])
+        };
+        assert!(contains(&ranges[0], 500) && contains(&ranges[1], 7000));
+        // Only the nodes holding the changes are reported: the leaves, or
+        // their parent if the delete moved entries between leaves
+        assert!(ranges.iter().all(|(l, u)| {
+            u.unwrap()[0] - l.unwrap()[0] <= (CHILDREN_SIZE * LEAF_ITEMS_SIZE) as u128
+        }));
+
+        // Cached hashes are dropped by the writes that undo or repeat the
+        // changes (a delete is not undone by an insert if it moved entries)
+        replica.insert([500; 1], 0);
+        primary.delete(&[7000; 1]);
+        assert_eq!(primary.merkle_hash(), replica.merkle_hash());
+
+        // Different shapes: the differing subtrees are reported whole
+        let loaded = BTree::bulk_load(primary.iter()).unwrap();
+        assert_eq!(primary.diff_ranges(&loaded), vec![(None, None)]);
+    }
+}
@@ -0,0 +1,125 @@
+//! Counters of the structural work done by the trees of the current thread,
+//! to tune the split policy and node size. With the `op-costs` feature, the
+//! work of every operation (nodes visited, key comparisons) is counted too.
+//! The counters are thread-local, so work is credited to the thread doing it
+//! even if a tree moves between threads: a multi-threaded driver sums the
+//! snapshots of its workers.
+use std::cell::RefCell;
+use std::fmt;
+use std::ops::{AddAssign, Sub};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Nodes created, by splits, new roots or bulk loads
+    pub nodes_allocated: u64,
+    pub leaf_splits: u64,
+    pub internal_splits: u64,
+    /// Root splits, each growing the tree by a level
+    pub root_splits: u64,
+    /// Root collapses, each shrinking the tree by a level
+    pub root_collapses: u64,
+    /// Underfull nodes that took an entry or a child from a sibling
+    pub borrows: u64,
+    /// Underfull nodes merged with a sibling
+    pub merges: u64,
+    /// Nodes copied before a write because a clone or snapshot shared them
+    pub nodes_copied: u64,
+    /// Nodes gone through by gets, inserts and deletes (`op-costs` only)
+    pub nodes_visited: u64,
+    /// Key comparisons made by the searches within nodes (`op-costs` only)
+    pub key_comparisons: u64,
+}
+
+impl AddAssign for Metrics {
+    fn add_assign(&mut self, other: Metrics) {
+        self.nodes_allocated += other.nodes_allocated;
+        self.leaf_splits += other.leaf_splits;
+        self.internal_splits += other.internal_splits;
+        self.root_splits += other.root_splits;
+        self.root_collapses += other.root_collapses;
+        self.borrows += other.borrows;
+        self.merges += other.merges;
+        self.nodes_copied += other.nodes_copied;
+        self.nodes_visited += other.nodes_visited;
+        self.key_comparisons += other.key_comparisons;
+    }
+}
+
+// Saturates at 0, for counters reset between the two snapshots
+impl Sub for Metrics {
+    type Output = Metrics;
+
+    fn sub(self, other: Metrics) -> Metrics {
+        Metrics {
+            nodes_allocated: self.nodes_allocated.saturating_sub(other.nodes_allocated),
+            leaf_splits: self.leaf_splits.saturating_sub(other.leaf_splits),
+            internal_splits: self.internal_splits.saturating_sub(other.internal_splits),
+            root_splits: self.root_splits.saturating_sub(other.root_splits),
+            root_collapses: self.root_collapses.saturating_sub(other.root_collapses),
+            borrows: self.borrows.saturating_sub(other.borrows),
+            merges: self.merges.saturating_sub(other.merges),
+            nodes_copied: self.nodes_copied.saturating_sub(other.nodes_copied),
+            nodes_visited: self.nodes_visited.saturating_sub(other.nodes_visited),
+            key_comparisons: self.key_comparisons.saturating_sub(other.key_comparisons),
+        }
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "nodes allocated: {}, leaf splits: {}, internal splits: {}, root splits: {}, \
+             root collapses: {}, borrows: {}, merges: {}, nodes copied: {}, \
+             nodes visited: {}, key comparisons: {}",
+            self.nodes_allocated,
+            self.leaf_splits,
+            self.internal_splits,
+            self.root_splits,
+            self.root_collapses,
+            self.borrows,
+            self.merges,
+            self.nodes_copied,
+            self.nodes_visited,
+            self.key_comparisons
+        )
+    }
+}
+
+thread_local! {
+    pub(super) static COUNTERS: RefCell<Metrics> = RefCell::new(Metrics::default());
+}
+
+// Bump one of the counters of the current thread
+macro_rules! count {
+    ($field:ident) => {
+        metrics::COUNTERS.with_borrow_mut(|m| m.$field += 1)
+    };
+}
+pub(super) use count;
+
+// Bump a per-operation counter, compiled out without the `op-costs` feature
+macro_rules! cost {
+    ($field:ident) => {
+        #[cfg(feature = "op-costs")]
+        metrics::COUNTERS.with_borrow_mut(|m| m.$field += 1);
+    };
+}
+pub(super) use cost;
+
+/// Counters accumulated by the current thread since it started or since the
+/// last `reset`
+pub fn snapshot() -> Metrics {
+    COUNTERS.with_borrow(|m| *m)
+}
+
+pub fn reset() {
+    COUNTERS.with_borrow_mut(|m| *m = Metrics::default());
+}
+
+/// Run `op` and return the counters it bumped on the current thread
+pub fn measure<T>(op: impl FnOnce() -> T) -> (T, Metrics) {
+    let before = snapshot();
+    let result = op();
+    (result, snapshot() - before)
+}
@@ -1,379 +1,1764 @@
+use crate::error::{self, Error};
 use arrayvec::ArrayVec;
+#[cfg(feature = "rkyv")]
 use rkyv::{Archive, Deserialize, Serialize};
+use std::borrow::Borrow;
 use std::fmt::Debug;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
 use std::usize;
-use std::{cell::RefCell, rc::Rc};
 
-type Key = [u128; 1];
-type Value = u8;
-type NodePtr = Rc<RefCell<dyn Node>>;
+// Emits a `tracing` event for a structural change (split, collapse...). The
+// whole statement, arguments included, is compiled out without the feature.
+macro_rules! structure_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
+mod batch;
+mod cached;
+mod cursor;
+mod digest;
+mod distribution;
+mod entry;
+mod frozen;
+mod hooks;
+mod iter;
+pub mod keycodec;
+#[cfg(feature = "merkle")]
+mod merkle;
+pub mod metrics;
+mod multimap;
+#[cfg(feature = "sampling")]
+mod sample;
+mod snapshot;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod tombstone;
+mod versioned;
+
+pub use batch::WriteBatch;
+pub use cached::CachedTree;
+pub use cursor::CursorMut;
+pub use distribution::RangeSize;
+pub use entry::{Entry, OccupiedEntry, VacantEntry};
+pub use frozen::{FrozenIter, FrozenLayout, FrozenTree};
+pub use hooks::StructureEvent;
+use hooks::{emit, Hooks};
+pub use iter::{IntoIter, Iter, Keys, Range, Values, ValuesMut};
+#[cfg(feature = "merkle")]
+pub use merkle::KeyRange;
+use metrics::{cost, count};
+pub use multimap::BTreeMultiMap;
+pub use snapshot::Snapshot;
+pub use tombstone::TombstoneTree;
+pub use versioned::{Retention, VersionedTree};
+
+pub type Key = [u128; 1];
+pub type Value = u8;
+type NodePtr<K = Key, V = Value, const LEAF: usize = LEAF_ITEMS_SIZE> = Arc<dyn Node<K, V, LEAF>>;
+
+/// Bounds of the key types a tree can hold, implemented for every type that
+/// meets them. The default leaf capacity is sized for the default `Key` and
+/// `Value`; trees of larger types pick a smaller one, see `BTree::sized`.
+pub trait TreeKey: Ord + Clone + Debug + Send + Sync + 'static {}
+
+impl<T: Ord + Clone + Debug + Send + Sync + 'static> TreeKey for T {}
+
+/// Bounds of the value types a tree can hold, implemented for every type that
+/// meets them. `get` and the iterators return clones of the values.
+pub trait TreeValue: Clone + Debug + Send + Sync + 'static {}
+
+impl<T: Clone + Debug + Send + Sync + 'static> TreeValue for T {}
 
-// const NODE_SIZE: usize = 1024 * 4;
-const NODE_SIZE: usize = 64 * 4;
-const LEAF_ITEMS_SIZE: usize =
+// Node size in bytes, overridable at build time with the KVS_NODE_SIZE
+// environment variable (see benches/node_size_sweep.sh). The capacities
+// derived from it are array lengths, which stable Rust can't compute from
+// the key and value types of each tree: the default leaf capacity fits the
+// default ones, and trees of other types pick theirs with the `LEAF`
+// parameter, see `BTree`.
+pub const NODE_SIZE: usize = parse_node_size(option_env!("KVS_NODE_SIZE"), 64 * 4);
+pub const LEAF_ITEMS_SIZE: usize =
     (NODE_SIZE - 32) / (std::mem::size_of::<Key>() + std::mem::size_of::<Value>());
-const INTERNAL_ITEMS_SIZE: usize =
-    (NODE_SIZE - 32) / (std::mem::size_of::<NodePtr>() + std::mem::size_of::<Key>());
+// At the smallest node sizes, internal nodes keep the 4 children a split
+// needs even if that takes them past NODE_SIZE
+const INTERNAL_ITEMS_SIZE: usize = {
+    let fit = (NODE_SIZE - 32) / (std::mem::size_of::<NodePtr>() + std::mem::size_of::<Key>());
+    if fit > 4 {
+        fit
+    } else {
+        4
+    }
+};
 const PIVOTS_SIZE: usize = INTERNAL_ITEMS_SIZE - 1;
 const CHILDREN_SIZE: usize = INTERNAL_ITEMS_SIZE;
+// Occupancy below which deletes rebalance a non-root node, half of the
+// capacity for leaves too. Midpoint splits leave both halves at least this
+// full; biased splits may not, and such nodes are rebalanced once a delete
+// goes through them.
+const MIN_CHILDREN: usize = CHILDREN_SIZE / 2;
+
+// Every non-root internal node has at least two children and no leaf is
+// empty, so a tree of height h holds at least 2^(h-1) entries
+const MAX_HEIGHT: usize = usize::BITS as usize;
+
+// Splitting an internal node needs at least 3 pivots to leave both halves with one
+const _: () = assert!(
+    PIVOTS_SIZE >= 3 && LEAF_ITEMS_SIZE >= 2,
+    "NODE_SIZE is too small to hold a splittable node"
+);
+
+const fn parse_node_size(var: Option<&str>, default: usize) -> usize {
+    let bytes = match var {
+        Some(s) => s.as_bytes(),
+        None => return default,
+    };
+    let mut size = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(
+            bytes[i].is_ascii_digit(),
+            "KVS_NODE_SIZE must be a number of bytes"
+        );
+        size = size * 10 + (bytes[i] - b'0') as usize;
+        i += 1;
+    }
+    size
+}
 
-pub trait Node: std::fmt::Debug {
-    fn get(&self, key: &Key) -> Option<Value>;
-    fn insert(&mut self, key: Key, val: Value);
-    fn delete(&mut self, key: &Key) -> bool;
-    fn split(&mut self) -> (Key, NodePtr);
-    fn get_first_key(&self) -> Key;
+// Nodes hold no interior mutability, so a tree can be built on one thread and
+// moved to (or shared with) others
+pub trait Node<K = Key, V = Value, const LEAF: usize = LEAF_ITEMS_SIZE>:
+    std::fmt::Debug + Send + Sync
+{
+    /// Returns the previous value if the key was already there, in which case
+    /// only its value changed. Full nodes on the way are split according to
+    /// `policy`.
+    fn insert(&mut self, key: K, val: V, policy: SplitPolicy) -> Option<V>;
+    /// Remove the entry with the smallest key, or the largest if `last`
+    fn pop_entry(&mut self, last: bool) -> Option<(K, V)>;
+    /// Keep the entries for which `f` returns true, in key order. Returns the
+    /// number of entries removed; the node itself may be left underfull.
+    fn retain(&mut self, f: &mut dyn FnMut(&K, &mut V) -> bool) -> usize;
+    /// Move the entries from `key` on to a new node of the same height,
+    /// returned. Both nodes may be left underfull.
+    fn split_off(&mut self, key: &K) -> NodePtr<K, V, LEAF>;
+    /// Remove the entries between `start` and `end`, which are not both
+    /// unbounded. Returns the number of entries removed; the node itself may
+    /// be left underfull.
+    fn delete_range(&mut self, start: Bound<&K>, end: Bound<&K>) -> usize;
+    /// Split the node before inserting `key`, the position depending on
+    /// `policy`. Returns the first key of the new right sibling and the
+    /// sibling.
+    fn split(&mut self, policy: SplitPolicy, key: &K) -> (K, NodePtr<K, V, LEAF>);
+    fn get_first_key(&self) -> K;
+    /// Entries in the subtree, O(1)
     fn total_len(&self) -> usize;
     fn is_full(&self) -> bool;
     fn is_empty(&self) -> bool;
+    /// Below the minimum occupancy that deletes maintain
+    fn is_underfull(&self) -> bool;
+    /// Above the minimum occupancy: a sibling can take an entry or a child
+    fn can_lend(&self) -> bool;
     fn len(&self) -> usize;
-    fn pop_first_child(&mut self) -> Option<NodePtr>;
+    fn pop_first_child(&mut self) -> Option<NodePtr<K, V, LEAF>>;
+    /// Shallow copy of the node: the children are shared with the original
+    fn clone_node(&self) -> NodePtr<K, V, LEAF>;
+    /// Check the node's structural invariants, with every key expected in
+    /// `[lower, upper)`. Recurses into children.
+    fn check(&self, lower: Option<&K>, upper: Option<&K>, is_root: bool) -> Result<(), String>;
+    fn as_leaf(&self) -> Option<&LeafNode<K, V, LEAF>>;
+    fn as_leaf_mut(&mut self) -> Option<&mut LeafNode<K, V, LEAF>>;
+    fn as_internal(&self) -> Option<&InternalNode<K, V, LEAF>>;
+    fn as_internal_mut(&mut self) -> Option<&mut InternalNode<K, V, LEAF>>;
 }
 
-#[derive(Debug)]
-pub struct InternalNode {
-    pivots: ArrayVec<Key, PIVOTS_SIZE>,
-    children: ArrayVec<NodePtr, CHILDREN_SIZE>,
+#[derive(Clone, Debug)]
+pub struct InternalNode<K = Key, V = Value, const LEAF: usize = LEAF_ITEMS_SIZE> {
+    pivots: ArrayVec<K, PIVOTS_SIZE>,
+    children: ArrayVec<NodePtr<K, V, LEAF>, CHILDREN_SIZE>,
+    // Entries in the subtree, kept up to date by inserts and deletes
+    len: usize,
+    // Merkle hash of the subtree, cleared when the node is written
+    #[cfg(feature = "merkle")]
+    hash: std::sync::OnceLock<u64>,
 }
 
-#[derive(Archive, Deserialize, Serialize, Debug)]
-pub struct LeafNode {
-    keys: ArrayVec<Key, LEAF_ITEMS_SIZE>,
-    values: ArrayVec<Value, LEAF_ITEMS_SIZE>,
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "rkyv", derive(Archive, Deserialize, Serialize))]
+#[cfg_attr(feature = "rkyv", archive(check_bytes))]
+pub struct LeafNode<K = Key, V = Value, const LEAF: usize = LEAF_ITEMS_SIZE> {
+    keys: ArrayVec<K, LEAF>,
+    values: ArrayVec<V, LEAF>,
 }
 
-#[derive(Debug)]
-pub struct BTree {
-    root: NodePtr,
+/// Where a full node is split, see `BTree::with_split_policy`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SplitPolicy {
+    /// In the middle, leaving both nodes half full
+    #[default]
+    Midpoint,
+    /// The left node keeps the given percentage of the entries, rounded
+    Ratio(u8),
+    /// `Ratio` when the inserted key goes past the last key of the node, and
+    /// `Midpoint` otherwise: ascending inserts leave nearly full nodes behind
+    /// them while the others still split evenly
+    Append(u8),
 }
 
-impl BTree {
-    pub fn new() -> BTree {
+impl SplitPolicy {
+    // Entries of `len` the left node keeps, before clamping to the node type
+    fn left_len(self, len: usize, appending: bool) -> usize {
+        let percent = match self {
+            SplitPolicy::Ratio(percent) => percent,
+            SplitPolicy::Append(percent) if appending => percent,
+            SplitPolicy::Midpoint | SplitPolicy::Append(_) => return len / 2,
+        };
+        (len * percent.min(100) as usize + 50) / 100
+    }
+}
+
+/// Shape of the tree as reported by `BTree::stats`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TreeStats {
+    /// Number of levels, counting the deepest path (a lone leaf is height 1)
+    pub height: usize,
+    pub internal_nodes: usize,
+    pub leaf_nodes: usize,
+    pub entries: usize,
+    pub pivots: usize,
+    /// Entries a leaf holds when full
+    pub leaf_capacity: usize,
+}
+
+impl TreeStats {
+    /// Average fraction of leaf slots in use
+    pub fn leaf_fill(&self) -> f64 {
+        self.entries as f64 / (self.leaf_nodes * self.leaf_capacity) as f64
+    }
+
+    /// Average fraction of internal node pivot slots in use (0 without internal nodes)
+    pub fn internal_fill(&self) -> f64 {
+        if self.internal_nodes == 0 {
+            return 0.0;
+        }
+        self.pivots as f64 / (self.internal_nodes * PIVOTS_SIZE) as f64
+    }
+}
+
+impl std::fmt::Display for TreeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "height: {}, leaves: {}, internal nodes: {}, entries: {}, leaf fill: {:.1}%, internal fill: {:.1}%",
+            self.height,
+            self.leaf_nodes,
+            self.internal_nodes,
+            self.entries,
+            self.leaf_fill() * 100.0,
+            self.internal_fill() * 100.0
+        )
+    }
+}
+
+/// Cloning a tree is O(1): the clones share their nodes, and each copies the
+/// nodes it writes to, leaving the other untouched
+#[derive(Clone, Debug)]
+pub struct BTree<K = Key, V = Value, const LEAF: usize = LEAF_ITEMS_SIZE> {
+    root: NodePtr<K, V, LEAF>,
+    hooks: Option<Arc<Hooks<K>>>,
+    split_policy: SplitPolicy,
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> IntoIterator for BTree<K, V, LEAF> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V, LEAF>;
+
+    /// Consume the tree, yielding its entries in ascending key order and
+    /// freeing each node once it has been visited
+    fn into_iter(self) -> IntoIter<K, V, LEAF> {
+        IntoIter::new(self.root)
+    }
+}
+
+impl<'a, K: TreeKey, V: TreeValue, const LEAF: usize> IntoIterator for &'a BTree<K, V, LEAF> {
+    type Item = (K, V);
+    type IntoIter = Iter<'a, K, V, LEAF>;
+
+    fn into_iter(self) -> Iter<'a, K, V, LEAF> {
+        self.iter()
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> FromIterator<(K, V)> for BTree<K, V, LEAF> {
+    /// Tree of the entries, inserted in order: a key seen twice keeps its
+    /// last value. `bulk_load` builds it faster from sorted entries.
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(entries: I) -> BTree<K, V, LEAF> {
+        let mut tree = BTree::sized(SplitPolicy::default());
+        tree.extend(entries);
+        tree
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> Extend<(K, V)> for BTree<K, V, LEAF> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, entries: I) {
+        entries.into_iter().for_each(|(key, val)| {
+            self.insert(key, val);
+        });
+    }
+}
+
+// The constructors of the trees with the default leaf capacity, which can't
+// be inferred from the generic ones: type parameter defaults don't apply to
+// expressions
+impl<K: TreeKey, V: TreeValue> BTree<K, V> {
+    pub fn new() -> BTree<K, V> {
+        BTree::sized(SplitPolicy::default())
+    }
+
+    /// Empty tree splitting its full nodes according to `policy`
+    pub fn with_split_policy(policy: SplitPolicy) -> BTree<K, V> {
+        BTree::sized(policy)
+    }
+
+    /// Build a tree from entries sorted by strictly ascending key, see
+    /// `bulk_load_sized`
+    pub fn bulk_load<I>(entries: I) -> error::Result<BTree<K, V>>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        BTree::bulk_load_sized(entries)
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> BTree<K, V, LEAF> {
+    /// Empty tree whose leaves hold up to `LEAF` entries, splitting its full
+    /// nodes according to `policy`. For keys or values larger than the
+    /// default ones, a smaller capacity keeps the leaves within `NODE_SIZE`:
+    /// `BTree::<String, Vec<u8>, 4>::sized(SplitPolicy::default())`.
+    pub fn sized(policy: SplitPolicy) -> BTree<K, V, LEAF> {
         BTree {
-            root: Rc::new(RefCell::from(LeafNode::new())),
+            root: new_node(LeafNode::new()),
+            hooks: None,
+            split_policy: policy,
         }
     }
 
-    pub fn insert(&mut self, key: Key, val: Value) {
-        if self.root.borrow_mut().is_full() {
-            let (pivot, child_node) = self.root.borrow_mut().split();
-            self.root = Rc::new(RefCell::from(InternalNode::new_with_key(
-                pivot,
-                self.root.to_owned(),
-                child_node,
-            )));
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self)))]
+    pub fn insert(&mut self, key: K, val: V) -> Option<V> {
+        #[cfg(feature = "verify")]
+        let inserted = key.clone();
+        let previous = hooks::scoped(self.hooks.clone(), || {
+            self.split_root_if_full(&key);
+            make_mut(&mut self.root).insert(key, val, self.split_policy)
+        });
+        #[cfg(feature = "verify")]
+        self.verify("insert", &inserted);
+        previous
+    }
+
+    // Splits are done preemptively on the way down, so a full root grows the tree by a level
+    fn split_root_if_full(&mut self, key: &K) {
+        if self.root.is_full() {
+            let (pivot, child_node) = make_mut(&mut self.root).split(self.split_policy, key);
+            structure_event!(pivot = ?pivot, "root split, tree grows a level");
+            count!(root_splits);
+            emit(|| StructureEvent::Split {
+                pivot: pivot.clone(),
+                leaf: child_node.as_leaf().is_some(),
+            });
+            self.grow_root(pivot, child_node);
+            emit(|| StructureEvent::<K>::RootChange { height_grew: true });
         }
-        self.root.borrow_mut().insert(key, val);
     }
 
-    pub fn get(&self, key: &Key) -> Option<Value> {
-        return self.root.borrow().get(key);
+    // Put a new root above the current one, with `right` as its second child
+    fn grow_root(&mut self, pivot: K, right: NodePtr<K, V, LEAF>) {
+        let left = std::mem::replace(&mut self.root, new_node(InternalNode::new()));
+        *make_mut(&mut self.root).as_internal_mut().unwrap() =
+            InternalNode::new_with_key(pivot, left, right);
     }
 
-    pub fn delete(&mut self, key: &Key) -> bool {
-        let result = self.root.borrow_mut().delete(key);
+    /// Value of `key`, which can be any borrowed form of the key type, like
+    /// a `&str` for `String` keys, ordered the same way
+    pub fn get<Q: Ord + ?Sized>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        find(&*self.root, key).map(|(_, val)| val.clone())
+    }
 
-        if result && self.root.borrow_mut().is_empty() {
-            // If the root is empty, we can remove a level
-            let child = self.root.borrow_mut().pop_first_child();
-            match child {
-                Some(new_root) => self.root = new_root,
-                None => (),
-            };
+    /// The key as stored in the tree along with its value, e.g. to get an
+    /// owned key back from a borrowed one
+    pub fn get_key_value<Q: Ord + ?Sized>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        find(&*self.root, key).map(|(key, val)| (key.clone(), val.clone()))
+    }
+
+    /// Whether `key` is in the tree. Cheaper than `get` for large values,
+    /// which it neither clones nor reads.
+    pub fn contains_key<Q: Ord + ?Sized>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        find(&*self.root, key).is_some()
+    }
+
+    /// Entry with the smallest key, O(height)
+    pub fn first_key_value(&self) -> Option<(K, V)> {
+        let leaf = self.edge_leaf(|_| 0);
+        Some((leaf.keys.first()?.clone(), leaf.values[0].clone()))
+    }
+
+    /// Entry with the largest key, O(height)
+    pub fn last_key_value(&self) -> Option<(K, V)> {
+        let leaf = self.edge_leaf(|children| children - 1);
+        Some((leaf.keys.last()?.clone(), leaf.values.last()?.clone()))
+    }
+
+    /// First entry with a key at or after `key`, O(height). Like `get`, this
+    /// and the other lookups by key take any borrowed form of the key type.
+    pub fn lower_bound<Q: Ord + ?Sized>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        Iter::seek(&self.root, Bound::Included(key)).next()
+    }
+
+    /// First entry with a key after `key`, O(height)
+    pub fn upper_bound<Q: Ord + ?Sized>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        Iter::seek(&self.root, Bound::Excluded(key)).next()
+    }
+
+    /// Last entry with a key at or before `key`, O(height): the start of the
+    /// range `key` falls into, when the entries are keyed by range start
+    pub fn floor<Q: Ord + ?Sized>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        last_before(&*self.root, key, true)
+    }
+
+    /// Last entry with a key before `key`, O(height)
+    pub fn predecessor<Q: Ord + ?Sized>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        last_before(&*self.root, key, false)
+    }
+
+    // Leaf reached by following, at each level, the child `pick` returns out
+    // of the number of children. Only the root leaf of an empty tree is empty.
+    fn edge_leaf(&self, pick: impl Fn(usize) -> usize) -> &LeafNode<K, V, LEAF> {
+        let mut node = &*self.root;
+        while let Some(internal) = node.as_internal() {
+            cost!(nodes_visited);
+            node = &*internal.children[pick(internal.children.len())];
         }
-        return result;
+        cost!(nodes_visited);
+        node.as_leaf().unwrap()
     }
 
-    pub fn total_len(&self) -> usize {
-        self.root.borrow().total_len()
+    /// Mutable reference to the value of `key`, to update it in place. The
+    /// nodes on the path that a snapshot or clone still shares are copied, as
+    /// for a write, even if the key is absent.
+    pub fn get_mut<Q: Ord + ?Sized>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+    {
+        let mut node = make_mut(&mut self.root);
+        while node.as_internal().is_some() {
+            cost!(nodes_visited);
+            let internal = node.as_internal_mut().unwrap();
+            let idx = internal.child_idx(key);
+            node = make_mut(&mut internal.children[idx]);
+        }
+        cost!(nodes_visited);
+        let leaf = node.as_leaf_mut().unwrap();
+        let idx = search(&leaf.keys, key).ok()?;
+        Some(&mut leaf.values[idx])
     }
-}
 
-impl InternalNode {
-    pub fn new() -> InternalNode {
-        InternalNode {
-            pivots: ArrayVec::new(),
-            children: ArrayVec::new(),
+    /// Remove `key`, which can be any borrowed form of the key type, returning
+    /// its value. The borrowed form is `Debug` for the tracing span and the
+    /// `verify` report.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip(self)))]
+    pub fn delete<Q: Ord + Debug + ?Sized>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        let result = hooks::scoped(self.hooks.clone(), || self.delete_scoped(key));
+        #[cfg(feature = "verify")]
+        self.verify("delete", key);
+        result
+    }
+
+    fn delete_scoped<Q: Ord + ?Sized>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        let result = delete(make_mut(&mut self.root), key);
+        if result.is_some() {
+            self.collapse_root();
         }
+        result
     }
 
-    pub fn new_from(pivots: &[Key], children: &[NodePtr]) -> InternalNode {
-        let mut p = ArrayVec::new();
-        let mut c = ArrayVec::new();
-        p.try_extend_from_slice(pivots).unwrap();
-        children.iter().for_each(|x| c.push(x.clone()));
-        InternalNode {
-            pivots: p,
-            children: c,
+    /// Keep only the entries for which `f` returns true, in a single pass in
+    /// key order. The leaves are filtered first and each level rebalanced
+    /// once afterwards, rather than after every removal. Every node is copied
+    /// if a snapshot shares it, since `f` may update the values.
+    pub fn retain<F: FnMut(&K, &mut V) -> bool>(&mut self, mut f: F) {
+        hooks::scoped(self.hooks.clone(), || {
+            make_mut(&mut self.root).retain(&mut f);
+            self.shrink_root();
+        });
+        #[cfg(feature = "verify")]
+        if let Err(e) = self.check_invariants() {
+            panic!("invariant violated after retain: {}", e);
         }
     }
 
-    pub fn new_with_key(key: Key, left: NodePtr, right: NodePtr) -> InternalNode {
-        let mut node = InternalNode {
-            pivots: ArrayVec::new(),
-            children: ArrayVec::new(),
-        };
-        node.pivots.push(key);
-        node.children.push(left);
-        node.children.push(right);
-        return node;
+    /// Remove the entries within `range`, returning how many there were. The
+    /// subtrees that fall entirely inside the range are dropped whole,
+    /// without visiting them; only the nodes on the paths to the two ends of
+    /// the range are modified and rebalanced.
+    pub fn delete_range<R: RangeBounds<K>>(&mut self, range: R) -> usize {
+        let (start, end) = (range.start_bound(), range.end_bound());
+        if let (Bound::Unbounded, Bound::Unbounded) = (start, end) {
+            let removed = self.len();
+            self.clear();
+            return removed;
+        }
+        let removed = hooks::scoped(self.hooks.clone(), || {
+            let removed = make_mut(&mut self.root).delete_range(start, end);
+            self.shrink_root();
+            removed
+        });
+        #[cfg(feature = "verify")]
+        if let Err(e) = self.check_invariants() {
+            panic!("invariant violated after delete_range: {}", e);
+        }
+        removed
     }
 
-    pub fn try_split(&mut self, idx: usize) {
-        if self.children[idx].borrow_mut().is_full() {
-            let (pivot, child_node) = self.children[idx].borrow_mut().split();
-            // println!("Split detected: insert:{:?}; idx:{}; pivot:{:?}", key, idx, pivot);
-            self.pivots.insert(idx, pivot);
-            self.children.insert(idx + 1, child_node);
+    /// Move the entries from `key` on to a new tree, returned. Only the nodes
+    /// on the path to `key` are split; the subtrees on either side of it are
+    /// moved as they are, and shared with any snapshot.
+    pub fn split_off(&mut self, key: &K) -> BTree<K, V, LEAF> {
+        let mut other = BTree {
+            root: new_node(LeafNode::new()),
+            hooks: self.hooks.clone(),
+            split_policy: self.split_policy,
+        };
+        hooks::scoped(self.hooks.clone(), || {
+            other.root = make_mut(&mut self.root).split_off(key);
+            self.shrink_root();
+            other.shrink_root();
+        });
+        #[cfg(feature = "verify")]
+        {
+            self.verify("split_off", key);
+            other.verify("split_off", key);
         }
+        other
     }
-}
 
-impl Node for InternalNode {
-    fn insert(&mut self, key: Key, val: Value) {
-        let mut idx = match self.pivots.binary_search(&key) {
-            Ok(idx) => idx,
-            Err(idx) => {
-                self.try_split(idx);
-                idx
+    /// Remove and return the entry with the smallest key, in one descent
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        self.pop(false)
+    }
+
+    /// Remove and return the entry with the largest key, in one descent
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        self.pop(true)
+    }
+
+    fn pop(&mut self, last: bool) -> Option<(K, V)> {
+        let popped = hooks::scoped(self.hooks.clone(), || {
+            let popped = make_mut(&mut self.root).pop_entry(last);
+            if popped.is_some() {
+                self.collapse_root();
             }
+            popped
+        });
+        #[cfg(feature = "verify")]
+        if let Some((key, _)) = &popped {
+            self.verify(if last { "pop_last" } else { "pop_first" }, key);
+        }
+        popped
+    }
+
+    /// Remove every entry, leaving an empty leaf as the root. The nodes are
+    /// dropped at once unless a snapshot or clone still shares them; the
+    /// hooks and the split policy of the tree are kept.
+    pub fn clear(&mut self) {
+        self.root = new_node(LeafNode::new());
+    }
+
+    /// Move every entry of `other` into this tree. If the keys of one tree
+    /// are all before those of the other, the shorter tree is hung whole on
+    /// the edge of the taller one, splitting and rebalancing only the nodes
+    /// on that edge. Otherwise the entries of `other` are inserted one by
+    /// one, its values winning for the keys in both.
+    pub fn append(&mut self, other: BTree<K, V, LEAF>) {
+        let (Some((other_first, _)), Some((other_last, _))) =
+            (other.first_key_value(), other.last_key_value())
+        else {
+            return;
         };
-        // println!("{:?}", self);
-        if idx < self.pivots.len() && key > self.pivots[idx] {
-            idx += 1; // Might be in right sibling
+        let (Some((first, _)), Some((last, _))) = (self.first_key_value(), self.last_key_value())
+        else {
+            self.root = other.root;
+            return;
+        };
+        if last < other_first {
+            hooks::scoped(self.hooks.clone(), || {
+                self.graft(other.root, other_first, true)
+            });
+        } else if other_last < first {
+            hooks::scoped(self.hooks.clone(), || self.graft(other.root, first, false));
+        } else {
+            self.extend(other);
         }
-        self.children[idx].borrow_mut().insert(key, val);
+        #[cfg(feature = "verify")]
+        self.verify("append", &other_last);
     }
 
-    fn split(&mut self) -> (Key, NodePtr) {
-        let mid = (self.pivots.len() / 2) as usize;
+    // Hang `subtree`, whose keys are all after those of the tree if `right`,
+    // or all before, on the right or left edge of the tree. `pivot` is the
+    // first key of the two sides' upper one. The shorter of the tree and the
+    // subtree becomes a child of a node of the other, at the depth where its
+    // leaves line up with the other's.
+    fn graft(&mut self, subtree: NodePtr<K, V, LEAF>, pivot: K, right: bool) {
+        if node_height(&*self.root) < node_height(&*subtree) {
+            let root = std::mem::replace(&mut self.root, subtree);
+            return self.graft(root, pivot, !right);
+        }
+        if node_height(&*self.root) == node_height(&*subtree) {
+            let root = std::mem::replace(&mut self.root, new_node(LeafNode::new()));
+            let (left, right) = if right {
+                (root, subtree)
+            } else {
+                (subtree, root)
+            };
+            self.root = new_node(InternalNode::new_with_key(pivot, left, right));
+            emit(|| StructureEvent::<K>::RootChange { height_grew: true });
+            let root = make_mut(&mut self.root).as_internal_mut().unwrap();
+            root.rebalance_children();
+            self.shrink_root();
+            return;
+        }
+        // Make room on the edge on the way down, like an insert of `pivot`
+        self.split_root_if_full(&pivot);
+        let (added, target) = (subtree.total_len(), node_height(&*subtree) + 1);
+        let mut height = node_height(&*self.root);
+        let edge =
+            |node: &InternalNode<K, V, LEAF>| if right { node.children.len() - 1 } else { 0 };
+        let mut node = make_mut(&mut self.root).as_internal_mut().unwrap();
+        while height > target {
+            node.len += added;
+            node.try_split(edge(node), self.split_policy, &pivot);
+            let idx = edge(node);
+            node = make_mut(&mut node.children[idx]).as_internal_mut().unwrap();
+            height -= 1;
+        }
+        node.len += added;
+        if right {
+            node.pivots.push(pivot);
+            node.children.push(subtree);
+        } else {
+            node.pivots.insert(0, pivot);
+            node.children.insert(0, subtree);
+        }
+        // The subtree was a root, possibly below the minimum occupancy
+        node.rebalance_child(edge(node));
+    }
 
-        let right_node = Rc::new(RefCell::from(InternalNode::new_from(
-            &self.pivots[mid + 1..],
-            &self.children[mid + 1..],
-        )));
-        let pivot = self.pivots[mid];
-        self.pivots.truncate(mid);
-        self.children.truncate(mid + 1);
-        return (pivot, right_node);
+    // Remove levels until the root has two children or is a leaf, after a
+    // bulk change left a chain of single children at the top
+    fn shrink_root(&mut self) {
+        while self.root.as_internal().is_some() && self.root.is_empty() {
+            self.collapse_root();
+        }
     }
 
-    fn get(&self, key: &Key) -> Option<Value> {
-        let idx = match self.pivots.binary_search(&key) {
-            Ok(idx) => idx + 1, // If key=pivot, look in right child
-            Err(idx) => idx,
-        };
-        return self.children[idx].borrow().get(key);
-    }
-
-    fn delete(&mut self, key: &Key) -> bool {
-        match self.pivots.binary_search(&key) {
-            Ok(left_idx) => {
-                let idx = left_idx + 1;
-                if self.children[idx].borrow_mut().delete(key) {
-                    if self.children[idx].borrow_mut().is_empty() {
-                        self.pivots.remove(left_idx);
-                        self.children.remove(idx);
-                    } else {
-                        self.pivots[idx] = self.children[idx].borrow_mut().get_first_key();
-                    }
-                    return true;
-                }
-                return false;
-            }
-            // Key to remove is not a pivot, recursive delete in the child node
-            Err(idx) => {
-                let deleted = self.children[idx].borrow_mut().delete(key);
-                // println!("Deleting {:?}", key);
-                if self.children[idx].borrow_mut().is_empty() {
-                    // If the child is an intermediary node it might still have a child, so let's fetch it
-                    let child = self.children[idx].borrow_mut().pop_first_child();
-                    if child.is_some() {
-                        self.children[idx] = child.unwrap()
-                    } else if self.children[idx + 1].borrow().len() > 1 {
-                        // Right child is splitable
-                        let (key, right_node) = self.children[idx + 1].borrow_mut().split();
-                        self.pivots[idx] = key;
-                        self.children.swap(idx, idx + 1);
-                        self.children[idx + 1] = right_node;
-                    } else {
-                        // right child is too small for split
-                        self.pivots.remove(idx);
-                        self.children.remove(idx);
-                    }
+    // Remove a level after a removal emptied the root
+    fn collapse_root(&mut self) {
+        if self.root.is_empty() {
+            let child = make_mut(&mut self.root).pop_first_child();
+            match child {
+                Some(new_root) => {
+                    structure_event!("root collapsed, tree loses a level");
+                    count!(root_collapses);
+                    self.root = new_root;
+                    emit(|| StructureEvent::<K>::RootChange { height_grew: false });
                 }
-                return deleted;
-            }
+                None => (),
+            };
         }
     }
 
-    fn total_len(&self) -> usize {
-        self.children.iter().map(|x| x.borrow().total_len()).sum()
+    /// Number of entries, O(1): the internal nodes keep the entry count of
+    /// their subtree
+    pub fn len(&self) -> usize {
+        self.root.total_len()
     }
 
-    fn is_full(&self) -> bool {
-        self.pivots.is_full()
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
-    fn is_empty(&self) -> bool {
-        self.pivots.is_empty()
+    /// Same as `len`
+    pub fn total_len(&self) -> usize {
+        self.len()
     }
 
-    fn get_first_key(&self) -> Key {
-        self.pivots[0]
+    /// Iterate over all entries in ascending key order, without allocating
+    pub fn iter(&self) -> Iter<'_, K, V, LEAF> {
+        Iter::new(&self.root)
     }
 
-    fn len(&self) -> usize {
-        self.pivots.len()
+    /// Iterate over the keys in ascending order, borrowing them
+    pub fn keys(&self) -> Keys<'_, K, V, LEAF> {
+        Keys { iter: self.iter() }
     }
 
-    fn pop_first_child(&mut self) -> Option<NodePtr> {
-        self.children.pop()
+    /// Iterate over the values in ascending key order, borrowing them
+    pub fn values(&self) -> Values<'_, K, V, LEAF> {
+        Values { iter: self.iter() }
     }
-}
 
-impl LeafNode {
-    pub fn new() -> LeafNode {
-        LeafNode {
-            keys: ArrayVec::new(),
-            values: ArrayVec::new(),
-        }
+    /// Iterate over mutable references to the values in ascending key order,
+    /// to rewrite them in one pass. The nodes a snapshot or clone still
+    /// shares are copied, as for a write.
+    pub fn values_mut(&mut self) -> ValuesMut<'_, K, V, LEAF> {
+        ValuesMut::new(&mut self.root)
     }
 
-    pub fn new_from(keys: &[Key], values: &[Value]) -> LeafNode {
-        let mut k = ArrayVec::new();
-        k.try_extend_from_slice(keys).unwrap();
-        let mut v = ArrayVec::new();
-        v.try_extend_from_slice(values).unwrap();
-        LeafNode { keys: k, values: v }
+    /// Take every entry out of the tree, in ascending key order, leaving it
+    /// empty. Entries are moved out of the nodes no snapshot shares, which
+    /// are freed as the iterator leaves them, and cloned from the others.
+    /// Entries not consumed are dropped with the iterator.
+    pub fn drain(&mut self) -> IntoIter<K, V, LEAF> {
+        IntoIter::new(std::mem::replace(&mut self.root, new_node(LeafNode::new())))
     }
-}
-
-impl Node for LeafNode {
-    fn split(&mut self) -> (Key, NodePtr) {
-        let mid = self.keys.len() / 2;
 
-        let right_node = Rc::new(RefCell::from(LeafNode::new_from(
-            &self.keys[mid..],
-            &self.values[mid..],
-        )));
-        let pivot = self.keys[mid];
-        self.keys.truncate(mid);
-        self.values.truncate(mid);
-        return (pivot, right_node);
+    /// Iterate over the entries within `range` in ascending key order. Finds
+    /// the first one with a single descent, then walks the leaves like
+    /// `iter`. A range whose start is after its end yields nothing.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Range<'_, K, V, LEAF> {
+        Range::new(&self.root, range)
     }
 
-    fn insert(&mut self, key: Key, val: Value) {
-        match self.keys.binary_search(&key) {
-            Ok(idx) => self.values[idx] = val,
-            Err(idx) => {
-                self.keys.insert(idx, key);
-                self.values.insert(idx, val);
-            }
-        }
+    /// Every `step`-th entry in ascending key order, starting with the first.
+    /// The entries in between are stepped over by subtree, so a sample costs
+    /// O(height * fanout) instead of `step` entries. Panics if `step` is 0.
+    pub fn iter_sampled(&self, step: usize) -> std::iter::StepBy<Iter<'_, K, V, LEAF>> {
+        self.iter().step_by(step)
     }
 
-    fn get(&self, key: &Key) -> Option<Value> {
-        match self.keys.binary_search(&key) {
-            Ok(idx) => Some(self.values[idx]),
-            Err(_) => None,
-        }
+    /// Read-only view of the tree as it is now, cheap to clone and to query
+    /// from many threads at once. The tree stays writable: it copies the
+    /// nodes it modifies while the snapshot still shares them.
+    pub fn share(&self) -> Snapshot<K, V, LEAF> {
+        Snapshot::new(self.clone())
     }
 
-    fn get_first_key(&self) -> Key {
-        self.keys[0]
+    // Take the nodes of `other`, keeping the hooks of this tree
+    pub(crate) fn replace_nodes(&mut self, other: BTree<K, V, LEAF>) {
+        self.root = other.root;
     }
 
-    fn delete(&mut self, key: &Key) -> bool {
-        match self.keys.binary_search(&key) {
-            Ok(idx) => {
-                self.keys.remove(idx);
-                self.values.remove(idx);
-                true
+    /// Walk the whole tree and gather its structural statistics
+    pub fn stats(&self) -> TreeStats {
+        let mut stats = TreeStats {
+            leaf_capacity: LEAF,
+            ..TreeStats::default()
+        };
+        let mut level: Vec<&dyn Node<K, V, LEAF>> = vec![&*self.root];
+        while !level.is_empty() {
+            stats.height += 1;
+            let mut next = Vec::new();
+            for node in level {
+                match node.as_internal() {
+                    Some(internal) => {
+                        stats.internal_nodes += 1;
+                        stats.pivots += internal.pivots.len();
+                        next.extend(internal.children.iter().map(|child| &**child));
+                    }
+                    None => {
+                        stats.leaf_nodes += 1;
+                        stats.entries += node.len();
+                    }
+                }
             }
-            Err(_) => false,
+            level = next;
         }
+        stats
     }
 
-    fn total_len(&self) -> usize {
-        return self.keys.len();
+    /// Walk the whole tree and check its structural invariants: sorted keys,
+    /// keys within the range delimited by the parent pivots, no empty
+    /// non-root node and every leaf at the same depth. Returns a description
+    /// of the first violation found.
+    pub fn check_invariants(&self) -> error::Result<()> {
+        self.root
+            .check(None, None, true)
+            .map_err(Error::Corruption)?;
+        checked_height(&*self.root)
+            .map(|_| ())
+            .map_err(Error::Corruption)
     }
 
-    fn is_full(&self) -> bool {
-        self.keys.is_full()
+    /// `get` along with the work it did: nodes visited and key comparisons
+    #[cfg(feature = "op-costs")]
+    pub fn get_traced(&self, key: &K) -> (Option<V>, metrics::Metrics) {
+        metrics::measure(|| self.get(key))
     }
 
-    fn is_empty(&self) -> bool {
-        self.keys.is_empty()
+    /// `insert` along with the work it did, splits included
+    #[cfg(feature = "op-costs")]
+    pub fn insert_traced(&mut self, key: K, val: V) -> metrics::Metrics {
+        metrics::measure(|| self.insert(key, val)).1
     }
 
-    fn len(&self) -> usize {
-        self.keys.len()
+    /// `delete` along with the work it did, merges included
+    #[cfg(feature = "op-costs")]
+    pub fn delete_traced(&mut self, key: &K) -> (Option<V>, metrics::Metrics) {
+        metrics::measure(|| self.delete(key))
     }
 
-    fn pop_first_child(&mut self) -> Option<NodePtr> {
-        None
+    // With the `verify` feature every mutation re-checks the whole tree, which
+    // makes it O(n) but pinpoints the operation that broke an invariant
+    #[cfg(feature = "verify")]
+    fn verify<Q: Debug + ?Sized>(&self, op: &str, key: &Q) {
+        if let Err(e) = self.check_invariants() {
+            panic!("invariant violated after {} of {:?}: {}", op, key, e);
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    fn test_insert<I>(btree: &mut BTree, keys: I)
+    /// Build a tree from entries sorted by strictly ascending key.
+    /// Leaves are packed full and the internal levels are built bottom-up,
+    /// which avoids a root-to-leaf descent (and the splits) for every key.
+    /// Fails with `Error::InvalidInput` if the keys are not strictly ascending.
+    pub fn bulk_load_sized<I>(entries: I) -> error::Result<BTree<K, V, LEAF>>
     where
-        I: Iterator<Item = u128>,
+        I: IntoIterator<Item = (K, V)>,
     {
-        let mut key: Key = [0; 1];
-        let mut expected_len = 0;
-        for n in keys {
-            key[0] = n;
-            btree.insert(key, 0);
-            expected_len += 1;
-            if expected_len < btree.total_len() as u128 {
-                println!("Tree: {:?}", btree);
-                assert!(false);
+        let mut leaves = Vec::new();
+        let mut current = LeafNode::new();
+        let mut last_key: Option<K> = None;
+        for (key, val) in entries {
+            if let Some(last) = &last_key {
+                if *last >= key {
+                    return Err(Error::InvalidInput(format!(
+                        "bulk_load keys must be strictly ascending, got {:?} after {:?}",
+                        key, last
+                    )));
+                }
+            }
+            last_key = Some(key.clone());
+            if current.keys.is_full() {
+                leaves.push(std::mem::replace(&mut current, LeafNode::new()));
             }
+            current.keys.push(key);
+            current.values.push(val);
         }
-    }
+        if current.keys.is_empty() {
+            return Ok(BTree::sized(SplitPolicy::default()));
+        }
+        // Even out the last two leaves so the tail isn't left nearly empty
+        if let Some(prev) = leaves.pop() {
+            let keys: Vec<K> = prev
+                .keys
+                .iter()
+                .chain(current.keys.iter())
+                .cloned()
+                .collect();
+            let values: Vec<V> = prev
+                .values
+                .iter()
+                .chain(current.values.iter())
+                .cloned()
+                .collect();
+            let mid = keys.len().div_ceil(2);
+            leaves.push(LeafNode::new_from(&keys[..mid], &values[..mid]));
+            current = LeafNode::new_from(&keys[mid..], &values[mid..]);
+        }
+        leaves.push(current);
 
-    fn test_read<I>(btree: &mut BTree, keys: I)
-    where
-        I: Iterator<Item = u128>,
-    {
-        let mut key: Key = [0; 1];
-        for n in keys {
-            key[0] = n;
-            assert!(btree.get(&key).is_some());
+        let mut level: Vec<(K, NodePtr<K, V, LEAF>)> = leaves
+            .into_iter()
+            .map(|leaf| {
+                let first_key = leaf.get_first_key();
+                let ptr: NodePtr<K, V, LEAF> = new_node(leaf);
+                (first_key, ptr)
+            })
+            .collect();
+        while level.len() > 1 {
+            let mut parents = Vec::new();
+            let mut children = level.into_iter();
+            for size in bulk_chunk_sizes(children.len(), CHILDREN_SIZE) {
+                let mut node = InternalNode::new();
+                let (first_key, first_child) = children.next().unwrap();
+                node.children.push(first_child);
+                for (key, child) in children.by_ref().take(size - 1) {
+                    node.pivots.push(key);
+                    node.children.push(child);
+                }
+                node.len = node.children.iter().map(|child| child.total_len()).sum();
+                let ptr: NodePtr<K, V, LEAF> = new_node(node);
+                parents.push((first_key, ptr));
+            }
+            level = parents;
+        }
+        let tree = BTree {
+            root: level.pop().unwrap().1,
+            hooks: None,
+            split_policy: SplitPolicy::default(),
+        };
+        structure_event!(stats = %tree.stats(), "bulk load");
+        #[cfg(feature = "verify")]
+        if let Err(e) = tree.check_invariants() {
+            panic!("invariant violated after bulk_load: {}", e);
         }
+        Ok(tree)
     }
+}
 
-    fn test_delete<I>(btree: &mut BTree, keys: I)
-    where
-        I: Iterator<Item = u128>,
-    {
-        let mut key: Key = [0; 1];
-        for n in keys {
-            key[0] = n;
-            // println!("Tree: {:?}", btree);
-            // println!("Removing key: {:?}", key);
-            assert!(btree.delete(&key));
-        }
+impl BTree {
+    /// Rewrite the tree into its compact read-only form, freeing the nodes as
+    /// their entries are moved out
+    pub fn freeze(self) -> FrozenTree {
+        self.freeze_with(FrozenLayout::default())
     }
 
-    #[test]
-    // Insert, read, and delete in ascending order
-    fn test_asc_crud_btree() {
-        let nb_keys = 1000;
-        let mut btree = BTree::new();
-        test_insert(&mut btree, 0..nb_keys);
-        test_read(&mut btree, 0..nb_keys);
-        test_delete(&mut btree, 0..nb_keys);
-        assert!(btree.total_len() == 0)
+    /// `freeze` with an explicit layout of the internal levels
+    pub fn freeze_with(self, layout: FrozenLayout) -> FrozenTree {
+        let entries = self.into_iter();
+        let mut keys = Vec::with_capacity(entries.len());
+        let mut values = Vec::with_capacity(entries.len());
+        for (key, val) in entries {
+            keys.push(key);
+            values.push(val);
+        }
+        FrozenTree::from_sorted(keys, values, layout)
     }
+}
 
-    #[test]
-    // Insert, read, and delete in descending order
+// Every node of a tree is allocated through here, so that allocations are counted
+fn new_node<K: TreeKey, V: TreeValue, const LEAF: usize, N: Node<K, V, LEAF> + 'static>(
+    node: N,
+) -> NodePtr<K, V, LEAF> {
+    count!(nodes_allocated);
+    Arc::new(node)
+}
+
+// Mutable access to a node, copying it first if another tree or a snapshot
+// still shares it. The copy shares the children, so a write only copies the
+// path it goes down.
+fn make_mut<K: TreeKey, V: TreeValue, const LEAF: usize>(
+    node: &mut NodePtr<K, V, LEAF>,
+) -> &mut dyn Node<K, V, LEAF> {
+    if Arc::get_mut(node).is_none() {
+        count!(nodes_copied);
+        *node = node.clone_node();
+    }
+    let node = Arc::get_mut(node).unwrap();
+    #[cfg(feature = "merkle")]
+    if let Some(internal) = node.as_internal_mut() {
+        internal.hash.take();
+    }
+    node
+}
+
+// Split `len` items into chunks of at most `cap`, with the last two chunks
+// sharing the remainder so that no node ends up with a single child
+fn bulk_chunk_sizes(len: usize, cap: usize) -> Vec<usize> {
+    let mut sizes = vec![cap; len / cap];
+    let rem = len % cap;
+    if rem > 0 {
+        match sizes.pop() {
+            Some(full) => {
+                sizes.push((full + rem).div_ceil(2));
+                sizes.push((full + rem) / 2);
+            }
+            None => sizes.push(rem),
+        }
+    }
+    sizes
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> InternalNode<K, V, LEAF> {
+    pub fn new() -> InternalNode<K, V, LEAF> {
+        InternalNode {
+            pivots: ArrayVec::new(),
+            children: ArrayVec::new(),
+            len: 0,
+            #[cfg(feature = "merkle")]
+            hash: Default::default(),
+        }
+    }
+
+    pub fn new_from<I>(pivots: &[K], children: I) -> InternalNode<K, V, LEAF>
+    where
+        I: IntoIterator<Item = NodePtr<K, V, LEAF>>,
+    {
+        let mut p = ArrayVec::new();
+        let mut c = ArrayVec::new();
+        p.extend(pivots.iter().cloned());
+        children.into_iter().for_each(|x| c.push(x));
+        InternalNode {
+            len: c.iter().map(|x| x.total_len()).sum(),
+            pivots: p,
+            children: c,
+            #[cfg(feature = "merkle")]
+            hash: Default::default(),
+        }
+    }
+
+    pub fn new_with_key(
+        key: K,
+        left: NodePtr<K, V, LEAF>,
+        right: NodePtr<K, V, LEAF>,
+    ) -> InternalNode<K, V, LEAF> {
+        let mut node = InternalNode {
+            pivots: ArrayVec::new(),
+            len: left.total_len() + right.total_len(),
+            children: ArrayVec::new(),
+            #[cfg(feature = "merkle")]
+            hash: Default::default(),
+        };
+        node.pivots.push(key);
+        node.children.push(left);
+        node.children.push(right);
+        return node;
+    }
+
+    pub fn try_split(&mut self, idx: usize, policy: SplitPolicy, key: &K) {
+        if self.children[idx].is_full() {
+            let (pivot, child_node) = make_mut(&mut self.children[idx]).split(policy, key);
+            structure_event!(
+                pivot = ?pivot,
+                idx,
+                leaf = child_node.as_leaf().is_some(),
+                "child split"
+            );
+            emit(|| StructureEvent::Split {
+                pivot: pivot.clone(),
+                leaf: child_node.as_leaf().is_some(),
+            });
+            self.pivots.insert(idx, pivot);
+            self.children.insert(idx + 1, child_node);
+        }
+    }
+
+    /// Index of the child whose range contains `key` (keys equal to a pivot live on its right)
+    pub fn child_idx<Q: Ord + ?Sized>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+    {
+        match search(&self.pivots, key) {
+            Ok(idx) => idx + 1,
+            Err(idx) => idx,
+        }
+    }
+
+    // Called after a bulk removal, which can leave any number of children
+    // underfull: rebalance them one by one. A child that gains entries or
+    // children from a sibling can hold underfull children of its own, so it
+    // is rebalanced in turn.
+    fn rebalance_children(&mut self) {
+        while self.children.len() > 1 {
+            let Some(idx) = self.children.iter().position(|child| child.is_underfull()) else {
+                return;
+            };
+            let before = self.children.len();
+            self.rebalance_child(idx);
+            let changed = if self.children.len() < before {
+                idx.saturating_sub(1)
+            } else {
+                idx
+            };
+            if self.children[changed].as_internal().is_some() {
+                let child = make_mut(&mut self.children[changed]);
+                child.as_internal_mut().unwrap().rebalance_children();
+            }
+        }
+    }
+
+    // Called after a delete in `children[idx]`: a child left underfull takes
+    // an entry (or a child) from a sibling that can spare one, or is merged
+    // with a sibling. Our parent does the same for us on the way back up, and
+    // the root loses a level once it is left with a single child.
+    fn rebalance_child(&mut self, idx: usize) {
+        if !self.children[idx].is_underfull() || self.children.len() < 2 {
+            return;
+        }
+        if idx > 0 && self.children[idx - 1].can_lend() {
+            self.borrow_from_left(idx);
+        } else if idx + 1 < self.children.len() && self.children[idx + 1].can_lend() {
+            self.borrow_from_right(idx);
+        } else {
+            self.merge_children(idx.saturating_sub(1));
+        }
+    }
+
+    // Move the last entry or child of `children[idx - 1]` to `children[idx]`
+    fn borrow_from_left(&mut self, idx: usize) {
+        structure_event!(idx, "borrowed from left sibling");
+        count!(borrows);
+        let (before, after) = self.children.split_at_mut(idx);
+        let left = make_mut(&mut before[idx - 1]);
+        let child = make_mut(&mut after[0]);
+        let pivot = &mut self.pivots[idx - 1];
+        let old_pivot = match (left.as_leaf_mut(), child.as_leaf_mut()) {
+            (Some(left), Some(child)) => {
+                let key = left.keys.pop().unwrap();
+                child.keys.insert(0, key.clone());
+                child.values.insert(0, left.values.pop().unwrap());
+                std::mem::replace(pivot, key)
+            }
+            _ => {
+                let left = left.as_internal_mut().unwrap();
+                let child = child.as_internal_mut().unwrap();
+                let moved = left.children.pop().unwrap();
+                left.len -= moved.total_len();
+                child.len += moved.total_len();
+                child.children.insert(0, moved);
+                let old_pivot = std::mem::replace(pivot, left.pivots.pop().unwrap());
+                child.pivots.insert(0, old_pivot.clone());
+                old_pivot
+            }
+        };
+        emit(|| StructureEvent::Shift {
+            old_pivot,
+            new_pivot: pivot.clone(),
+        });
+    }
+
+    // Move the first entry or child of `children[idx + 1]` to `children[idx]`
+    fn borrow_from_right(&mut self, idx: usize) {
+        structure_event!(idx, "borrowed from right sibling");
+        count!(borrows);
+        let (before, after) = self.children.split_at_mut(idx + 1);
+        let child = make_mut(&mut before[idx]);
+        let right = make_mut(&mut after[0]);
+        let pivot = &mut self.pivots[idx];
+        let old_pivot = match (child.as_leaf_mut(), right.as_leaf_mut()) {
+            (Some(child), Some(right)) => {
+                child.keys.push(right.keys.remove(0));
+                child.values.push(right.values.remove(0));
+                std::mem::replace(pivot, right.keys[0].clone())
+            }
+            _ => {
+                let child = child.as_internal_mut().unwrap();
+                let right = right.as_internal_mut().unwrap();
+                let moved = right.children.remove(0);
+                right.len -= moved.total_len();
+                child.len += moved.total_len();
+                child.children.push(moved);
+                let old_pivot = std::mem::replace(pivot, right.pivots.remove(0));
+                child.pivots.push(old_pivot.clone());
+                old_pivot
+            }
+        };
+        emit(|| StructureEvent::Shift {
+            old_pivot,
+            new_pivot: pivot.clone(),
+        });
+    }
+
+    // Merge `children[idx + 1]` into `children[idx]`, both being at most at
+    // the minimum occupancy so that they fit in one node
+    fn merge_children(&mut self, idx: usize) {
+        structure_event!(idx, "merged with right sibling");
+        count!(merges);
+        let right = self.children.remove(idx + 1);
+        let pivot = self.pivots.remove(idx);
+        let left = make_mut(&mut self.children[idx]);
+        match (left.as_leaf_mut(), right.as_leaf()) {
+            (Some(left), Some(right)) => {
+                left.keys.extend(right.keys.iter().cloned());
+                left.values.extend(right.values.iter().cloned());
+            }
+            _ => {
+                let left = left.as_internal_mut().unwrap();
+                let right = right.as_internal().unwrap();
+                left.pivots.push(pivot.clone());
+                left.pivots.extend(right.pivots.iter().cloned());
+                left.children.extend(right.children.iter().cloned());
+                left.len += right.len;
+            }
+        }
+        emit(|| StructureEvent::Merge { pivot });
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> Node<K, V, LEAF> for InternalNode<K, V, LEAF> {
+    fn insert(&mut self, key: K, val: V, policy: SplitPolicy) -> Option<V> {
+        cost!(nodes_visited);
+        let mut idx = self.child_idx(&key);
+        self.try_split(idx, policy, &key);
+        if idx < self.pivots.len() && key >= self.pivots[idx] {
+            idx += 1; // Might be in right sibling
+        }
+        let previous = make_mut(&mut self.children[idx]).insert(key, val, policy);
+        self.len += previous.is_none() as usize;
+        previous
+    }
+
+    fn split(&mut self, policy: SplitPolicy, key: &K) -> (K, NodePtr<K, V, LEAF>) {
+        // The pivot at `mid` moves up, each side keeps at least one
+        let appending = self.pivots.last().is_some_and(|last| key >= last);
+        let mid = policy
+            .left_len(self.pivots.len(), appending)
+            .clamp(1, self.pivots.len() - 2);
+
+        count!(internal_splits);
+        let right_node = new_node(InternalNode::new_from(
+            &self.pivots[mid + 1..],
+            self.children.drain(mid + 1..),
+        ));
+        self.len -= right_node.total_len();
+        let pivot = self.pivots[mid].clone();
+        self.pivots.truncate(mid);
+        return (pivot, right_node);
+    }
+
+    fn retain(&mut self, f: &mut dyn FnMut(&K, &mut V) -> bool) -> usize {
+        let removed: usize = self
+            .children
+            .iter_mut()
+            .map(|child| make_mut(child).retain(f))
+            .sum();
+        self.len -= removed;
+        self.rebalance_children();
+        removed
+    }
+
+    fn split_off(&mut self, key: &K) -> NodePtr<K, V, LEAF> {
+        let idx = self.child_idx(key);
+        let mut right = InternalNode::new();
+        right
+            .children
+            .push(make_mut(&mut self.children[idx]).split_off(key));
+        right.children.extend(self.children.drain(idx + 1..));
+        right.pivots.extend(self.pivots.drain(idx..));
+        right.len = right.children.iter().map(|child| child.total_len()).sum();
+        self.len -= right.len;
+        self.rebalance_children();
+        right.rebalance_children();
+        new_node(right)
+    }
+
+    fn delete_range(&mut self, start: Bound<&K>, end: Bound<&K>) -> usize {
+        let first = match start {
+            Bound::Included(key) | Bound::Excluded(key) => self.child_idx(key),
+            Bound::Unbounded => 0,
+        };
+        let last = match end {
+            Bound::Included(key) | Bound::Excluded(key) => self.child_idx(key),
+            Bound::Unbounded => self.children.len() - 1,
+        };
+        // The part of the range within a child: the children between the
+        // first and the last one are entirely inside it
+        let within = |bound, edge| if edge { bound } else { Bound::Unbounded };
+        let mut removed = 0;
+        let mut idx = first;
+        for child in first..=last {
+            let (start, end) = (within(start, child == first), within(end, child == last));
+            if let (Bound::Unbounded, Bound::Unbounded) = (start, end) {
+                // Dropped without visiting it, along with a pivot next to it
+                removed += self.children.remove(idx).total_len();
+                self.pivots.remove(idx.min(self.pivots.len() - 1));
+            } else {
+                removed += make_mut(&mut self.children[idx]).delete_range(start, end);
+                idx += 1;
+            }
+        }
+        self.len -= removed;
+        self.rebalance_children();
+        removed
+    }
+
+    fn pop_entry(&mut self, last: bool) -> Option<(K, V)> {
+        cost!(nodes_visited);
+        let idx = if last { self.children.len() - 1 } else { 0 };
+        let popped = make_mut(&mut self.children[idx]).pop_entry(last);
+        if popped.is_some() {
+            self.len -= 1;
+            self.rebalance_child(idx);
+        }
+        popped
+    }
+
+    fn total_len(&self) -> usize {
+        self.len
+    }
+
+    fn is_full(&self) -> bool {
+        self.pivots.is_full()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pivots.is_empty()
+    }
+
+    fn is_underfull(&self) -> bool {
+        self.children.len() < MIN_CHILDREN
+    }
+
+    fn can_lend(&self) -> bool {
+        self.children.len() > MIN_CHILDREN
+    }
+
+    fn get_first_key(&self) -> K {
+        self.pivots[0].clone()
+    }
+
+    fn len(&self) -> usize {
+        self.pivots.len()
+    }
+
+    fn pop_first_child(&mut self) -> Option<NodePtr<K, V, LEAF>> {
+        let child = self.children.pop()?;
+        self.len -= child.total_len();
+        Some(child)
+    }
+
+    fn clone_node(&self) -> NodePtr<K, V, LEAF> {
+        Arc::new(self.clone())
+    }
+
+    fn check(&self, lower: Option<&K>, upper: Option<&K>, is_root: bool) -> Result<(), String> {
+        if self.children.len() != self.pivots.len() + 1 {
+            return Err(format!(
+                "internal node has {} pivots but {} children",
+                self.pivots.len(),
+                self.children.len()
+            ));
+        }
+        if !is_root && self.pivots.is_empty() {
+            return Err("non-root internal node has a single child".to_string());
+        }
+        check_sorted_in_range(&self.pivots, lower, upper)?;
+        let len: usize = self.children.iter().map(|child| child.total_len()).sum();
+        if self.len != len {
+            return Err(format!(
+                "internal node counts {} entries but its children hold {}",
+                self.len, len
+            ));
+        }
+        for (idx, child) in self.children.iter().enumerate() {
+            let child_lower = if idx == 0 {
+                lower
+            } else {
+                Some(&self.pivots[idx - 1])
+            };
+            let child_upper = self.pivots.get(idx).or(upper);
+            child
+                .check(child_lower, child_upper, false)
+                .map_err(|e| format!("child {} of pivots {:?} -> {}", idx, self.pivots, e))?;
+        }
+        Ok(())
+    }
+
+    fn as_leaf(&self) -> Option<&LeafNode<K, V, LEAF>> {
+        None
+    }
+
+    fn as_leaf_mut(&mut self) -> Option<&mut LeafNode<K, V, LEAF>> {
+        None
+    }
+
+    fn as_internal(&self) -> Option<&InternalNode<K, V, LEAF>> {
+        Some(self)
+    }
+
+    fn as_internal_mut(&mut self) -> Option<&mut InternalNode<K, V, LEAF>> {
+        Some(self)
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> LeafNode<K, V, LEAF> {
+    // Splitting a leaf needs 2 entries to leave both halves with one
+    const SPLITTABLE: () = assert!(LEAF >= 2, "a leaf must hold at least 2 entries");
+
+    pub fn new() -> LeafNode<K, V, LEAF> {
+        let () = Self::SPLITTABLE;
+        LeafNode {
+            keys: ArrayVec::new(),
+            values: ArrayVec::new(),
+        }
+    }
+
+    pub fn new_from(keys: &[K], values: &[V]) -> LeafNode<K, V, LEAF> {
+        let () = Self::SPLITTABLE;
+        let mut k = ArrayVec::new();
+        k.extend(keys.iter().cloned());
+        let mut v = ArrayVec::new();
+        v.extend(values.iter().cloned());
+        LeafNode { keys: k, values: v }
+    }
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> Node<K, V, LEAF> for LeafNode<K, V, LEAF> {
+    fn split(&mut self, policy: SplitPolicy, key: &K) -> (K, NodePtr<K, V, LEAF>) {
+        let appending = self.keys.last().is_some_and(|last| key > last);
+        let mid = policy
+            .left_len(self.keys.len(), appending)
+            .clamp(1, self.keys.len() - 1);
+
+        count!(leaf_splits);
+        let right_node = new_node(LeafNode::new_from(&self.keys[mid..], &self.values[mid..]));
+        let pivot = self.keys[mid].clone();
+        self.keys.truncate(mid);
+        self.values.truncate(mid);
+        return (pivot, right_node);
+    }
+
+    fn insert(&mut self, key: K, val: V, _: SplitPolicy) -> Option<V> {
+        cost!(nodes_visited);
+        match search(&self.keys, &key) {
+            Ok(idx) => Some(std::mem::replace(&mut self.values[idx], val)),
+            Err(idx) => {
+                self.keys.insert(idx, key);
+                self.values.insert(idx, val);
+                None
+            }
+        }
+    }
+
+    fn get_first_key(&self) -> K {
+        self.keys[0].clone()
+    }
+
+    fn retain(&mut self, f: &mut dyn FnMut(&K, &mut V) -> bool) -> usize {
+        let keep: ArrayVec<bool, LEAF> = self
+            .keys
+            .iter()
+            .zip(self.values.iter_mut())
+            .map(|(key, val)| f(key, val))
+            .collect();
+        let mut kept = keep.iter();
+        self.keys.retain(|_| *kept.next().unwrap());
+        let mut kept = keep.iter();
+        self.values.retain(|_| *kept.next().unwrap());
+        keep.len() - self.keys.len()
+    }
+
+    fn split_off(&mut self, key: &K) -> NodePtr<K, V, LEAF> {
+        let idx = self.keys.partition_point(|k| k < key);
+        let mut right = LeafNode::new();
+        right.keys.extend(self.keys.drain(idx..));
+        right.values.extend(self.values.drain(idx..));
+        new_node(right)
+    }
+
+    fn delete_range(&mut self, start: Bound<&K>, end: Bound<&K>) -> usize {
+        let first = self.keys.partition_point(|k| match start {
+            Bound::Included(key) => k < key,
+            Bound::Excluded(key) => k <= key,
+            Bound::Unbounded => false,
+        });
+        let last = self.keys.partition_point(|k| match end {
+            Bound::Included(key) => k <= key,
+            Bound::Excluded(key) => k < key,
+            Bound::Unbounded => true,
+        });
+        if first >= last {
+            return 0;
+        }
+        self.keys.drain(first..last);
+        self.values.drain(first..last);
+        last - first
+    }
+
+    fn pop_entry(&mut self, last: bool) -> Option<(K, V)> {
+        cost!(nodes_visited);
+        if last {
+            Some((self.keys.pop()?, self.values.pop()?))
+        } else if self.keys.is_empty() {
+            None
+        } else {
+            Some((self.keys.remove(0), self.values.remove(0)))
+        }
+    }
+
+    fn total_len(&self) -> usize {
+        return self.keys.len();
+    }
+
+    fn is_full(&self) -> bool {
+        self.keys.is_full()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    fn is_underfull(&self) -> bool {
+        self.keys.len() < LEAF / 2
+    }
+
+    fn can_lend(&self) -> bool {
+        self.keys.len() > LEAF / 2
+    }
+
+    fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    fn pop_first_child(&mut self) -> Option<NodePtr<K, V, LEAF>> {
+        None
+    }
+
+    fn clone_node(&self) -> NodePtr<K, V, LEAF> {
+        Arc::new(self.clone())
+    }
+
+    fn check(&self, lower: Option<&K>, upper: Option<&K>, is_root: bool) -> Result<(), String> {
+        if self.keys.len() != self.values.len() {
+            return Err(format!(
+                "leaf has {} keys but {} values",
+                self.keys.len(),
+                self.values.len()
+            ));
+        }
+        if !is_root && self.keys.is_empty() {
+            return Err("non-root leaf is empty".to_string());
+        }
+        check_sorted_in_range(&self.keys, lower, upper)
+    }
+
+    fn as_leaf(&self) -> Option<&LeafNode<K, V, LEAF>> {
+        Some(self)
+    }
+
+    fn as_leaf_mut(&mut self) -> Option<&mut LeafNode<K, V, LEAF>> {
+        Some(self)
+    }
+
+    fn as_internal(&self) -> Option<&InternalNode<K, V, LEAF>> {
+        None
+    }
+
+    fn as_internal_mut(&mut self) -> Option<&mut InternalNode<K, V, LEAF>> {
+        None
+    }
+}
+
+// Height of the subtree, if all its leaves are at the same depth
+fn checked_height<K: TreeKey, V: TreeValue, const LEAF: usize>(
+    node: &dyn Node<K, V, LEAF>,
+) -> Result<usize, String> {
+    let Some(internal) = node.as_internal() else {
+        return Ok(1);
+    };
+    let heights = internal
+        .children
+        .iter()
+        .map(|child| checked_height(&**child))
+        .collect::<Result<Vec<_>, _>>()?;
+    if heights.iter().any(|h| *h != heights[0]) {
+        return Err(format!(
+            "children of pivots {:?} have heights {:?}",
+            internal.pivots, heights
+        ));
+    }
+    Ok(heights[0] + 1)
+}
+
+// Number of levels of the subtree, leaves included
+fn node_height<K: TreeKey, V: TreeValue, const LEAF: usize>(
+    mut node: &dyn Node<K, V, LEAF>,
+) -> usize {
+    let mut height = 1;
+    while let Some(internal) = node.as_internal() {
+        node = &*internal.children[0];
+        height += 1;
+    }
+    height
+}
+
+// Remove `key` from the subtree of `node`, returning its value, and
+// rebalance the children on the way. A free function rather than a method of
+// `Node`, which can't be generic over the borrowed key type and stay `dyn`.
+fn delete<K, V, Q, const LEAF: usize>(node: &mut dyn Node<K, V, LEAF>, key: &Q) -> Option<V>
+where
+    K: TreeKey + Borrow<Q>,
+    V: TreeValue,
+    Q: Ord + ?Sized,
+{
+    cost!(nodes_visited);
+    if let Some(internal) = node.as_internal_mut() {
+        let idx = internal.child_idx(key);
+        let deleted = delete(make_mut(&mut internal.children[idx]), key);
+        if deleted.is_some() {
+            internal.len -= 1;
+            internal.rebalance_child(idx);
+        }
+        return deleted;
+    }
+    let leaf = node.as_leaf_mut().unwrap();
+    let idx = search(&leaf.keys, key).ok()?;
+    leaf.keys.remove(idx);
+    Some(leaf.values.remove(idx))
+}
+
+// Last entry of the subtree before `key`, or at it if `inclusive`. Pivots may
+// be left over from deleted keys, so the child `key` leads to can hold nothing
+// before it: the answer is then the last entry of a sibling to its left.
+fn last_before<K, V, Q, const LEAF: usize>(
+    node: &dyn Node<K, V, LEAF>,
+    key: &Q,
+    inclusive: bool,
+) -> Option<(K, V)>
+where
+    K: TreeKey + Borrow<Q>,
+    V: TreeValue,
+    Q: Ord + ?Sized,
+{
+    cost!(nodes_visited);
+    if let Some(internal) = node.as_internal() {
+        let idx = internal.child_idx(key);
+        return internal.children[..=idx]
+            .iter()
+            .rev()
+            .find_map(|child| last_before(&**child, key, inclusive));
+    }
+    let leaf = node.as_leaf().unwrap();
+    let end = match search(&leaf.keys, key) {
+        Ok(idx) if inclusive => idx + 1,
+        Ok(idx) | Err(idx) => idx,
+    };
+    let idx = end.checked_sub(1)?;
+    Some((leaf.keys[idx].clone(), leaf.values[idx].clone()))
+}
+
+/// Entry of `key` in the subtree of `node`, in a single descent. The
+/// lookups of the tree go through it.
+pub fn find<'a, K, V, Q, const LEAF: usize>(
+    mut node: &'a dyn Node<K, V, LEAF>,
+    key: &Q,
+) -> Option<(&'a K, &'a V)>
+where
+    K: TreeKey + Borrow<Q>,
+    V: TreeValue,
+    Q: Ord + ?Sized,
+{
+    while let Some(internal) = node.as_internal() {
+        cost!(nodes_visited);
+        node = &*internal.children[internal.child_idx(key)];
+    }
+    cost!(nodes_visited);
+    let leaf = node.as_leaf().unwrap();
+    let idx = search(&leaf.keys, key).ok()?;
+    Some((&leaf.keys[idx], &leaf.values[idx]))
+}
+
+// Binary search of a node's keys, counting comparisons with `op-costs`
+fn search<K: Borrow<Q>, Q: Ord + ?Sized>(keys: &[K], key: &Q) -> Result<usize, usize> {
+    keys.binary_search_by(|k| {
+        cost!(key_comparisons);
+        k.borrow().cmp(key)
+    })
+}
+
+// Keys must be strictly ascending and fall within `[lower, upper)`
+fn check_sorted_in_range<K: Ord + Debug>(
+    keys: &[K],
+    lower: Option<&K>,
+    upper: Option<&K>,
+) -> Result<(), String> {
+    if let Some(w) = keys.windows(2).find(|w| w[0] >= w[1]) {
+        return Err(format!("keys out of order: {:?} before {:?}", w[0], w[1]));
+    }
+    match (keys.first(), keys.last()) {
+        (Some(first), _) if lower.is_some_and(|l| first < l) => Err(format!(
+            "key {:?} is below its lower bound {:?}",
+            first,
+            lower.unwrap()
+        )),
+        (_, Some(last)) if upper.is_some_and(|u| last >= u) => Err(format!(
+            "key {:?} is not below its upper bound {:?}",
+            last,
+            upper.unwrap()
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::{self, Op};
+    use proptest::prelude::*;
+    use proptest::test_runner::{RngAlgorithm, TestRng, TestRunner};
+
+    fn test_insert<I>(btree: &mut BTree, keys: I)
+    where
+        I: Iterator<Item = u128>,
+    {
+        let mut key: Key = [0; 1];
+        let mut expected_len = 0;
+        for n in keys {
+            key[0] = n;
+            assert_eq!(btree.insert(key, 0), None);
+            expected_len += 1;
+            if expected_len < btree.total_len() as u128 {
+                println!("Tree: {:?}", btree);
+                assert!(false);
+            }
+        }
+    }
+
+    fn test_read<I>(btree: &mut BTree, keys: I)
+    where
+        I: Iterator<Item = u128>,
+    {
+        let mut key: Key = [0; 1];
+        for n in keys {
+            key[0] = n;
+            assert!(btree.get(&key).is_some());
+        }
+    }
+
+    fn test_delete<I>(btree: &mut BTree, keys: I)
+    where
+        I: Iterator<Item = u128>,
+    {
+        let mut key: Key = [0; 1];
+        for n in keys {
+            key[0] = n;
+            // println!("Tree: {:?}", btree);
+            // println!("Removing key: {:?}", key);
+            assert!(btree.delete(&key).is_some());
+        }
+    }
+
+    #[test]
+    // Insert, read, and delete in ascending order
+    fn test_asc_crud_btree() {
+        let nb_keys = 1000;
+        let mut btree = BTree::new();
+        test_insert(&mut btree, 0..nb_keys);
+        test_read(&mut btree, 0..nb_keys);
+        test_delete(&mut btree, 0..nb_keys);
+        assert!(btree.total_len() == 0)
+    }
+
+    #[test]
+    // Insert, read, and delete in descending order
     fn test_desc_crud_btree() {
         let nb_keys = 1000;
         let mut btree = BTree::new();
@@ -382,4 +1767,1012 @@ mod tests {
         test_delete(&mut btree, (0..nb_keys).rev());
         assert!(btree.total_len() == 0)
     }
+
+    #[test]
+    // Bulk load, then read, insert around, and delete everything
+    fn test_bulk_load_btree() {
+        let nb_keys = 1000;
+        let mut btree = BTree::bulk_load((0..nb_keys).map(|n| ([n * 2; 1], 0))).unwrap();
+        assert_eq!(btree.total_len(), nb_keys as usize);
+        test_read(&mut btree, (0..nb_keys).map(|n| n * 2));
+        for n in 0..nb_keys {
+            btree.insert([n * 2 + 1; 1], 0);
+        }
+        assert_eq!(btree.total_len(), 2 * nb_keys as usize);
+        test_read(&mut btree, 0..nb_keys * 2);
+        test_delete(&mut btree, 0..nb_keys * 2);
+        assert!(btree.total_len() == 0);
+
+        let unsorted = BTree::bulk_load([([2; 1], 0), ([1; 1], 0)]);
+        assert!(matches!(unsorted, Err(Error::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_bulk_load_chunk_sizes() {
+        assert_eq!(bulk_chunk_sizes(7, 7), vec![7]);
+        assert_eq!(bulk_chunk_sizes(8, 7), vec![4, 4]);
+        assert_eq!(bulk_chunk_sizes(16, 7), vec![7, 5, 4]);
+        assert_eq!(bulk_chunk_sizes(3, 7), vec![3]);
+    }
+
+    #[test]
+    fn test_check_invariants_detects_corruption() {
+        let unsorted: LeafNode = LeafNode::new_from(&[[2; 1], [1; 1]], &[0, 0]);
+        assert!(unsorted.check(None, None, true).is_err());
+
+        let leaf: LeafNode = LeafNode::new_from(&[[1; 1], [5; 1]], &[0, 0]);
+        assert!(leaf.check(Some(&[1; 1]), Some(&[6; 1]), false).is_ok());
+        assert!(leaf.check(Some(&[2; 1]), None, false).is_err());
+        assert!(leaf.check(None, Some(&[5; 1]), false).is_err());
+        assert!(LeafNode::<Key>::new().check(None, None, false).is_err());
+    }
+
+    #[test]
+    fn test_stats() {
+        let btree =
+            BTree::bulk_load((0..(LEAF_ITEMS_SIZE * 3) as u128).map(|k| ([k; 1], 0))).unwrap();
+        let stats = btree.stats();
+        assert_eq!(stats.height, 2);
+        assert_eq!(stats.leaf_nodes, 3);
+        assert_eq!(stats.internal_nodes, 1);
+        assert_eq!(stats.entries, LEAF_ITEMS_SIZE * 3);
+        assert_eq!(stats.leaf_fill(), 1.0);
+        assert_eq!(BTree::<Key>::new().stats().internal_fill(), 0.0);
+    }
+
+    #[test]
+    // Large keys and values in leaves of a few entries, kept within the
+    // node size
+    fn test_leaf_capacity() {
+        type Wide = [u128; 4];
+        let entry = std::mem::size_of::<Wide>() * 2;
+        assert!(std::mem::size_of::<LeafNode<Wide, Wide, 4>>() <= 4 * entry + 32);
+        assert!(std::mem::size_of::<LeafNode<Wide, Wide>>() > NODE_SIZE);
+
+        let mut btree = BTree::<Wide, Wide, 4>::sized(SplitPolicy::default());
+        let mut reference = std::collections::BTreeMap::new();
+        for k in (0..3000).map(|k| k * 7919 % 3001) {
+            btree.insert([k; 4], [k + 1; 4]);
+            reference.insert([k; 4], [k + 1; 4]);
+        }
+        for k in (0..3000).step_by(3) {
+            assert_eq!(btree.delete(&[k; 4]), reference.remove(&[k; 4]));
+        }
+        btree.check_invariants().unwrap();
+        assert!(btree.iter().eq(reference.clone()));
+        let stats = btree.stats();
+        assert_eq!(stats.leaf_capacity, 4);
+        assert!(stats.leaf_fill() >= 0.5);
+
+        let loaded = BTree::<Wide, Wide, 4>::bulk_load_sized(reference.clone()).unwrap();
+        loaded.check_invariants().unwrap();
+        assert_eq!(loaded.stats().leaf_nodes, reference.len().div_ceil(4));
+        let collected: BTree<Wide, Wide, 4> = reference.into_iter().collect();
+        assert_eq!(collected.len(), 2000);
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut btree = BTree::new();
+        assert_eq!(btree.iter().next(), None);
+        test_insert(&mut btree, (0..1000).rev());
+        test_delete(&mut btree, (0..1000).filter(|k| k % 3 == 0));
+        let expected: Vec<(Key, Value)> = (0..1000)
+            .filter(|k| k % 3 != 0)
+            .map(|k| ([k; 1], 0))
+            .collect();
+        assert_eq!(btree.iter().collect::<Vec<_>>(), expected);
+
+        let mut iter = btree.iter();
+        assert_eq!(iter.len(), expected.len());
+        iter.by_ref().take(100).for_each(drop);
+        assert_eq!(iter.len(), expected.len() - 100);
+        iter.by_ref().for_each(drop);
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.len(), 0);
+
+        // Counted lazily: a hint taken mid-scan covers only the rest
+        let mut iter = btree.iter();
+        assert_eq!(iter.nth(99), Some(expected[99]));
+        assert_eq!(
+            iter.size_hint(),
+            (expected.len() - 100, Some(expected.len() - 100))
+        );
+    }
+
+    #[test]
+    fn test_split_policy() {
+        let fill = |policy, keys: &mut dyn Iterator<Item = u128>| {
+            let mut btree = BTree::with_split_policy(policy);
+            keys.for_each(|k| {
+                btree.insert([k; 1], 0);
+            });
+            btree.check_invariants().unwrap();
+            assert_eq!(btree.total_len(), 5000);
+            btree.stats().leaf_fill()
+        };
+        assert!(fill(SplitPolicy::Midpoint, &mut (0..5000)) < 0.6);
+        assert!(fill(SplitPolicy::Append(90), &mut (0..5000)) > 0.85);
+        assert!(fill(SplitPolicy::Ratio(10), &mut (0..5000).rev()) > 0.85);
+        // Only appends are biased
+        let shuffled = &mut (0..5000).map(|k| k * 7919 % 5000);
+        assert!(fill(SplitPolicy::Append(90), shuffled) < 0.85);
+        for percent in [0, 1, 50, 99, 100, 200] {
+            fill(
+                SplitPolicy::Ratio(percent),
+                &mut (0..5000).map(|k| k * 7919 % 5000),
+            );
+        }
+    }
+
+    #[test]
+    fn test_iter_sampled() {
+        let mut btree = BTree::new();
+        (0..5000).for_each(|k| {
+            btree.insert([k; 1], 0);
+        });
+        (0..5000).step_by(7).for_each(|k| {
+            btree.delete(&[k; 1]);
+        });
+        let entries: Vec<_> = btree.iter().collect();
+        for step in [1, 2, 13, 100, 4285, 10_000] {
+            assert!(btree
+                .iter_sampled(step)
+                .eq(entries.iter().copied().step_by(step)));
+        }
+        let mut iter = btree.iter();
+        assert_eq!(iter.nth(100), Some(entries[100]));
+        assert_eq!(iter.len(), entries.len() - 101);
+        assert_eq!(iter.nth(entries.len()), None);
+        assert_eq!(BTree::<Key>::new().iter_sampled(3).next(), None);
+    }
+
+    #[test]
+    fn test_get_mut() {
+        let mut btree: BTree<Key, u64> = BTree::new();
+        (0..1000).for_each(|k| {
+            btree.insert([k; 1], 0);
+        });
+        let snapshot = btree.share();
+        for k in (0..1000).step_by(3) {
+            *btree.get_mut(&[k; 1]).unwrap() += k as u64;
+        }
+        assert_eq!(btree.get_mut(&[1000; 1]), None);
+        assert_eq!(btree.get(&[999; 1]), Some(999));
+        assert_eq!(btree.get(&[998; 1]), Some(0));
+        assert!(snapshot.iter().all(|(_, v)| v == 0));
+        btree.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn test_len() {
+        let mut btree = BTree::new();
+        assert!(btree.is_empty());
+        test_insert(&mut btree, 0..1000);
+        assert_eq!(btree.len(), 1000);
+        assert_eq!(btree.insert([500; 1], 1), Some(0));
+        assert_eq!(btree.insert([500; 1], 2), Some(1));
+        assert_eq!(btree.len(), 1000);
+        assert_eq!(btree.delete(&[1000; 1]), None);
+        test_delete(&mut btree, 0..1000);
+        assert!(btree.is_empty());
+    }
+
+    #[test]
+    // Used as a priority index: pop the smallest and largest entries
+    fn test_first_last_key_value() {
+        let mut btree = BTree::new();
+        assert_eq!(btree.first_key_value(), None);
+        assert_eq!(btree.last_key_value(), None);
+        for k in (0..2003).map(|k| k * 7919 % 2003) {
+            btree.insert([k; 1], (k % 256) as Value);
+        }
+        assert_eq!(btree.first_key_value(), Some(([0; 1], 0)));
+        assert_eq!(btree.last_key_value(), Some(([2002; 1], 210)));
+        for k in 0..1001 {
+            let (first, _) = btree.first_key_value().unwrap();
+            let (last, _) = btree.last_key_value().unwrap();
+            assert_eq!((first[0], last[0]), (k, 2002 - k));
+            btree.delete(&first);
+            btree.delete(&last);
+        }
+        assert_eq!(btree.first_key_value(), Some(([1001; 1], 233)));
+        assert_eq!(btree.share().last_key_value(), Some(([1001; 1], 233)));
+    }
+
+    #[test]
+    // Used as an ordered work queue: filled at the back, drained from the
+    // front, then emptied from the back
+    fn test_pop_first_last() {
+        let mut btree = BTree::new();
+        assert_eq!(btree.pop_first(), None);
+        test_insert(&mut btree, 0..3000);
+        let snapshot = btree.share();
+        for k in 0..1000 {
+            assert_eq!(btree.pop_first(), Some(([k; 1], 0)));
+            btree.insert([3000 + k; 1], 1);
+            if k % 100 == 0 {
+                btree.check_invariants().unwrap();
+                assert!(half_full(&*btree.root, true));
+            }
+        }
+        assert_eq!(btree.len(), 3000);
+        assert_eq!(btree.first_key_value(), Some(([1000; 1], 0)));
+        for k in (1000..4000).rev() {
+            assert_eq!(btree.pop_last().map(|(k, _)| k[0]), Some(k));
+        }
+        assert_eq!(btree.pop_last(), None);
+        btree.check_invariants().unwrap();
+        assert_eq!(btree.stats().height, 1);
+        assert_eq!(snapshot.len(), 3000);
+        assert_eq!(snapshot.first_key_value(), Some(([0; 1], 0)));
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut btree = BTree::new();
+        test_insert(&mut btree, 0..3000);
+        let snapshot = btree.share();
+        let events = Arc::new(std::sync::Mutex::new(0));
+        let counter = events.clone();
+        btree.on_structure_change(move |_| *counter.lock().unwrap() += 1);
+
+        btree.clear();
+        assert!(btree.is_empty());
+        assert_eq!(btree.iter().next(), None);
+        assert_eq!(btree.stats().height, 1);
+        btree.check_invariants().unwrap();
+        assert_eq!(snapshot.len(), 3000);
+        assert_eq!(snapshot.get(&[1500; 1]), Some(0));
+
+        // The tree is usable again, hooks included
+        test_insert(&mut btree, 0..1000);
+        assert_eq!(btree.len(), 1000);
+        btree.check_invariants().unwrap();
+        assert!(*events.lock().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut btree = BTree::new();
+        for k in 0..3000 {
+            btree.insert([k; 1], vec![k as u8; 4]);
+        }
+        let snapshot = btree.share();
+        let drained: Vec<_> = btree.drain().collect();
+        assert!(btree.is_empty());
+        btree.check_invariants().unwrap();
+        assert_eq!(drained.len(), 3000);
+        assert!(drained
+            .iter()
+            .eq(snapshot.iter().collect::<Vec<_>>().iter()));
+
+        // The tree takes writes again; a drain dropped early empties it all the same
+        for k in 0..3000 {
+            btree.insert([k; 1], vec![0; 4]);
+        }
+        let mut drain = btree.drain();
+        assert_eq!(drain.len(), 3000);
+        assert_eq!(drain.nth(10), Some(([10; 1], vec![0; 4])));
+        assert_eq!(drain.len(), 2989);
+        drop(drain);
+        assert!(btree.is_empty());
+        assert_eq!(snapshot.get(&[10; 1]), Some(vec![10; 4]));
+    }
+
+    #[test]
+    // Ranges keyed by their start: `floor` finds the range a key falls into.
+    // Deleting every third range leaves pivots of deleted keys behind.
+    fn test_bounds() {
+        let mut btree = BTree::new();
+        let mut reference = std::collections::BTreeMap::new();
+        for k in (1..3000).map(|k| k * 10) {
+            btree.insert([k; 1], (k % 256) as Value);
+            reference.insert([k; 1], (k % 256) as Value);
+        }
+        for k in (1..3000).filter(|k| k % 3 == 0).map(|k| k * 10) {
+            btree.delete(&[k; 1]);
+            reference.remove(&[k; 1]);
+        }
+        btree.check_invariants().unwrap();
+        let entry = |(k, v): (&Key, &Value)| (*k, *v);
+        for k in 0..30_010 {
+            let key = [k; 1];
+            assert_eq!(
+                btree.lower_bound(&key),
+                reference.range(key..).next().map(entry)
+            );
+            assert_eq!(
+                btree.upper_bound(&key),
+                reference
+                    .range((Bound::Excluded(key), Bound::Unbounded))
+                    .next()
+                    .map(entry)
+            );
+            assert_eq!(
+                btree.floor(&key),
+                reference.range(..=key).next_back().map(entry)
+            );
+            assert_eq!(
+                btree.predecessor(&key),
+                reference.range(..key).next_back().map(entry)
+            );
+        }
+        let snapshot = btree.share();
+        assert_eq!(snapshot.floor(&[35; 1]), Some(([20; 1], 20)));
+        assert_eq!(snapshot.upper_bound(&[29_990; 1]), None);
+        assert_eq!(BTree::<Key>::new().floor(&[1; 1]), None);
+    }
+
+    #[test]
+    fn test_range() {
+        use std::ops::Bound::{Excluded, Included, Unbounded};
+        let mut btree = BTree::new();
+        let mut reference = std::collections::BTreeMap::new();
+        for k in (0..5000).map(|k| k * 2) {
+            btree.insert([k; 1], (k % 256) as Value);
+            reference.insert([k; 1], (k % 256) as Value);
+        }
+        let bounds = |k: u128| [Included([k; 1]), Excluded([k; 1]), Unbounded];
+        for (lo, hi) in [
+            (0, 10_000),
+            (1, 7),
+            (100, 100),
+            (100, 101),
+            (3001, 6000),
+            (9998, 20_000),
+        ] {
+            for start in bounds(lo) {
+                for end in bounds(hi) {
+                    // Not `BTreeMap::range`, which panics on empty ranges
+                    let expected: Vec<_> = reference
+                        .iter()
+                        .filter(|(k, _)| (start, end).contains(*k))
+                        .map(|(k, v)| (*k, *v))
+                        .collect();
+                    assert_eq!(btree.range((start, end)).len(), expected.len());
+                    assert!(
+                        btree.range((start, end)).eq(expected),
+                        "{:?}..{:?}",
+                        start,
+                        end
+                    );
+                }
+            }
+        }
+        assert_eq!(btree.range([10; 1]..[2; 1]).next(), None);
+        assert_eq!(btree.range([20_000; 1]..).next(), None);
+        assert_eq!(BTree::<Key>::new().range(..).next(), None);
+
+        let mut range = btree.range([4; 1]..[8; 1]);
+        assert_eq!(range.len(), 2);
+        assert_eq!(range.by_ref().count(), 2);
+        assert_eq!(range.next(), None);
+        assert_eq!(range.next_back(), None);
+    }
+
+    #[test]
+    // Latest entries below a timestamp, and both ends of a range consumed
+    // until they meet
+    fn test_range_rev() {
+        let mut btree = BTree::new();
+        test_insert(&mut btree, (0..5000).map(|k| k * 2));
+        let latest: Vec<_> = btree
+            .range(..[7001; 1])
+            .rev()
+            .take(3)
+            .map(|(k, _)| k[0])
+            .collect();
+        assert_eq!(latest, vec![7000, 6998, 6996]);
+        assert!(btree
+            .range(..)
+            .rev()
+            .map(|(k, _)| k[0])
+            .eq((0..5000).rev().map(|k| k * 2)));
+        assert_eq!(btree.range([9000; 1]..=[8000; 1]).next_back(), None);
+        assert_eq!(btree.range([10_000; 1]..).next_back(), None);
+
+        for (lo, hi) in [(0, 10_000), (1001, 1003), (1000, 1001), (3001, 6000)] {
+            let expected: Vec<u128> = (lo..hi).filter(|k| k % 2 == 0).collect();
+            // Alternate ends, then finish from the front
+            let mut range = btree.range([lo; 1]..[hi; 1]);
+            let (mut front, mut back) = (Vec::new(), Vec::new());
+            for step in 0.. {
+                let item = if step % 3 == 0 {
+                    range.next().map(|(k, _)| front.push(k[0]))
+                } else {
+                    range.next_back().map(|(k, _)| back.push(k[0]))
+                };
+                if item.is_none() || step == 100 {
+                    break;
+                }
+            }
+            assert_eq!(range.len(), expected.len() - front.len() - back.len());
+            front.extend(range.by_ref().map(|(k, _)| k[0]));
+            front.extend(back.iter().rev());
+            assert_eq!(front, expected, "{}..{}", lo, hi);
+            assert_eq!((range.next(), range.next_back()), (None, None));
+        }
+    }
+
+    #[test]
+    fn test_keys_values() {
+        let mut btree: BTree<u64, Vec<u8>> = BTree::new();
+        (0..3000u64).for_each(|k| {
+            btree.insert(k * 3, vec![(k % 256) as u8; 4]);
+        });
+        assert!(btree.keys().copied().eq((0..3000).map(|k| k * 3)));
+        assert_eq!(btree.keys().len(), 3000);
+        assert_eq!(btree.keys().nth(1000), Some(&3000));
+        assert_eq!(btree.values().map(|v| v.len()).sum::<usize>(), 3000 * 4);
+        assert_eq!(btree.values().nth(300), Some(&vec![44; 4]));
+
+        // Rewrite every value in one pass; the snapshot keeps the old ones
+        let snapshot = btree.share();
+        let values = btree.values_mut();
+        assert_eq!(values.len(), 3000);
+        values.for_each(|v| v.push(1));
+        btree.check_invariants().unwrap();
+        assert!(btree.values().all(|v| v.len() == 5 && v[4] == 1));
+        assert!(snapshot.values().all(|v| v.len() == 4));
+        assert_eq!(BTree::<u64, u64>::new().values_mut().next(), None);
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut base = BTree::new();
+        test_insert(&mut base, 0..3000);
+        let ops = prop::collection::vec(any::<u8>(), 1..8);
+        run_seeded(ops, |thresholds| {
+            // Shares its nodes with `base`, which must not see the changes
+            let mut btree = base.clone();
+            let mut reference: Vec<u128> = (0..3000).collect();
+            // Successive filters, from sparse removals to emptying the tree
+            for threshold in thresholds {
+                let keep = |k: u128| (k.wrapping_mul(2_654_435_761) >> 8) as u8 >= threshold;
+                btree.retain(|k, v| {
+                    *v = v.wrapping_add(1);
+                    keep(k[0])
+                });
+                reference.retain(|k| keep(*k));
+                btree
+                    .check_invariants()
+                    .map_err(|e| TestCaseError::fail(e.to_string()))?;
+                prop_assert!(half_full(&*btree.root, true));
+                prop_assert!(btree.keys().map(|k| k[0]).eq(reference.iter().copied()));
+            }
+            prop_assert!(base.iter().eq((0..3000).map(|k| ([k; 1], 0))));
+            Ok(())
+        });
+
+        let mut btree = base;
+        btree.retain(|k, _| k[0] % 1000 == 0);
+        assert_eq!(
+            btree.keys().map(|k| k[0]).collect::<Vec<_>>(),
+            vec![0, 1000, 2000]
+        );
+        assert_eq!(btree.stats().height, 1);
+        btree.retain(|_, _| false);
+        assert!(btree.is_empty());
+        btree.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let mut base = BTree::new();
+        test_insert(&mut base, 0..3000);
+        let bound = (0..3u8, 0..3100u128).prop_map(|(kind, key)| match kind {
+            0 => Bound::Included([key; 1]),
+            1 => Bound::Excluded([key; 1]),
+            _ => Bound::Unbounded,
+        });
+        let ops = prop::collection::vec((bound.clone(), bound), 1..6);
+        run_seeded(ops, |ranges| {
+            // Shares its nodes with `base`, which must not see the changes
+            let mut btree = base.clone();
+            let mut reference: std::collections::BTreeMap<u128, Value> =
+                (0..3000).map(|k| (k, 0)).collect();
+            for (start, end) in ranges {
+                let inside = |k: &u128| (start, end).contains(&[*k; 1]);
+                let expected = reference.keys().filter(|k| inside(k)).count();
+                reference.retain(|k, _| !inside(k));
+                prop_assert_eq!(btree.delete_range((start, end)), expected);
+                btree
+                    .check_invariants()
+                    .map_err(|e| TestCaseError::fail(e.to_string()))?;
+                prop_assert!(half_full(&*btree.root, true));
+                prop_assert!(btree.keys().map(|k| k[0]).eq(reference.keys().copied()));
+            }
+            prop_assert_eq!(base.len(), 3000);
+            Ok(())
+        });
+
+        // The subtrees inside the range are dropped, not copied from the
+        // snapshot sharing them
+        let mut btree = base.clone();
+        let (removed, metrics) = metrics::measure(|| btree.delete_range([1; 1]..[2999; 1]));
+        assert_eq!(removed, 2998);
+        assert!(metrics.nodes_copied < 20, "{:?}", metrics);
+        assert_eq!(
+            btree.iter().collect::<Vec<_>>(),
+            [([0; 1], 0), ([2999; 1], 0)]
+        );
+        assert_eq!(btree.stats().height, 1);
+        assert_eq!(btree.delete_range(..), 2);
+        assert!(btree.is_empty());
+        btree.check_invariants().unwrap();
+    }
+
+    #[test]
+    // Shard a tree by key range, and split at keys outside of it
+    fn test_split_off() {
+        let mut base = BTree::new();
+        test_insert(&mut base, (0..4000).map(|k| k * 2));
+        for at in [0, 1, 2, 777, 1000, 4001, 7998, 7999, 10_000] {
+            let mut lower = base.clone();
+            let upper = lower.split_off(&[at; 1]);
+            for tree in [&lower, &upper] {
+                tree.check_invariants().unwrap();
+                assert!(half_full(&*tree.root, true), "split at {}", at);
+            }
+            assert!(lower.keys().all(|k| k[0] < at));
+            assert!(upper.keys().all(|k| k[0] >= at));
+            assert_eq!(lower.len() + upper.len(), 4000);
+            assert!(lower.iter().chain(upper.iter()).eq(base.iter()));
+        }
+        assert_eq!(base.len(), 4000);
+
+        let mut shards = vec![base];
+        for at in [6000, 4000, 2000] {
+            let upper = shards[0].split_off(&[at; 1]);
+            shards.insert(1, upper);
+        }
+        assert_eq!(
+            shards.iter().map(|shard| shard.len()).collect::<Vec<_>>(),
+            vec![1000; 4]
+        );
+        assert_eq!(shards[2].first_key_value(), Some(([4000; 1], 0)));
+    }
+
+    #[test]
+    // Merge per-shard trees of all sizes, in either order
+    fn test_append() {
+        let shard = |range: std::ops::Range<u128>| {
+            let mut btree = BTree::new();
+            test_insert(&mut btree, range);
+            btree
+        };
+        for (low, high) in [
+            (0..1500, 1500..3000),
+            (0..10, 10..3000),
+            (0..2990, 2990..3000),
+            (0..1, 1..2),
+            (0..0, 0..3000),
+            (0..3000, 3000..3000),
+        ] {
+            let expected: Vec<_> = low.clone().chain(high.clone()).collect();
+            for (mut btree, other) in [
+                (shard(low.clone()), shard(high.clone())),
+                (shard(high.clone()), shard(low.clone())),
+            ] {
+                btree.append(other);
+                btree.check_invariants().unwrap();
+                assert!(half_full(&*btree.root, true));
+                assert!(btree.keys().map(|k| k[0]).eq(expected.iter().copied()));
+            }
+        }
+
+        // Shards built in parallel, merged back in any order
+        let mut shards: Vec<_> = (0..8).map(|n| shard(n * 1000..n * 1000 + 1000)).collect();
+        let snapshot = shards[3].share();
+        let mut merged = shards.remove(3);
+        for other in shards {
+            merged.append(other);
+        }
+        merged.check_invariants().unwrap();
+        assert!(merged.keys().map(|k| k[0]).eq(0..8000));
+        assert_eq!(snapshot.len(), 1000);
+
+        // Interleaved keys are inserted one by one
+        let mut evens: BTree = (0..1000).map(|k| ([k * 2; 1], 0)).collect();
+        evens.append((0..1000).map(|k| ([k * 3; 1], 1)).collect());
+        evens.check_invariants().unwrap();
+        assert_eq!(evens.len(), 1000 + 1000 - 334);
+        assert_eq!(evens.get(&[6; 1]), Some(1));
+    }
+
+    #[test]
+    fn test_from_iter_extend() {
+        let mut btree: BTree<u64, u64> = (0..1000).map(|k| (k, k * 2)).collect();
+        btree.extend((500..1500).map(|k| (k, k)));
+        btree.check_invariants().unwrap();
+        assert_eq!(btree.len(), 1500);
+        assert_eq!(btree.get(&400), Some(800));
+        assert_eq!(btree.get(&600), Some(600));
+
+        let mut sum = 0;
+        for (_, v) in &btree {
+            sum += v;
+        }
+        assert_eq!(
+            sum,
+            (0..500).map(|k| k * 2).sum::<u64>() + (500..1500).sum::<u64>()
+        );
+        let evens: BTree<u64, u64> = btree.into_iter().filter(|(k, _)| k % 2 == 0).collect();
+        assert_eq!(evens.len(), 750);
+    }
+
+    #[test]
+    fn test_into_iter() {
+        assert_eq!(BTree::<Key>::new().into_iter().next(), None);
+        let mut btree = BTree::new();
+        test_insert(&mut btree, (0..1000).rev());
+        let expected: Vec<(Key, Value)> = btree.iter().collect();
+        let mut iter = btree.into_iter();
+        assert_eq!(iter.next(), Some(([0; 1], 0)));
+        assert_eq!(iter.len(), expected.len() - 1);
+        let rest: Vec<(Key, Value)> = iter.collect();
+        assert_eq!(rest, expected[1..]);
+
+        // Dropping a partially consumed iterator frees the rest of the tree
+        let mut btree = BTree::new();
+        test_insert(&mut btree, 0..1000);
+        let mut iter = btree.into_iter();
+        assert_eq!(iter.nth(500), Some(([500; 1], 0)));
+        drop(iter);
+    }
+
+    #[test]
+    fn test_send() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<BTree>();
+
+        let mut btree = BTree::new();
+        test_insert(&mut btree, 0..1000);
+        let btree = std::thread::spawn(move || {
+            test_delete(&mut btree, 0..500);
+            btree
+        })
+        .join()
+        .unwrap();
+        assert_eq!(btree.total_len(), 500);
+    }
+
+    #[test]
+    fn test_string_keys() {
+        let key = |k: u32| format!("user:{:05}", k);
+        let mut btree = BTree::new();
+        let events = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = events.clone();
+        btree.on_structure_change(move |_: &StructureEvent<String>| {
+            counted.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        });
+        (0..1000).rev().for_each(|k| {
+            btree.insert(key(k), (k % 256) as Value);
+        });
+        btree.check_invariants().unwrap();
+        assert!(events.load(std::sync::atomic::Ordering::Relaxed) > 0);
+        assert_eq!(btree.get(&key(42)), Some(42));
+        assert_eq!(btree.get(&"user".to_string()), None);
+        assert_eq!(btree.delete(&key(42)), Some(42));
+        assert_eq!(btree.total_len(), 999);
+
+        let keys: Vec<String> = btree.iter().map(|(k, _)| k).collect();
+        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+        let loaded = BTree::bulk_load(btree.share().iter()).unwrap();
+        loaded.check_invariants().unwrap();
+        assert!(loaded.into_iter().map(|(k, _)| k).eq(keys));
+    }
+
+    #[test]
+    fn test_vec_values() {
+        let mut btree: BTree<u64, Vec<u8>> = BTree::new();
+        (0..1000u64).for_each(|k| {
+            btree.insert(k << 32, k.to_le_bytes().to_vec());
+        });
+        btree.check_invariants().unwrap();
+        assert_eq!(btree.get(&(7 << 32)), Some(vec![7, 0, 0, 0, 0, 0, 0, 0]));
+        btree.insert(7 << 32, vec![]);
+        assert_eq!(btree.get(&(7 << 32)), Some(vec![]));
+        assert_eq!(btree.get(&7), None);
+        assert!(btree.contains_key(&(7 << 32)) && !btree.contains_key(&7));
+
+        let mut batch = WriteBatch::new();
+        batch.insert(1, vec![1; 100]).delete(0);
+        btree.apply(&batch);
+        let snapshot = btree.share();
+        assert_eq!(snapshot.get(&1).map(|v| v.len()), Some(100));
+        assert!(snapshot.contains_key(&1) && !snapshot.contains_key(&0));
+        assert_eq!(
+            snapshot.iter().map(|(_, v)| v.len()).sum::<usize>(),
+            999 * 8 + 100 - 8
+        );
+    }
+
+    #[test]
+    // String keys looked up by `&str`, without building a `String`
+    fn test_borrowed_lookups() {
+        let mut btree: BTree<String, usize> = BTree::new();
+        for n in 0..2000 {
+            btree.insert(format!("user:{:05}", n), n);
+        }
+        assert_eq!(btree.get("user:01234"), Some(1234));
+        assert_eq!(btree.get("user:1234"), None);
+        assert!(btree.contains_key("user:00000") && !btree.contains_key("user:"));
+        assert_eq!(
+            btree.get_key_value("user:00042"),
+            Some(("user:00042".to_string(), 42))
+        );
+        *btree.get_mut("user:00042").unwrap() += 1;
+        assert_eq!(btree.get(&"user:00042".to_string()), Some(43));
+
+        let snapshot = btree.share();
+        assert_eq!(btree.delete("user:00042"), Some(43));
+        assert_eq!(btree.delete("user:00042"), None);
+        assert_eq!(btree.get_key_value("user:00042"), None);
+        assert_eq!(
+            snapshot.get_key_value("user:00042").map(|(_, v)| v),
+            Some(43)
+        );
+        assert!(snapshot.contains_key("user:01999"));
+
+        let value = |entry: Option<(String, usize)>| entry.map(|(_, v)| v);
+        assert_eq!(value(btree.lower_bound("user:00042")), Some(43));
+        assert_eq!(value(btree.upper_bound("user:00041")), Some(43));
+        assert_eq!(value(btree.floor("user:00042")), Some(41));
+        assert_eq!(value(btree.predecessor("user:00041")), Some(40));
+        assert_eq!(value(snapshot.floor("user:00042")), Some(43));
+        assert_eq!(btree.rank("user:00042"), 42);
+        assert_eq!(snapshot.rank("user:00043"), 43);
+    }
+
+    #[test]
+    #[cfg(feature = "op-costs")]
+    fn test_op_costs() {
+        let mut btree = BTree::new();
+        test_insert(&mut btree, 0..1000);
+        let height = btree.stats().height as u64;
+        let (val, cost) = btree.get_traced(&[500; 1]);
+        assert_eq!(val, Some(0));
+        assert_eq!(cost.nodes_visited, height);
+        assert!(cost.key_comparisons >= height);
+
+        let cost = btree.insert_traced([1000; 1], 0);
+        assert_eq!(cost.nodes_visited, btree.stats().height as u64);
+        let (deleted, cost) = btree.delete_traced(&[1000; 1]);
+        assert_eq!(deleted, Some(0));
+        assert_eq!(cost.leaf_splits, 0);
+    }
+
+    #[test]
+    fn test_metrics() {
+        metrics::reset();
+        let mut btree = BTree::new();
+        test_insert(&mut btree, 0..1000);
+        let m = metrics::snapshot();
+        assert_eq!(m.leaf_splits as usize + 1, btree.stats().leaf_nodes);
+        assert_eq!(m.root_splits as usize + 1, btree.stats().height);
+        assert_eq!(
+            m.nodes_allocated as usize,
+            btree.stats().leaf_nodes + btree.stats().internal_nodes
+        );
+
+        test_delete(&mut btree, 0..1000);
+        let m = metrics::snapshot();
+        assert_eq!(m.root_collapses, m.root_splits);
+        assert!(m.merges > 0 && m.borrows > 0);
+    }
+
+    #[test]
+    fn test_shape_roundtrip_and_forced_split_merge() {
+        use testing::Shape;
+        let shape = Shape::internal(
+            &[10, 20],
+            vec![
+                Shape::leaf(&[1, 5]),
+                Shape::leaf(&[10, 15]),
+                Shape::leaf(&[20, 25, 30]),
+            ],
+        );
+        let mut btree = testing::build(&shape).unwrap();
+        assert_eq!(testing::shape(&btree), shape);
+        assert_eq!(testing::height(&btree), 2);
+
+        assert!(testing::split_leaf(&mut btree, &[30; 1]));
+        assert_eq!(
+            testing::leaf_ranges(&btree),
+            vec![([1], [5]), ([10], [15]), ([20], [20]), ([25], [30])]
+        );
+        assert!(testing::merge_leaf(&mut btree, &[20; 1]));
+        assert_eq!(testing::shape(&btree), shape);
+
+        assert!(testing::merge_leaf(&mut btree, &[1; 1]));
+        assert!(testing::merge_leaf(&mut btree, &[1; 1]));
+        assert_eq!(testing::height(&btree), 1);
+        assert_eq!(btree.total_len(), 7);
+
+        let too_big: Vec<u128> = (0..=LEAF_ITEMS_SIZE as u128).collect();
+        let result = testing::build(&Shape::leaf(&too_big));
+        assert!(matches!(result, Err(Error::Capacity(_))));
+        let result = testing::build(&Shape::leaf(&[2, 1]));
+        assert!(matches!(result, Err(Error::Corruption(_))));
+    }
+
+    // Structural snapshots of canonical operation sequences, so a change to
+    // the split/merge logic shows up as a reviewed snapshot diff. The
+    // snapshots are recorded with the default node size only.
+    fn assert_shape_snapshot(name: &str, btree: &BTree) {
+        if NODE_SIZE != 256 {
+            return;
+        }
+        btree.check_invariants().unwrap();
+        insta::assert_snapshot!(name, testing::dump(btree));
+    }
+
+    #[test]
+    fn test_snapshot_insert_asc() {
+        let mut btree = BTree::new();
+        test_insert(&mut btree, 0..100);
+        assert_shape_snapshot("insert_asc", &btree);
+    }
+
+    #[test]
+    fn test_snapshot_insert_desc() {
+        let mut btree = BTree::new();
+        test_insert(&mut btree, (0..100).rev());
+        assert_shape_snapshot("insert_desc", &btree);
+    }
+
+    #[test]
+    fn test_snapshot_insert_interleaved() {
+        let mut btree = BTree::new();
+        test_insert(&mut btree, (0..100).step_by(2).chain((1..100).step_by(2)));
+        assert_shape_snapshot("insert_interleaved", &btree);
+    }
+
+    #[test]
+    fn test_snapshot_delete_every_other() {
+        let mut btree = BTree::new();
+        test_insert(&mut btree, 0..100);
+        test_delete(&mut btree, (0..100).step_by(2));
+        assert_shape_snapshot("delete_every_other", &btree);
+    }
+
+    #[test]
+    fn test_snapshot_delete_prefix() {
+        let mut btree = BTree::new();
+        test_insert(&mut btree, 0..100);
+        test_delete(&mut btree, 0..90);
+        assert_shape_snapshot("delete_prefix", &btree);
+    }
+
+    #[test]
+    fn test_snapshot_bulk_load() {
+        let btree = BTree::bulk_load((0..100).map(|k| ([k; 1], 0))).unwrap();
+        assert_shape_snapshot("bulk_load", &btree);
+    }
+
+    #[test]
+    // Deleting the first key of a leaf, which is also its parent's pivot
+    fn test_delete_first_key_of_leaf_that_is_pivot() {
+        use testing::Shape;
+        let mut btree = testing::build(&Shape::internal(
+            &[10],
+            vec![Shape::leaf(&[1, 5]), Shape::leaf(&[10, 15])],
+        ))
+        .unwrap();
+        assert_eq!(btree.delete(&[10; 1]), Some(0));
+        assert_eq!(btree.get(&[10; 1]), None);
+        assert_eq!(btree.get(&[15; 1]), Some(0));
+        btree.check_invariants().unwrap();
+        assert_eq!(btree.delete(&[15; 1]), Some(0));
+        btree.check_invariants().unwrap();
+        assert_eq!(testing::shape(&btree), Shape::leaf(&[1, 5]));
+    }
+
+    // Every non-root node at least at the minimum occupancy
+    fn half_full(node: &dyn Node, is_root: bool) -> bool {
+        (is_root || !node.is_underfull())
+            && node.as_internal().is_none_or(|internal| {
+                internal
+                    .children
+                    .iter()
+                    .all(|child| half_full(&**child, false))
+            })
+    }
+
+    #[test]
+    // Interleaved inserts and deletes keep the non-root nodes at least half
+    // full, and the tree balanced
+    fn test_delete_rebalancing() {
+        let ops = prop::collection::vec((0..500u128, any::<bool>()), 1..2000);
+        run_seeded(ops, |ops| {
+            let mut btree = BTree::new();
+            let mut reference = std::collections::BTreeSet::new();
+            (0..500).for_each(|k| {
+                btree.insert([k; 1], 0);
+            });
+            reference.extend(0..500);
+            for (k, insert) in ops {
+                if insert {
+                    btree.insert([k; 1], 0);
+                    reference.insert(k);
+                } else {
+                    prop_assert_eq!(btree.delete(&[k; 1]).is_some(), reference.remove(&k));
+                }
+            }
+            btree
+                .check_invariants()
+                .map_err(|e| TestCaseError::fail(e.to_string()))?;
+            prop_assert!(half_full(&*btree.root, true));
+            prop_assert!(btree
+                .iter()
+                .map(|(k, _)| k[0])
+                .eq(reference.iter().copied()));
+            Ok(())
+        });
+
+        // Deleting everything shrinks the tree level by level
+        let mut btree = BTree::new();
+        test_insert(&mut btree, 0..5000);
+        for k in (0..5000).rev() {
+            assert_eq!(btree.delete(&[k; 1]), Some(0));
+            if k % 100 == 0 {
+                assert!(half_full(&*btree.root, true));
+            }
+        }
+        assert_eq!(btree.stats().height, 1);
+    }
+
+    // A small key space makes overwrites, misses and pivot hits frequent.
+    // Range bounds are drawn independently, so some are reversed; the
+    // removals over a range are rarer so that the tree still grows.
+    fn op_strategy() -> impl Strategy<Value = Op> {
+        let key = (0..256u128).prop_map(|k| [k; 1]);
+        let bounds = (key.clone(), key.clone());
+        prop_oneof![
+            6 => (key.clone(), any::<Value>()).prop_map(|(k, v)| Op::Insert(k, v)),
+            3 => key.clone().prop_map(Op::Get),
+            3 => key.prop_map(Op::Delete),
+            1 => bounds.clone().prop_map(|(lo, hi)| Op::Range(lo, hi)),
+            1 => bounds.clone().prop_map(|(lo, hi)| Op::RangeRev(lo, hi)),
+            1 => bounds.prop_map(|(lo, hi)| Op::DeleteRange(lo, hi)),
+            1 => Just(Op::PopFirst),
+            1 => Just(Op::PopLast),
+        ]
+    }
+
+    // Seed for the randomized tests: KVS_SEED when set, random otherwise.
+    // It is reported on failure so the exact run can be replayed.
+    fn test_seed() -> u64 {
+        match std::env::var("KVS_SEED") {
+            Ok(s) => s.parse().expect("KVS_SEED must be a u64"),
+            Err(_) => rand::random(),
+        }
+    }
+
+    // Run a property with an RNG derived from the test seed rather than proptest's own entropy
+    fn run_seeded<S: Strategy>(strategy: S, test: impl Fn(S::Value) -> Result<(), TestCaseError>) {
+        let seed = test_seed();
+        let mut rng_seed = [0; 32];
+        rng_seed[..8].copy_from_slice(&seed.to_le_bytes());
+        let config = ProptestConfig {
+            failure_persistence: None,
+            ..ProptestConfig::default()
+        };
+        let rng = TestRng::from_seed(RngAlgorithm::ChaCha, &rng_seed);
+        if let Err(e) = TestRunner::new_with_rng(config, rng).run(&strategy, test) {
+            panic!("{}\nreproduce with KVS_SEED={}", e, seed);
+        }
+    }
+
+    #[test]
+    // Random operation sequences must behave exactly like std's BTreeMap. The
+    // failure reports the shrunk sequence as a ready-to-paste unit test.
+    fn test_differential_against_btreemap() {
+        run_seeded(prop::collection::vec(op_strategy(), 1..1000), |ops| {
+            trace::check(&ops, 1)
+                .map_err(|e| TestCaseError::fail(format!("{}\n\n{}", e, trace::unit_test(&ops))))
+        });
+    }
 }
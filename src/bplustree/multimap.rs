@@ -0,0 +1,125 @@
+//! Tree holding any number of values per key, e.g. an inverted index from a
+//! term to the documents it appears in. Each value is stored as its own
+//! entry, keyed by the key and the sequence number of its insert: the values
+//! of a key are next to each other in insertion order, and reading, counting
+//! or removing them are range operations on the inner tree.
+use super::*;
+
+#[derive(Clone, Debug)]
+pub struct BTreeMultiMap<K = Key, V = Value> {
+    tree: BTree<(K, u64), V>,
+    // Sequence number of the next insert
+    seq: u64,
+}
+
+impl<K: TreeKey, V: TreeValue> Default for BTreeMultiMap<K, V> {
+    fn default() -> BTreeMultiMap<K, V> {
+        BTreeMultiMap::new()
+    }
+}
+
+impl<K: TreeKey, V: TreeValue> BTreeMultiMap<K, V> {
+    pub fn new() -> BTreeMultiMap<K, V> {
+        BTreeMultiMap {
+            tree: BTree::new(),
+            seq: 0,
+        }
+    }
+
+    /// Add `val` to the values of `key`, after the ones already there
+    pub fn insert(&mut self, key: K, val: V) {
+        self.tree.insert((key, self.seq), val);
+        self.seq += 1;
+    }
+
+    /// Values of `key` in insertion order
+    pub fn get_all(&self, key: &K) -> impl DoubleEndedIterator<Item = V> + '_ {
+        self.tree.range(bounds(key)).map(|(_, val)| val)
+    }
+
+    /// First value inserted for `key` that is still there
+    pub fn get_first(&self, key: &K) -> Option<V> {
+        self.get_all(key).next()
+    }
+
+    /// Number of values of `key`, from the entry counts of the inner tree
+    /// rather than by walking them
+    pub fn count(&self, key: &K) -> usize {
+        self.tree.count_range(bounds(key))
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get_first(key).is_some()
+    }
+
+    /// Remove every value of `key`, returning how many there were
+    pub fn remove_all(&mut self, key: &K) -> usize {
+        self.tree.delete_range(bounds(key))
+    }
+
+    /// Number of values, over all keys
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Every key and value, in ascending key order and in insertion order
+    /// for the values of a key
+    pub fn iter(&self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.tree.iter().map(|((key, _), val)| (key, val))
+    }
+}
+
+// Entries of all the values of `key`
+fn bounds<K: Clone>(key: &K) -> std::ops::RangeInclusive<(K, u64)> {
+    (key.clone(), 0)..=(key.clone(), u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    // Inverted index from terms to the documents holding them
+    fn test_multimap() {
+        let mut index: BTreeMultiMap<String, u32> = BTreeMultiMap::new();
+        for doc in 0..3000u32 {
+            for term in ["all", if doc % 2 == 0 { "even" } else { "odd" }] {
+                index.insert(term.to_string(), doc);
+            }
+            if doc % 1000 == 7 {
+                index.insert("rare".to_string(), 3000 - doc);
+            }
+        }
+        assert_eq!(index.len(), 6003);
+        assert_eq!(index.count(&"all".to_string()), 3000);
+        assert_eq!(index.count(&"even".to_string()), 1500);
+        assert_eq!(index.count(&"none".to_string()), 0);
+        assert!(index
+            .get_all(&"odd".to_string())
+            .eq((0..3000).filter(|doc| doc % 2 == 1)));
+        // Insertion order, not value order
+        let rare: Vec<_> = index.get_all(&"rare".to_string()).collect();
+        assert_eq!(rare, vec![2993, 1993, 993]);
+        assert_eq!(index.get_all(&"rare".to_string()).next_back(), Some(993));
+        assert_eq!(index.get_first(&"even".to_string()), Some(0));
+
+        // Keys in order, each with its values in insertion order
+        let first: Vec<_> = index.iter().map(|(term, _)| term).take(3001).collect();
+        assert!(first[..3000].iter().all(|term| term == "all"));
+        assert_eq!(first[3000], "even");
+
+        assert_eq!(index.remove_all(&"even".to_string()), 1500);
+        assert!(!index.contains_key(&"even".to_string()));
+        assert!(index.contains_key(&"odd".to_string()));
+        assert_eq!(index.len(), 4503);
+        index.insert("even".to_string(), 42);
+        assert_eq!(
+            index.get_all(&"even".to_string()).collect::<Vec<_>>(),
+            vec![42]
+        );
+    }
+}
@@ -0,0 +1,77 @@
+//! Uniform random samples of the entries. The entries are picked by rank
+//! rather than by walking the tree: the ranks are drawn first, then reached
+//! in ascending order by an iterator that steps over whole subtrees, so a
+//! sample costs a descent per entry whatever the size of the tree.
+use super::*;
+use rand::Rng;
+use std::collections::BTreeSet;
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> BTree<K, V, LEAF> {
+    /// `n` distinct entries picked uniformly at random, every subset of `n`
+    /// entries being equally likely, in ascending key order. Returns every
+    /// entry if the tree holds fewer than `n`.
+    pub fn sample_n<R: Rng + ?Sized>(&self, n: usize, rng: &mut R) -> Vec<(K, V)> {
+        let len = self.total_len();
+        let n = n.min(len);
+        // Floyd's algorithm: n distinct ranks out of len in n draws
+        let mut ranks = BTreeSet::new();
+        for j in len - n..len {
+            let rank = rng.gen_range(0..=j);
+            if !ranks.insert(rank) {
+                ranks.insert(j);
+            }
+        }
+        let mut iter = self.iter();
+        let mut next = 0;
+        ranks
+            .into_iter()
+            .map(|rank| {
+                let entry = iter.nth(rank - next).unwrap();
+                next = rank + 1;
+                entry
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_sample_n() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut tree = BTree::new();
+        (0..10_000).for_each(|k| {
+            tree.insert([k * 3; 1], (k % 256) as Value);
+        });
+
+        let sample = tree.sample_n(500, &mut rng);
+        assert_eq!(sample.len(), 500);
+        assert!(sample.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(sample.iter().all(|(k, v)| tree.get(k) == Some(*v)));
+        assert_eq!(tree.sample_n(20_000, &mut rng).len(), 10_000);
+        assert_eq!(BTree::<Key>::new().sample_n(3, &mut rng), vec![]);
+        let names: BTree<String, Vec<u8>> = (0..100).map(|k| (k.to_string(), vec![k])).collect();
+        assert!(names
+            .sample_n(10, &mut rng)
+            .iter()
+            .all(|(k, v)| k.parse() == Ok(v[0])));
+
+        // Each of 10 entries is drawn about as often
+        let mut small = BTree::new();
+        (0..10).for_each(|k| {
+            small.insert([k; 1], 0);
+        });
+        let mut hits = [0; 10];
+        for _ in 0..10_000 {
+            small
+                .sample_n(2, &mut rng)
+                .iter()
+                .for_each(|(k, _)| hits[k[0] as usize] += 1);
+        }
+        assert!(hits.iter().all(|h| (1800..2200).contains(h)), "{:?}", hits);
+    }
+}
@@ -0,0 +1,206 @@
+//! Read-only views of a tree, shared across threads. A snapshot holds the
+//! root of the tree it was taken from; since writes copy the nodes a snapshot
+//! still references, it keeps seeing the tree as it was.
+use super::*;
+
+/// Immutable view of a tree at the time of `BTree::share`. Cloning it is
+/// O(1), and it is `Send` and `Sync`, so a clone (or an `Arc`) can be handed
+/// to every thread of a pool.
+#[derive(Clone, Debug)]
+pub struct Snapshot<K = Key, V = Value, const LEAF: usize = LEAF_ITEMS_SIZE> {
+    tree: BTree<K, V, LEAF>,
+}
+
+impl<K: TreeKey, V: TreeValue, const LEAF: usize> Snapshot<K, V, LEAF> {
+    pub(super) fn new(tree: BTree<K, V, LEAF>) -> Snapshot<K, V, LEAF> {
+        Snapshot { tree }
+    }
+
+    pub fn get<Q: Ord + ?Sized>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        self.tree.get(key)
+    }
+
+    pub fn get_key_value<Q: Ord + ?Sized>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        self.tree.get_key_value(key)
+    }
+
+    pub fn contains_key<Q: Ord + ?Sized>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        self.tree.contains_key(key)
+    }
+
+    pub fn first_key_value(&self) -> Option<(K, V)> {
+        self.tree.first_key_value()
+    }
+
+    pub fn last_key_value(&self) -> Option<(K, V)> {
+        self.tree.last_key_value()
+    }
+
+    pub fn lower_bound<Q: Ord + ?Sized>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        self.tree.lower_bound(key)
+    }
+
+    pub fn upper_bound<Q: Ord + ?Sized>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        self.tree.upper_bound(key)
+    }
+
+    pub fn floor<Q: Ord + ?Sized>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        self.tree.floor(key)
+    }
+
+    pub fn predecessor<Q: Ord + ?Sized>(&self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        self.tree.predecessor(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    pub fn total_len(&self) -> usize {
+        self.tree.total_len()
+    }
+
+    pub fn count_range<R: RangeBounds<K>>(&self, range: R) -> usize {
+        self.tree.count_range(range)
+    }
+
+    pub fn rank<Q: Ord + ?Sized>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+    {
+        self.tree.rank(key)
+    }
+
+    pub fn select(&self, n: usize) -> Option<(K, V)> {
+        self.tree.select(n)
+    }
+
+    pub fn iter(&self) -> Iter<'_, K, V, LEAF> {
+        self.tree.iter()
+    }
+
+    pub fn keys(&self) -> Keys<'_, K, V, LEAF> {
+        self.tree.keys()
+    }
+
+    pub fn values(&self) -> Values<'_, K, V, LEAF> {
+        self.tree.values()
+    }
+
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Range<'_, K, V, LEAF> {
+        self.tree.range(range)
+    }
+
+    pub fn stats(&self) -> TreeStats {
+        self.tree.stats()
+    }
+
+    /// Writable copy of the snapshot, sharing its nodes until they are written
+    pub fn to_tree(&self) -> BTree<K, V, LEAF> {
+        self.tree.clone()
+    }
+}
+
+impl<'a, K: TreeKey, V: TreeValue, const LEAF: usize> IntoIterator for &'a Snapshot<K, V, LEAF> {
+    type Item = (K, V);
+    type IntoIter = Iter<'a, K, V, LEAF>;
+
+    fn into_iter(self) -> Iter<'a, K, V, LEAF> {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn tree_of(keys: impl Iterator<Item = u128>) -> BTree {
+        let mut tree = BTree::new();
+        keys.for_each(|k| {
+            tree.insert([k; 1], k as Value);
+        });
+        tree
+    }
+
+    #[test]
+    fn test_snapshot_isolated_from_writes() {
+        let mut tree = tree_of(0..1000);
+        let snapshot = tree.share();
+        metrics::reset();
+        (0..1000).step_by(2).for_each(|k| {
+            tree.delete(&[k; 1]);
+        });
+        (1000..1500).for_each(|k| {
+            tree.insert([k; 1], 1);
+        });
+        assert!(metrics::snapshot().nodes_copied > 0);
+
+        assert_eq!(snapshot.total_len(), 1000);
+        assert!(snapshot.iter().map(|(k, _)| k[0]).eq(0..1000));
+        assert_eq!(tree.total_len(), 1000);
+        assert_eq!(tree.get(&[0; 1]), None);
+        assert_eq!(snapshot.get(&[0; 1]), Some(0));
+        snapshot.tree.check_invariants().unwrap();
+        tree.check_invariants().unwrap();
+
+        // A tree derived from the snapshot diverges without affecting it
+        let mut copy = snapshot.to_tree();
+        copy.insert([5000; 1], 0);
+        assert_eq!(snapshot.get(&[5000; 1]), None);
+    }
+
+    #[test]
+    fn test_snapshot_across_threads() {
+        let tree = tree_of(0..10_000);
+        let snapshot = tree.share();
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let snapshot = snapshot.clone();
+                thread::spawn(move || {
+                    (t..10_000)
+                        .step_by(4)
+                        .all(|k| snapshot.get(&[k; 1]) == Some(k as Value))
+                })
+            })
+            .collect();
+        drop(tree);
+        assert!(handles.into_iter().all(|h| h.join().unwrap()));
+    }
+
+    #[test]
+    fn test_unshared_writes_copy_nothing() {
+        let mut tree = tree_of(0..1000);
+        drop(tree.share());
+        metrics::reset();
+        (1000..2000).for_each(|k| {
+            tree.insert([k; 1], 0);
+        });
+        assert_eq!(metrics::snapshot().nodes_copied, 0);
+    }
+}
@@ -0,0 +1,235 @@
+//! Helpers to build trees with an explicit shape, inspect node boundaries and
+//! force structural changes deterministically. Enabled by the `testing` feature
+//! for downstream regression tests.
+use super::*;
+
+/// Explicit description of a (sub)tree: a leaf with its entries, or an
+/// internal node with its pivots and children.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Shape {
+    Leaf(Vec<(Key, Value)>),
+    Internal(Vec<Key>, Vec<Shape>),
+}
+
+impl Shape {
+    /// Leaf holding `keys`, all with value 0
+    pub fn leaf(keys: &[u128]) -> Shape {
+        Shape::Leaf(keys.iter().map(|k| ([*k; 1], 0)).collect())
+    }
+
+    /// Internal node with the given pivots and children
+    pub fn internal(pivots: &[u128], children: Vec<Shape>) -> Shape {
+        Shape::Internal(pivots.iter().map(|k| [*k; 1]).collect(), children)
+    }
+}
+
+/// Build a tree with exactly the given shape. Fails with `Error::Capacity` if
+/// a node doesn't fit, or `Error::Corruption` if the shape breaks the tree
+/// invariants.
+pub fn build(shape: &Shape) -> error::Result<BTree> {
+    let tree = BTree {
+        root: build_node(shape)?,
+        hooks: None,
+        split_policy: SplitPolicy::default(),
+    };
+    tree.check_invariants()?;
+    Ok(tree)
+}
+
+fn build_node(shape: &Shape) -> error::Result<NodePtr> {
+    match shape {
+        Shape::Leaf(entries) => {
+            if entries.len() > LEAF_ITEMS_SIZE {
+                return Err(Error::Capacity(format!(
+                    "leaf of {} entries, at most {} fit",
+                    entries.len(),
+                    LEAF_ITEMS_SIZE
+                )));
+            }
+            let keys: Vec<Key> = entries.iter().map(|(k, _)| *k).collect();
+            let values: Vec<Value> = entries.iter().map(|(_, v)| *v).collect();
+            Ok(Arc::new(LeafNode::new_from(&keys, &values)))
+        }
+        Shape::Internal(pivots, children) => {
+            if pivots.len() + 1 != children.len() {
+                return Err(Error::Corruption(format!(
+                    "internal node with {} pivots and {} children",
+                    pivots.len(),
+                    children.len()
+                )));
+            }
+            if children.len() > CHILDREN_SIZE {
+                return Err(Error::Capacity(format!(
+                    "internal node with {} children, at most {} fit",
+                    children.len(),
+                    CHILDREN_SIZE
+                )));
+            }
+            let children = children
+                .iter()
+                .map(build_node)
+                .collect::<error::Result<Vec<NodePtr>>>()?;
+            Ok(Arc::new(InternalNode::new_from(pivots, children)))
+        }
+    }
+}
+
+/// Current shape of the tree
+pub fn shape(tree: &BTree) -> Shape {
+    node_shape(&tree.root)
+}
+
+fn node_shape(node: &NodePtr) -> Shape {
+    if let Some(leaf) = node.as_leaf() {
+        return Shape::Leaf(
+            leaf.keys
+                .iter()
+                .copied()
+                .zip(leaf.values.iter().copied())
+                .collect(),
+        );
+    }
+    let internal = node.as_internal().unwrap();
+    Shape::Internal(
+        internal.pivots.to_vec(),
+        internal.children.iter().map(node_shape).collect(),
+    )
+}
+
+/// First and last key of every non-empty leaf, from left to right
+pub fn leaf_ranges(tree: &BTree) -> Vec<(Key, Key)> {
+    let mut ranges = Vec::new();
+    collect_leaf_ranges(&shape(tree), &mut ranges);
+    ranges
+}
+
+fn collect_leaf_ranges(shape: &Shape, ranges: &mut Vec<(Key, Key)>) {
+    match shape {
+        Shape::Leaf(entries) => {
+            if let (Some(first), Some(last)) = (entries.first(), entries.last()) {
+                ranges.push((first.0, last.0));
+            }
+        }
+        Shape::Internal(_, children) => children
+            .iter()
+            .for_each(|child| collect_leaf_ranges(child, ranges)),
+    }
+}
+
+/// Number of levels on the leftmost path, a lone leaf being height 1
+pub fn height(tree: &BTree) -> usize {
+    let mut height = 1;
+    let mut node: &dyn Node = &*tree.root;
+    while let Some(internal) = node.as_internal() {
+        node = &*internal.children[0];
+        height += 1;
+    }
+    height
+}
+
+/// Split the leaf whose range contains `key` in two, the same way an insert
+/// into a full leaf would. Full ancestors are split on the way down so the new
+/// pivot always fits. Returns false if the leaf has fewer than two entries.
+pub fn split_leaf(tree: &mut BTree, key: &Key) -> bool {
+    if tree.root.as_leaf().is_some() {
+        if tree.root.len() < 2 {
+            return false;
+        }
+        let (pivot, right) = make_mut(&mut tree.root).split(SplitPolicy::Midpoint, key);
+        tree.grow_root(pivot, right);
+        return true;
+    }
+    tree.split_root_if_full(key);
+    let mut node = make_mut(&mut tree.root);
+    loop {
+        let internal = node.as_internal_mut().unwrap();
+        let idx = internal.child_idx(key);
+        let child = &mut internal.children[idx];
+        if child.as_leaf().is_some() {
+            if child.len() < 2 {
+                return false;
+            }
+            let (pivot, right) = make_mut(child).split(SplitPolicy::Midpoint, key);
+            internal.pivots.insert(idx, pivot);
+            internal.children.insert(idx + 1, right);
+            return true;
+        }
+        internal.try_split(idx, SplitPolicy::Midpoint, key);
+        let idx = internal.child_idx(key);
+        node = make_mut(&mut internal.children[idx]);
+    }
+}
+
+/// Merge the leaf whose range contains `key` with its right sibling under the
+/// same parent. Returns false if there is no such sibling, the entries don't
+/// fit in one leaf, or the parent would be left with a single child (unless it
+/// is the root, in which case the tree loses a level).
+pub fn merge_leaf(tree: &mut BTree, key: &Key) -> bool {
+    let mut node = make_mut(&mut tree.root);
+    let mut is_root = true;
+    loop {
+        let internal = match node.as_internal_mut() {
+            Some(internal) => internal,
+            None => return false,
+        };
+        let idx = internal.child_idx(key);
+        if internal.children[idx].as_leaf().is_none() {
+            node = make_mut(&mut internal.children[idx]);
+            is_root = false;
+            continue;
+        }
+        if idx + 1 >= internal.children.len() || (!is_root && internal.pivots.len() < 2) {
+            return false;
+        }
+        if internal.children[idx].len() + internal.children[idx + 1].len() > LEAF_ITEMS_SIZE {
+            return false;
+        }
+        internal.pivots.remove(idx);
+        let right = internal.children.remove(idx + 1);
+        let right = right.as_leaf().unwrap();
+        let left = make_mut(&mut internal.children[idx]);
+        for (k, v) in right.keys.iter().zip(right.values.iter()) {
+            left.insert(*k, *v, SplitPolicy::Midpoint);
+        }
+        break;
+    }
+    if tree.root.is_empty() {
+        let only_child = make_mut(&mut tree.root).pop_first_child();
+        if let Some(child) = only_child {
+            tree.root = child;
+        }
+    }
+    true
+}
+
+/// Indented text rendering of the tree, one node per line: internal nodes with
+/// their pivots, leaves with their key range and entry count. Meant for
+/// snapshot tests, where the layout and fill matter more than every key.
+pub fn dump(tree: &BTree) -> String {
+    let mut out = String::new();
+    dump_shape(&shape(tree), 0, &mut out);
+    out
+}
+
+fn dump_shape(shape: &Shape, depth: usize, out: &mut String) {
+    let indent = "  ".repeat(depth);
+    match shape {
+        Shape::Leaf(entries) => match (entries.first(), entries.last()) {
+            (Some(first), Some(last)) => out.push_str(&format!(
+                "{}leaf {}..={} ({})\n",
+                indent,
+                first.0[0],
+                last.0[0],
+                entries.len()
+            )),
+            _ => out.push_str(&format!("{}leaf empty\n", indent)),
+        },
+        Shape::Internal(pivots, children) => {
+            let pivots: Vec<u128> = pivots.iter().map(|p| p[0]).collect();
+            out.push_str(&format!("{}internal {:?}\n", indent, pivots));
+            children
+                .iter()
+                .for_each(|child| dump_shape(child, depth + 1, out));
+        }
+    }
+}
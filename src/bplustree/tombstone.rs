@@ -0,0 +1,174 @@
+//! Tree whose deletes leave a tombstone, so that replication or sync code can
+//! see which keys went away and when. A tombstone is an entry of the tree
+//! like a live value, stamped with the version of the delete instead: it
+//! takes the place of the value, and a later insert overwrites it. Reads skip
+//! the tombstones, which stay in the tree until an explicit `purge`, once
+//! every consumer has caught up.
+use super::*;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Slot<V> {
+    Live(V),
+    // Version of the delete that left the tombstone
+    Dead(u64),
+}
+
+/// Tree of `Key`s to any value type, whose deletes leave tombstones
+#[derive(Clone, Debug)]
+pub struct TombstoneTree<V = Value> {
+    tree: BTree<Key, Slot<V>>,
+    // Entries of the tree that are tombstones
+    dead: usize,
+    version: u64,
+}
+
+impl<V: TreeValue> Default for TombstoneTree<V> {
+    fn default() -> TombstoneTree<V> {
+        TombstoneTree::new()
+    }
+}
+
+impl<V: TreeValue> TombstoneTree<V> {
+    pub fn new() -> TombstoneTree<V> {
+        TombstoneTree {
+            tree: BTree::new(),
+            dead: 0,
+            version: 0,
+        }
+    }
+
+    /// Version of the last write, 0 before any
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Insert or overwrite `key`, clearing its tombstone if it had one.
+    /// Returns the value replaced, None for a tombstoned key.
+    pub fn insert(&mut self, key: Key, val: V) -> Option<V> {
+        self.version += 1;
+        match self.tree.insert(key, Slot::Live(val))? {
+            Slot::Live(previous) => Some(previous),
+            Slot::Dead(_) => {
+                self.dead -= 1;
+                None
+            }
+        }
+    }
+
+    /// Replace the value of `key` with a tombstone stamped with the new
+    /// version. Returns the removed value, or None, without a tombstone, if
+    /// the key was absent.
+    pub fn delete(&mut self, key: &Key) -> Option<V> {
+        let slot = self.tree.get_mut(key)?;
+        let version = self.version + 1;
+        match std::mem::replace(slot, Slot::Dead(version)) {
+            Slot::Live(removed) => {
+                self.version = version;
+                self.dead += 1;
+                Some(removed)
+            }
+            // Already tombstoned: keep the version of the first delete
+            dead => {
+                *slot = dead;
+                None
+            }
+        }
+    }
+
+    pub fn get(&self, key: &Key) -> Option<V> {
+        match self.tree.get(key)? {
+            Slot::Live(val) => Some(val),
+            Slot::Dead(_) => None,
+        }
+    }
+
+    /// Version of the delete that tombstoned `key`, if it is tombstoned
+    pub fn tombstone(&self, key: &Key) -> Option<u64> {
+        match self.tree.get(key)? {
+            Slot::Live(_) => None,
+            Slot::Dead(version) => Some(version),
+        }
+    }
+
+    /// Tombstones in ascending key order, with the version of their delete.
+    /// Walks the live entries too.
+    pub fn tombstones(&self) -> impl Iterator<Item = (Key, u64)> + '_ {
+        self.tree.iter().filter_map(|(key, slot)| match slot {
+            Slot::Live(_) => None,
+            Slot::Dead(version) => Some((key, version)),
+        })
+    }
+
+    /// Live entries in ascending key order, skipping the tombstones
+    pub fn iter(&self) -> impl Iterator<Item = (Key, V)> + '_ {
+        self.tree.iter().filter_map(|(key, slot)| match slot {
+            Slot::Live(val) => Some((key, val)),
+            Slot::Dead(_) => None,
+        })
+    }
+
+    /// Number of live entries
+    pub fn len(&self) -> usize {
+        self.tree.len() - self.dead
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Physically remove the tombstones left by deletes before version
+    /// `before` from the tree, in one pass. Returns how many were removed.
+    pub fn purge(&mut self, before: u64) -> usize {
+        let len = self.tree.len();
+        self.tree
+            .retain(|_, slot| !matches!(slot, Slot::Dead(version) if *version < before));
+        let purged = len - self.tree.len();
+        self.dead -= purged;
+        purged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tombstones_and_purge() {
+        let mut tree = TombstoneTree::new();
+        (0..10).for_each(|k| {
+            tree.insert([k; 1], 1);
+        });
+        assert_eq!(tree.delete(&[3; 1]), Some(1));
+        assert_eq!(tree.delete(&[3; 1]), None);
+        assert_eq!(tree.delete(&[42; 1]), None);
+        assert_eq!(tree.delete(&[1; 1]), Some(1));
+        assert_eq!(tree.delete(&[5; 1]), Some(1));
+        assert_eq!(tree.insert([5; 1], 2), None);
+        assert_eq!(tree.insert([6; 1], 2), Some(1));
+
+        assert_eq!(tree.get(&[3; 1]), None);
+        assert_eq!(tree.get(&[5; 1]), Some(2));
+        assert_eq!(tree.tombstone(&[3; 1]), Some(11));
+        assert_eq!(tree.tombstone(&[5; 1]), None);
+        assert_eq!(
+            tree.tombstones().collect::<Vec<_>>(),
+            vec![([1; 1], 12), ([3; 1], 11)]
+        );
+        assert_eq!(tree.len(), 8);
+        assert_eq!(tree.iter().count(), 8);
+        assert_eq!(tree.iter().nth(1), Some(([2; 1], 1)));
+
+        assert_eq!(tree.purge(12), 1);
+        assert_eq!(tree.tombstones().collect::<Vec<_>>(), vec![([1; 1], 12)]);
+        assert_eq!(tree.purge(tree.version() + 1), 1);
+        assert_eq!(tree.tombstones().next(), None);
+        assert_eq!(tree.tree.len(), 8);
+        assert_eq!(tree.insert([1; 1], 3), None);
+        assert_eq!(tree.len(), 9);
+
+        let mut blobs = TombstoneTree::new();
+        blobs.insert([1; 1], vec![1u8; 100]);
+        assert_eq!(blobs.delete(&[1; 1]).map(|blob| blob.len()), Some(100));
+        assert_eq!(blobs.tombstone(&[1; 1]), Some(2));
+    }
+}
@@ -0,0 +1,232 @@
+//! Tree that keeps the past values of its keys. Every write gets the next
+//! version number, and reads can ask for the value a key had at any version
+//! still covered by the retention policy. The tree itself only holds current
+//! values; the history lives on the side, so plain reads cost the same.
+use super::*;
+use std::collections::{HashMap, VecDeque};
+
+/// How much history a `VersionedTree` keeps
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retention {
+    /// The last n values of each key, the current one included (at least 1)
+    Count(usize),
+    /// Enough to answer reads at any of the last n versions
+    Versions(u64),
+}
+
+#[derive(Clone, Debug)]
+struct History<V> {
+    // Writes to the key, oldest first, with the value they left (None for a
+    // delete)
+    writes: VecDeque<(u64, Option<V>)>,
+    // Older writes were dropped, so versions before the first one are unknown
+    truncated: bool,
+}
+
+impl<V> Default for History<V> {
+    fn default() -> History<V> {
+        History {
+            writes: VecDeque::new(),
+            truncated: false,
+        }
+    }
+}
+
+/// Tree of `Key`s to any value type, with the history of every key
+#[derive(Clone, Debug)]
+pub struct VersionedTree<V = Value> {
+    tree: BTree<Key, V>,
+    history: HashMap<Key, History<V>>,
+    version: u64,
+    retention: Retention,
+}
+
+impl<V: TreeValue> VersionedTree<V> {
+    pub fn new(retention: Retention) -> VersionedTree<V> {
+        VersionedTree {
+            tree: BTree::new(),
+            history: HashMap::new(),
+            version: 0,
+            retention,
+        }
+    }
+
+    /// Version of the last write, 0 before any
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Insert or overwrite `key`, returning the value replaced
+    pub fn insert(&mut self, key: Key, val: V) -> Option<V> {
+        let previous = self.tree.insert(key, val.clone());
+        self.record(key, Some(val));
+        previous
+    }
+
+    /// Remove `key`, returning its value. Deleting an absent key is not a
+    /// write and doesn't take a version.
+    pub fn delete(&mut self, key: &Key) -> Option<V> {
+        let removed = self.tree.delete(key);
+        if removed.is_some() {
+            self.record(*key, None);
+        }
+        removed
+    }
+
+    pub fn get(&self, key: &Key) -> Option<V> {
+        self.tree.get(key)
+    }
+
+    /// Value of `key` right after the write of `version`. Fails with
+    /// `Error::InvalidInput` for a future version, or one whose value was
+    /// dropped by the retention policy.
+    pub fn get_at(&self, key: &Key, version: u64) -> error::Result<Option<V>> {
+        if version > self.version {
+            return Err(Error::InvalidInput(format!(
+                "version {} is ahead of the tree (at {})",
+                version, self.version
+            )));
+        }
+        if let Retention::Versions(n) = self.retention {
+            if version < self.version.saturating_sub(n) {
+                return Err(Error::InvalidInput(format!(
+                    "version {} is older than the last {} versions",
+                    version, n
+                )));
+            }
+        }
+        let history = match self.history.get(key) {
+            Some(history) => history,
+            None => return Ok(None),
+        };
+        let idx = history.writes.partition_point(|(v, _)| *v <= version);
+        match idx.checked_sub(1) {
+            Some(idx) => Ok(history.writes[idx].1.clone()),
+            None if history.truncated => Err(Error::InvalidInput(format!(
+                "version {} of {:?} is older than its retained history",
+                version, key
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    /// Retained writes to `key`, oldest first, with the version they were
+    /// made at and the value they left (None for a delete)
+    pub fn history(&self, key: &Key) -> Vec<(u64, Option<V>)> {
+        self.history
+            .get(key)
+            .map_or_else(Vec::new, |history| history.writes.iter().cloned().collect())
+    }
+
+    /// The current tree, without history
+    pub fn tree(&self) -> &BTree<Key, V> {
+        &self.tree
+    }
+
+    /// Drop the history of every key that fell out of the retention window.
+    /// Writes prune the history of the key they touch; this catches the keys
+    /// that are no longer written.
+    pub fn compact(&mut self) {
+        let horizon = self.horizon();
+        self.history.retain(|_, history| {
+            prune(history, horizon);
+            // A key deleted before the horizon reads as absent at every
+            // retained version, same as a key without history
+            !matches!(history.writes.front(), Some((v, None)) if history.writes.len() == 1 && *v <= horizon)
+        });
+    }
+
+    // Add a write to the history of `key`. The last write of a key is never
+    // pruned, and a key without history is absent.
+    fn record(&mut self, key: Key, val: Option<V>) {
+        self.version += 1;
+        let horizon = self.horizon();
+        let history = self.history.entry(key).or_default();
+        history.writes.push_back((self.version, val));
+        if let Retention::Count(n) = self.retention {
+            while history.writes.len() > n.max(1) {
+                history.writes.pop_front();
+                history.truncated = true;
+            }
+        }
+        prune(history, horizon);
+    }
+
+    // Oldest version reads must be answered for
+    fn horizon(&self) -> u64 {
+        match self.retention {
+            Retention::Count(_) => 0,
+            Retention::Versions(n) => self.version.saturating_sub(n),
+        }
+    }
+}
+
+// Drop the writes superseded before `horizon`, keeping the one still visible
+// at the horizon
+fn prune<V>(history: &mut History<V>, horizon: u64) {
+    while history.writes.len() > 1 && history.writes[1].0 <= horizon {
+        history.writes.pop_front();
+        history.truncated = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_at_and_history() {
+        let mut tree = VersionedTree::new(Retention::Count(3));
+        let key = [7; 1];
+        assert_eq!(tree.insert(key, 1), None);
+        tree.insert([8; 1], 0);
+        assert_eq!(tree.insert(key, 2), Some(1));
+        assert_eq!(tree.delete(&key), Some(2));
+        assert_eq!(tree.delete(&key), None);
+        assert_eq!(tree.insert(key, 3), None);
+        assert_eq!(tree.version(), 5);
+        assert_eq!(
+            tree.history(&key),
+            vec![(3, Some(2)), (4, None), (5, Some(3))]
+        );
+        assert_eq!(tree.get_at(&key, 5).unwrap(), Some(3));
+        assert_eq!(tree.get_at(&key, 4).unwrap(), None);
+        assert_eq!(tree.get_at(&key, 3).unwrap(), Some(2));
+        // The first write fell out of the last 3
+        assert!(tree.get_at(&key, 2).is_err());
+        assert!(tree.get_at(&key, 6).is_err());
+        // Never written before version 2
+        assert_eq!(tree.get_at(&[8; 1], 1).unwrap(), None);
+        assert_eq!(tree.get_at(&[9; 1], 1).unwrap(), None);
+
+        let mut docs = VersionedTree::new(Retention::Count(2));
+        docs.insert(key, "draft".to_string());
+        assert_eq!(
+            docs.insert(key, "final".to_string()).as_deref(),
+            Some("draft")
+        );
+        assert_eq!(docs.get_at(&key, 1).unwrap().as_deref(), Some("draft"));
+    }
+
+    #[test]
+    fn test_version_window() {
+        let mut tree = VersionedTree::new(Retention::Versions(10));
+        tree.insert([1; 1], 1);
+        tree.insert([2; 1], 1);
+        assert_eq!(tree.delete(&[2; 1]), Some(1));
+        for v in 0..20 {
+            tree.insert([3; 1], v);
+        }
+        assert_eq!(tree.version(), 23);
+        assert!(tree.get_at(&[3; 1], 12).is_err());
+        assert_eq!(tree.get_at(&[3; 1], 13).unwrap(), Some(9));
+        // Written once, long ago, and still visible
+        assert_eq!(tree.get_at(&[1; 1], 13).unwrap(), Some(1));
+        assert_eq!(tree.history(&[3; 1]).len(), 11);
+
+        tree.compact();
+        assert_eq!(tree.history(&[1; 1]), vec![(1, Some(1))]);
+        assert_eq!(tree.history(&[2; 1]), vec![]);
+        assert_eq!(tree.get_at(&[2; 1], 13).unwrap(), None);
+    }
+}
@@ -0,0 +1,24 @@
+//! Error type shared by the fallible APIs of the crate
+use std::io;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// A node or container has no room for the requested entries
+    #[error("capacity exceeded: {0}")]
+    Capacity(String),
+    /// A structural invariant of the tree doesn't hold
+    #[error("corrupted tree: {0}")]
+    Corruption(String),
+    /// The caller broke a precondition, e.g. unsorted bulk load input
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    /// Malformed serialized data
+    #[error("serialization error: {0}")]
+    Serialization(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -1,3 +1,5 @@
+use crate::error::{self, Error};
+
 // Freelist is a 32 bits freelist
 pub struct Freelist<T> {
     list: Vec<Handle<T>>,
@@ -46,14 +48,16 @@ impl<T> Freelist<T> {
         }
     }
 
-    pub fn delete(&mut self, idx: u32) -> Option<()> {
-        match self.list[idx as usize] {
-            Handle::Next(_) => None, // Already a tombstone
-            Handle::Value(_) => {
+    pub fn delete(&mut self, idx: u32) -> error::Result<()> {
+        match self.list.get(idx as usize) {
+            None => Err(Error::InvalidInput(format!("slot {} out of bounds", idx))),
+            // Already a tombstone
+            Some(Handle::Next(_)) => Err(Error::InvalidInput(format!("slot {} is free", idx))),
+            Some(Handle::Value(_)) => {
                 self.list[idx as usize] = Handle::Next(self.free_list_head as u32);
                 self.free_list_head = idx as usize;
                 self.size -= 1;
-                return Some(());
+                Ok(())
             }
         }
     }
@@ -84,6 +88,8 @@ mod tests {
 
         list.delete(idx).expect("Should have been deleted");
         assert_eq!(list.len(), 0);
+        assert!(list.delete(idx).is_err()); // already a tombstone
+        assert!(list.delete(idx + 1).is_err()); // out of bounds
     }
 
     #[test]
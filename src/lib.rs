@@ -1,3 +1,8 @@
 pub mod bplustree;
+pub mod error;
 pub mod freelist;
 pub mod page;
+pub mod run;
+pub mod trace;
+
+pub use error::{Error, Result};
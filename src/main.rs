@@ -1,7 +1,39 @@
 pub mod bplustree;
-use rand::Rng;
+pub mod error;
+pub mod trace;
+use hdrhistogram::Histogram;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Lines};
+use std::panic;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(|s| s.as_str()) {
+        Some("bench") => bench(&args[1..]),
+        Some("load") => load(&args[1..]),
+        Some("stress") => stress(&args[1..]),
+        Some("replay") => replay(&args[1..]),
+        Some(cmd) => {
+            eprintln!("unknown command: {}", cmd);
+            eprintln!("usage: kvs-rs [bench --workload <seq|rand|zipf|mixed> --threads N --duration S [--keys N] [--seed N] [--record FILE]]");
+            eprintln!("       kvs-rs load <file.csv> [--sorted]");
+            eprintln!("       kvs-rs replay <trace> [--timed]");
+            eprintln!("       kvs-rs stress [--threads N] [--readers N] [--ops M] [--keys N] [--check-every N] [--seed N]");
+            process::exit(2);
+        }
+        None => demo(),
+    }
+}
+
+fn demo() {
     if cfg!(target_endian = "big") {
         println!("Big endian");
     } else {
@@ -17,7 +49,7 @@ fn main() {
 
     let n = 1_000_000;
     let mut rng = rand::thread_rng();
-    let mut t = bplustree::BTree::new();
+    let mut t: bplustree::BTree = bplustree::BTree::new();
     println!("inserting {} keys", n);
     for _ in 0..n {
         t.insert([rng.gen(); 1], 0);
@@ -27,3 +59,688 @@ fn main() {
         t.get(&[rng.gen(); 1]);
     }
 }
+
+#[derive(Clone, Copy, Debug)]
+enum Workload {
+    // Insert ascending keys
+    Seq,
+    // Insert uniformly random keys
+    Rand,
+    // Get preloaded keys with a zipfian popularity
+    Zipf,
+    // 50% zipfian gets, 50% random inserts on a preloaded tree
+    Mixed,
+}
+
+struct BenchConfig {
+    workload: Workload,
+    threads: usize,
+    duration: Duration,
+    keys: u64,
+    seed: u64,
+    record: Option<String>,
+}
+
+fn parse_bench_args(args: &[String]) -> Result<BenchConfig, String> {
+    let mut config = BenchConfig {
+        workload: Workload::Rand,
+        threads: 1,
+        duration: Duration::from_secs(10),
+        keys: 1_000_000,
+        seed: rand::random(),
+        record: None,
+    };
+    let mut iter = args.iter();
+    while let Some(flag) = iter.next() {
+        let val = iter
+            .next()
+            .ok_or_else(|| format!("missing value for {}", flag))?;
+        match flag.as_str() {
+            "--workload" => {
+                config.workload = match val.as_str() {
+                    "seq" => Workload::Seq,
+                    "rand" => Workload::Rand,
+                    "zipf" => Workload::Zipf,
+                    "mixed" => Workload::Mixed,
+                    _ => return Err(format!("unknown workload: {}", val)),
+                }
+            }
+            "--threads" => config.threads = val.parse().map_err(|_| "invalid --threads")?,
+            "--duration" => {
+                config.duration =
+                    Duration::from_secs(val.parse().map_err(|_| "invalid --duration")?)
+            }
+            "--keys" => config.keys = val.parse().map_err(|_| "invalid --keys")?,
+            "--seed" => config.seed = val.parse().map_err(|_| "invalid --seed")?,
+            "--record" => config.record = Some(val.clone()),
+            _ => return Err(format!("unknown flag: {}", flag)),
+        }
+    }
+    if config.threads == 0 || config.keys == 0 {
+        return Err("--threads and --keys must be positive".to_string());
+    }
+    // Every thread drives its own tree, so a single trace can only describe one
+    if config.record.is_some() && config.threads != 1 {
+        return Err("--record requires --threads 1".to_string());
+    }
+    Ok(config)
+}
+
+fn bench(args: &[String]) {
+    let config = match parse_bench_args(args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    println!(
+        "workload: {:?}, threads: {}, duration: {}s, keys: {}, seed: {}",
+        config.workload,
+        config.threads,
+        config.duration.as_secs(),
+        config.keys,
+        config.seed
+    );
+
+    let mut recorder = config.record.as_ref().map(|path| {
+        let file = File::create(path).unwrap_or_else(|e| {
+            eprintln!("cannot create {}: {}", path, e);
+            process::exit(1);
+        });
+        trace::TraceWriter::new(BufWriter::new(file))
+    });
+
+    // Every thread builds and drives its own tree
+    let handles: Vec<_> = (0..config.threads)
+        .map(|i| {
+            let (workload, duration, keys) = (config.workload, config.duration, config.keys);
+            // Each thread derives its own stream from the run's seed
+            let seed = config.seed.wrapping_add(i as u64);
+            // Only ever Some for a single thread
+            let recorder = recorder.take();
+            thread::spawn(move || run_worker(workload, duration, keys, seed, recorder))
+        })
+        .collect();
+
+    let mut total_ops = 0;
+    let mut histogram = new_histogram();
+    let mut metrics = bplustree::metrics::Metrics::default();
+    for handle in handles {
+        let (ops, h, m) = handle.join().expect("bench worker panicked");
+        total_ops += ops;
+        histogram.add(&h).expect("histograms auto-resize");
+        metrics += m;
+    }
+
+    let secs = config.duration.as_secs_f64();
+    println!("ops: {} ({:.0} ops/s)", total_ops, total_ops as f64 / secs);
+    println!(
+        "latency (ns): p50={} p90={} p99={} p99.9={} p99.99={} max={}",
+        histogram.value_at_quantile(0.5),
+        histogram.value_at_quantile(0.9),
+        histogram.value_at_quantile(0.99),
+        histogram.value_at_quantile(0.999),
+        histogram.value_at_quantile(0.9999),
+        histogram.max()
+    );
+    println!("{}", metrics);
+}
+
+// Returns the number of timed operations, their latencies and the tree's
+// structural counters (preload included)
+fn run_worker(
+    workload: Workload,
+    duration: Duration,
+    keys: u64,
+    seed: u64,
+    mut recorder: Option<trace::TraceWriter<BufWriter<File>>>,
+) -> (u64, Histogram<u64>, bplustree::metrics::Metrics) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut t = bplustree::BTree::new();
+    let mut histogram = new_histogram();
+    let trace_start = Instant::now();
+    let mut record = |at: Instant, op: &trace::Op| {
+        if let Some(recorder) = recorder.as_mut() {
+            recorder
+                .record(at - trace_start, op)
+                .unwrap_or_else(|e| trace_error("cannot write trace", e));
+        }
+    };
+    let zipf = match workload {
+        Workload::Zipf | Workload::Mixed => {
+            for i in 0..keys {
+                let op = trace::Op::Insert([i as u128; 1], 0);
+                record(Instant::now(), &op);
+                op.apply(&mut t);
+            }
+            Some(Zipfian::new(keys))
+        }
+        _ => None,
+    };
+
+    let mut ops: u64 = 0;
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        // Drawing the key is not part of the operation: only `apply` is timed
+        let op = match workload {
+            Workload::Seq => trace::Op::Insert([ops as u128; 1], 0),
+            Workload::Rand => trace::Op::Insert([rng.gen(); 1], 0),
+            Workload::Zipf => trace::Op::Get([zipf.as_ref().unwrap().next(&mut rng) as u128; 1]),
+            Workload::Mixed => {
+                if rng.gen_bool(0.5) {
+                    trace::Op::Get([zipf.as_ref().unwrap().next(&mut rng) as u128; 1])
+                } else {
+                    // Random u128 keys practically never collide with the preloaded ones
+                    trace::Op::Insert([rng.gen(); 1], 0)
+                }
+            }
+        };
+        let begin = Instant::now();
+        op.apply(&mut t);
+        histogram
+            .record(begin.elapsed().as_nanos() as u64)
+            .expect("histograms auto-resize");
+        // Written outside of the timed section, but it still slows the run down
+        record(begin, &op);
+        ops += 1;
+    }
+    if let Some(recorder) = recorder.as_mut() {
+        recorder
+            .flush()
+            .unwrap_or_else(|e| trace_error("cannot write trace", e));
+    }
+    (ops, histogram, bplustree::metrics::snapshot())
+}
+
+// Zipfian generator over [0, n) following Gray et al. "Quickly generating
+// billion-record synthetic databases" (the one used by YCSB)
+struct Zipfian {
+    n: u64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    fn new(n: u64) -> Zipfian {
+        let theta = 0.99;
+        let zeta2 = 1.0 + 0.5f64.powf(theta);
+        let zetan: f64 = (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum();
+        Zipfian {
+            n,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta: (1.0 - (2.0 / n as f64).powf(1.0 - theta)) / (1.0 - zeta2 / zetan),
+        }
+    }
+
+    fn next(&self, rng: &mut StdRng) -> u64 {
+        let u: f64 = rng.gen();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1.min(self.n - 1);
+        }
+        let v = (self.n as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64;
+        v.min(self.n - 1)
+    }
+}
+
+fn trace_error(msg: &str, e: impl std::fmt::Display) -> ! {
+    eprintln!("{}: {}", msg, e);
+    process::exit(1);
+}
+
+// Replays a recorded trace against a fresh tree, either as fast as possible or
+// (with --timed) issuing every operation at its recorded offset
+fn replay(args: &[String]) {
+    let mut path = None;
+    let mut timed = false;
+    for arg in args {
+        match arg.as_str() {
+            "--timed" => timed = true,
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => {
+                eprintln!("unexpected argument: {}", arg);
+                process::exit(2);
+            }
+        }
+    }
+    let path = path.unwrap_or_else(|| {
+        eprintln!("usage: kvs-rs replay <trace> [--timed]");
+        process::exit(2);
+    });
+    let file =
+        File::open(&path).unwrap_or_else(|e| trace_error(&format!("cannot open {}", path), e));
+
+    let mut t = bplustree::BTree::new();
+    let mut histogram = new_histogram();
+    let mut ops: u64 = 0;
+    let start = Instant::now();
+    for entry in trace::read(BufReader::new(file)) {
+        let (offset, op) = entry.unwrap_or_else(|e| trace_error("invalid trace", e));
+        if timed {
+            if let Some(wait) = (start + offset).checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+        }
+        let begin = Instant::now();
+        op.apply(&mut t);
+        histogram
+            .record(begin.elapsed().as_nanos() as u64)
+            .expect("histograms auto-resize");
+        ops += 1;
+    }
+
+    let secs = start.elapsed().as_secs_f64();
+    println!(
+        "ops: {} in {:.2}s ({:.0} ops/s)",
+        ops,
+        secs,
+        ops as f64 / secs
+    );
+    println!(
+        "latency (ns): p50={} p90={} p99={} p99.9={} p99.99={} max={}",
+        histogram.value_at_quantile(0.5),
+        histogram.value_at_quantile(0.9),
+        histogram.value_at_quantile(0.99),
+        histogram.value_at_quantile(0.999),
+        histogram.value_at_quantile(0.9999),
+        histogram.max()
+    );
+    println!("structure: {}", t.stats());
+    println!("{}", bplustree::metrics::snapshot());
+}
+
+// Latencies are recorded in nanoseconds with 3 significant digits
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new(3).expect("valid histogram precision")
+}
+
+fn load(args: &[String]) {
+    let mut path = None;
+    let mut sorted = false;
+    for arg in args {
+        match arg.as_str() {
+            "--sorted" => sorted = true,
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => {
+                eprintln!("unexpected argument: {}", arg);
+                process::exit(2);
+            }
+        }
+    }
+    let path = path.unwrap_or_else(|| {
+        eprintln!("usage: kvs-rs load <file.csv> [--sorted]");
+        process::exit(2);
+    });
+    let file = File::open(&path).unwrap_or_else(|e| {
+        eprintln!("cannot open {}: {}", path, e);
+        process::exit(1);
+    });
+
+    let start = Instant::now();
+    let entries = CsvEntries {
+        lines: BufReader::new(file).lines(),
+        line_no: 0,
+        loaded: 0,
+        last_key: None,
+        check_sorted: sorted,
+        start,
+    };
+    let t = if sorted {
+        bplustree::BTree::bulk_load(entries).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        })
+    } else {
+        let mut t = bplustree::BTree::new();
+        for (key, val) in entries {
+            t.insert(key, val);
+        }
+        t
+    };
+    let elapsed = start.elapsed().as_secs_f64();
+    let len = t.total_len();
+    println!(
+        "loaded {} entries in {:.2}s ({:.0} entries/s)",
+        len,
+        elapsed,
+        len as f64 / elapsed
+    );
+}
+
+const LOAD_PROGRESS_EVERY: u64 = 1_000_000;
+
+// Streams `key,value` lines out of a CSV file, exiting on malformed input
+// (or on out-of-order keys when the caller asked for the sorted path)
+struct CsvEntries {
+    lines: Lines<BufReader<File>>,
+    line_no: u64,
+    loaded: u64,
+    last_key: Option<u128>,
+    check_sorted: bool,
+    start: Instant,
+}
+
+impl Iterator for CsvEntries {
+    type Item = ([u128; 1], u8);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => load_error(self.line_no + 1, &e.to_string()),
+            };
+            self.line_no += 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, val) = match line.split_once(',') {
+                Some((key, val)) => (key.trim(), val.trim()),
+                None => load_error(self.line_no, "expected `key,value`"),
+            };
+            let key: u128 = key
+                .parse()
+                .unwrap_or_else(|_| load_error(self.line_no, "invalid key"));
+            let val: u8 = val
+                .parse()
+                .unwrap_or_else(|_| load_error(self.line_no, "invalid value"));
+            if self.check_sorted && self.last_key.is_some_and(|last| last >= key) {
+                load_error(self.line_no, "keys are not strictly ascending");
+            }
+            self.last_key = Some(key);
+
+            self.loaded += 1;
+            if self.loaded.is_multiple_of(LOAD_PROGRESS_EVERY) {
+                let elapsed = self.start.elapsed().as_secs_f64();
+                println!(
+                    "{} entries ({:.0} entries/s)",
+                    self.loaded,
+                    self.loaded as f64 / elapsed
+                );
+            }
+            return Some(([key; 1], val));
+        }
+    }
+}
+
+fn load_error(line_no: u64, msg: &str) -> ! {
+    eprintln!("line {}: {}", line_no, msg);
+    process::exit(1);
+}
+
+struct StressConfig {
+    threads: usize,
+    // Snapshot readers; with any, the threads write to one shared tree
+    readers: usize,
+    ops: u64,
+    keys: u64,
+    check_every: u64,
+    seed: u64,
+}
+
+fn parse_stress_args(args: &[String]) -> Result<StressConfig, String> {
+    let mut config = StressConfig {
+        threads: 1,
+        readers: 0,
+        ops: 10_000_000,
+        keys: 100_000,
+        check_every: 100_000,
+        seed: rand::random(),
+    };
+    let mut iter = args.iter();
+    while let Some(flag) = iter.next() {
+        let val = iter
+            .next()
+            .ok_or_else(|| format!("missing value for {}", flag))?;
+        match flag.as_str() {
+            "--threads" => config.threads = val.parse().map_err(|_| "invalid --threads")?,
+            "--readers" => config.readers = val.parse().map_err(|_| "invalid --readers")?,
+            "--ops" => config.ops = val.parse().map_err(|_| "invalid --ops")?,
+            "--keys" => config.keys = val.parse().map_err(|_| "invalid --keys")?,
+            "--check-every" => {
+                config.check_every = val.parse().map_err(|_| "invalid --check-every")?
+            }
+            "--seed" => config.seed = val.parse().map_err(|_| "invalid --seed")?,
+            _ => return Err(format!("unknown flag: {}", flag)),
+        }
+    }
+    if config.threads == 0 || config.keys == 0 || config.check_every == 0 {
+        return Err("--threads, --keys and --check-every must be positive".to_string());
+    }
+    Ok(config)
+}
+
+// Hammers one tree per thread with random inserts, deletes and gets, mirrored
+// into a std BTreeMap. Every result is compared against the shadow model, and
+// the invariant checker plus a full content comparison run periodically.
+fn stress(args: &[String]) {
+    let config = match parse_stress_args(args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    println!(
+        "threads: {}, readers: {}, ops: {}, keys: {}, check every: {}, seed: {}",
+        config.threads, config.readers, config.ops, config.keys, config.check_every, config.seed
+    );
+
+    let start = Instant::now();
+    if config.readers > 0 {
+        match stress_shared(&config) {
+            Ok(checks) => println!(
+                "ok: {} ops, {} snapshot checks in {:.1}s",
+                config.ops * config.threads as u64,
+                checks,
+                start.elapsed().as_secs_f64()
+            ),
+            Err(e) => {
+                eprintln!("{}", e);
+                // The interleaving of the writers is not replayable, only
+                // the operations each of them draws
+                eprintln!("rerun with --seed {}", config.seed);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    let handles: Vec<_> = (0..config.threads)
+        .map(|i| {
+            let (ops, keys, check_every) = (config.ops, config.keys, config.check_every);
+            let seed = config.seed.wrapping_add(i as u64);
+            thread::spawn(move || stress_worker(ops, keys, check_every, seed))
+        })
+        .collect();
+
+    let mut failed = false;
+    for (i, handle) in handles.into_iter().enumerate() {
+        match handle.join() {
+            Ok(Ok(())) => (),
+            Ok(Err((op, e))) => {
+                eprintln!("thread {} diverged at op {}: {}", i, op, e);
+                // One reproducer is enough, the other threads likely hit the same bug
+                if !failed {
+                    reduce_failure(config.seed.wrapping_add(i as u64), config.keys, op);
+                }
+                failed = true;
+            }
+            Err(_) => {
+                eprintln!("thread {} panicked", i);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        eprintln!("reproduce with --seed {}", config.seed);
+        process::exit(1);
+    }
+    println!(
+        "ok: {} ops in {:.1}s",
+        config.ops * config.threads as u64,
+        start.elapsed().as_secs_f64()
+    );
+}
+
+struct SharedTree {
+    tree: bplustree::BTree,
+    // Operations in the order they were applied to the tree
+    log: Vec<trace::Op>,
+}
+
+// Snapshot of the shared tree, with the digest the tree had when it was taken
+type Published = Option<(bplustree::Snapshot, u64)>;
+
+// The writers take turns on one tree behind a lock, logging every operation
+// as they apply it, and publish a snapshot every `check_every` operations.
+// Meanwhile the readers check the latest snapshot: the nodes it shares are
+// copied by the writes that follow, which must not show through. Once the
+// writers are done, the log is replayed against the model and on a fresh
+// tree, which the shared tree must match. Returns the number of snapshot
+// checks.
+fn stress_shared(config: &StressConfig) -> Result<u64, String> {
+    let shared = Mutex::new(SharedTree {
+        tree: bplustree::BTree::new(),
+        log: Vec::new(),
+    });
+    let published = RwLock::new(None);
+    let done = AtomicBool::new(false);
+    let checks = thread::scope(|scope| {
+        let (shared, published, done) = (&shared, &published, &done);
+        let readers: Vec<_> = (0..config.readers)
+            .map(|_| scope.spawn(move || shared_reader(published, done)))
+            .collect();
+        let writers: Vec<_> = (0..config.threads)
+            .map(|i| {
+                let seed = config.seed.wrapping_add(i as u64);
+                scope.spawn(move || shared_writer(shared, published, config, seed))
+            })
+            .collect();
+        // Every writer is joined before the readers are stopped
+        let panicked = writers
+            .into_iter()
+            .map(|writer| writer.join())
+            .filter(Result::is_err)
+            .count();
+        done.store(true, Ordering::Release);
+        let mut checks = 0;
+        for (i, reader) in readers.into_iter().enumerate() {
+            checks += reader
+                .join()
+                .map_err(|_| format!("reader {} panicked", i))?
+                .map_err(|e| format!("reader {}: {}", i, e))?;
+        }
+        if panicked > 0 {
+            return Err(format!("{} writers panicked", panicked));
+        }
+        Ok(checks)
+    })?;
+
+    let SharedTree { tree, log } = shared.into_inner().map_err(|_| "a writer panicked")?;
+    trace::check(&log, config.check_every as usize)
+        .map_err(|e| format!("the log diverges from the model: {}", e))?;
+    let mut replayed = bplustree::BTree::new();
+    log.iter().for_each(|op| op.apply(&mut replayed));
+    tree.check_invariants()
+        .map_err(|e| format!("shared tree: {}", e))?;
+    if !tree.iter().eq(replayed.iter()) {
+        return Err("the shared tree doesn't hold the entries of the replayed log".to_string());
+    }
+    Ok(checks)
+}
+
+fn shared_writer(
+    shared: &Mutex<SharedTree>,
+    published: &RwLock<Published>,
+    config: &StressConfig,
+    seed: u64,
+) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    for idx in 0..config.ops {
+        let op = stress_op(&mut rng, config.keys);
+        let mut guard = shared.lock().unwrap();
+        op.apply(&mut guard.tree);
+        guard.log.push(op);
+        if (idx + 1) % config.check_every == 0 {
+            let snapshot = (guard.tree.share(), guard.tree.content_hash());
+            drop(guard);
+            *published.write().unwrap() = Some(snapshot);
+        }
+    }
+}
+
+// Check the latest snapshot, over and over until the writers are done.
+// Returns the number of checks.
+fn shared_reader(published: &RwLock<Published>, done: &AtomicBool) -> Result<u64, error::Error> {
+    let mut checks = 0;
+    while !done.load(Ordering::Acquire) {
+        let Some((snapshot, digest)) = published.read().unwrap().clone() else {
+            thread::yield_now();
+            continue;
+        };
+        let tree = snapshot.to_tree();
+        tree.check_invariants()?;
+        if tree.content_hash() != digest {
+            return Err(error::Error::Corruption(
+                "a snapshot changed after it was taken".to_string(),
+            ));
+        }
+        checks += 1;
+    }
+    Ok(checks)
+}
+
+// Operation `idx` of a stress run is the `idx`th draw from the seeded RNG:
+// half inserts, 30% deletes and 20% gets
+fn stress_op(rng: &mut StdRng, keys: u64) -> trace::Op {
+    let key = [rng.gen_range(0..keys) as u128; 1];
+    match rng.gen_range(0..10) {
+        0..=4 => trace::Op::Insert(key, rng.gen()),
+        5..=7 => trace::Op::Delete(key),
+        _ => trace::Op::Get(key),
+    }
+}
+
+// Returns the index of the failing operation along with the failure
+fn stress_worker(
+    ops: u64,
+    keys: u64,
+    check_every: u64,
+    seed: u64,
+) -> Result<(), (u64, error::Error)> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut checker = trace::Checker::new();
+    for idx in 0..ops {
+        let op = stress_op(&mut rng, keys);
+        checker.apply(&op).map_err(|e| (idx, e))?;
+        if (idx + 1) % check_every == 0 || idx + 1 == ops {
+            checker.check_contents().map_err(|e| (idx, e))?;
+        }
+    }
+    Ok(())
+}
+
+// Regenerates the operations of a failed worker up to the failing one and
+// shrinks them to a minimal failing sequence, printed as a unit test
+fn reduce_failure(seed: u64, keys: u64, failed_at: u64) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let ops: Vec<trace::Op> = (0..=failed_at).map(|_| stress_op(&mut rng, keys)).collect();
+    eprintln!("shrinking {} operations...", ops.len());
+    // Every candidate that panics would print its backtrace otherwise
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let ops = trace::shrink(ops, |ops| trace::check(ops, ops.len()).is_err());
+    panic::set_hook(hook);
+    if let Err(e) = trace::check(&ops, ops.len()) {
+        eprintln!("minimal failing sequence ({} ops): {}", ops.len(), e);
+    }
+    eprintln!("{}", trace::unit_test(&ops));
+}
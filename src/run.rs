@@ -0,0 +1,431 @@
+//! Sorted runs: immutable files holding entries in ascending key order, the
+//! way an SSTable does, to hand a tree's content off to compaction or
+//! analytics tools. A sparse index of the first key of every block and a
+//! bloom filter over the keys let a reader answer point lookups with at most
+//! one block read, and none for most absent keys. Runs are imported back by
+//! merging them with the content of a tree into a bulk load.
+//!
+//! Layout, integers little-endian:
+//!
+//! ```text
+//! header  magic "KVSRUN01", entry count (u64)
+//! data    entries in ascending key order: key (u128), value (u8)
+//! index   first key of every block of BLOCK_ENTRIES entries (u128)
+//! bloom   filter bits (u64 words)
+//! footer  bloom word count (u64), bloom hash count (u32), magic "KVSRUN01"
+//! ```
+use crate::bplustree::{BTree, Key, Value};
+use crate::error::{self, Error};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"KVSRUN01";
+const HEADER_SIZE: u64 = 16;
+const FOOTER_SIZE: u64 = 20;
+const ENTRY_SIZE: u64 = (std::mem::size_of::<Key>() + std::mem::size_of::<Value>()) as u64;
+/// Entries per block of the sparse index
+pub const BLOCK_ENTRIES: usize = 128;
+// About 1% false positives
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u32 = 7;
+
+impl BTree {
+    /// Write the entries of the tree to a new sorted run file at `path`
+    pub fn export_run<P: AsRef<Path>>(&self, path: P) -> error::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write_run(&mut out, self.iter())?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Upsert the entries of the runs at `paths`: the runs and the current
+    /// entries are merged in a single pass and the tree is rebuilt by
+    /// `bulk_load`, with full leaves, instead of inserting key by key. A key
+    /// takes its value from the last run holding it. On error the tree is
+    /// left as it was.
+    pub fn import_runs<P: AsRef<Path>>(&mut self, paths: &[P]) -> error::Result<()> {
+        let mut runs = paths
+            .iter()
+            .map(Run::open)
+            .collect::<error::Result<Vec<Run>>>()?;
+        let (tree, error) = {
+            let mut sources: Vec<Source> = vec![Box::new(self.iter().map(Ok))];
+            for run in runs.iter_mut() {
+                sources.push(Box::new(run.iter()?));
+            }
+            let mut merge = Merge::new(sources);
+            (BTree::bulk_load(&mut merge), merge.error)
+        };
+        if let Some(e) = error {
+            return Err(e);
+        }
+        let tree = tree?;
+        self.replace_nodes(tree);
+        Ok(())
+    }
+}
+
+type Source<'a> = Box<dyn Iterator<Item = error::Result<(Key, Value)>> + 'a>;
+
+// K-way merge of sorted sources, the last source holding a key winning. Stops
+// at the first read error, which it keeps.
+struct Merge<'a> {
+    sources: Vec<Source<'a>>,
+    // Next entry of every source that has one, smallest key first and, for
+    // equal keys, the last source first
+    heads: BinaryHeap<(Reverse<Key>, usize, Value)>,
+    error: Option<Error>,
+}
+
+impl<'a> Merge<'a> {
+    fn new(sources: Vec<Source<'a>>) -> Merge<'a> {
+        let mut merge = Merge {
+            heads: BinaryHeap::with_capacity(sources.len()),
+            sources,
+            error: None,
+        };
+        (0..merge.sources.len()).for_each(|src| merge.advance(src));
+        merge
+    }
+
+    fn advance(&mut self, src: usize) {
+        match self.sources[src].next() {
+            Some(Ok((key, val))) => self.heads.push((Reverse(key), src, val)),
+            Some(Err(e)) => self.error = self.error.take().or(Some(e)),
+            None => (),
+        }
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = (Key, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error.is_some() {
+            return None;
+        }
+        let (Reverse(key), src, val) = self.heads.pop()?;
+        self.advance(src);
+        // Older values of the key
+        while let Some((Reverse(k), src, _)) = self.heads.peek().copied() {
+            if k != key {
+                break;
+            }
+            self.heads.pop();
+            self.advance(src);
+        }
+        Some((key, val))
+    }
+}
+
+/// Write `entries` as a sorted run. Fails with `Error::InvalidInput` if the
+/// keys are not strictly ascending, in which case `out` holds a partial run.
+pub fn write_run<W, I>(mut out: W, entries: I) -> error::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = (Key, Value)>,
+    I::IntoIter: ExactSizeIterator,
+{
+    let entries = entries.into_iter();
+    let len = entries.len();
+    out.write_all(MAGIC)?;
+    out.write_all(&(len as u64).to_le_bytes())?;
+
+    let mut index = Vec::with_capacity(len.div_ceil(BLOCK_ENTRIES));
+    let mut bloom = Bloom::with_capacity(len);
+    let mut last: Option<Key> = None;
+    for (idx, (key, val)) in entries.enumerate() {
+        if let Some(last) = last {
+            if last >= key {
+                return Err(Error::InvalidInput(format!(
+                    "run keys must be strictly ascending, got {:?} after {:?}",
+                    key, last
+                )));
+            }
+        }
+        last = Some(key);
+        if idx % BLOCK_ENTRIES == 0 {
+            index.push(key);
+        }
+        bloom.insert(&key);
+        out.write_all(&key[0].to_le_bytes())?;
+        out.write_all(&val.to_le_bytes())?;
+    }
+
+    for key in &index {
+        out.write_all(&key[0].to_le_bytes())?;
+    }
+    for word in &bloom.words {
+        out.write_all(&word.to_le_bytes())?;
+    }
+    out.write_all(&(bloom.words.len() as u64).to_le_bytes())?;
+    out.write_all(&bloom.hashes.to_le_bytes())?;
+    out.write_all(MAGIC)?;
+    Ok(())
+}
+
+/// Sorted run opened for reading. The index and the bloom filter are loaded
+/// in memory; the entries are read from the file as needed.
+#[derive(Debug)]
+pub struct Run {
+    file: BufReader<File>,
+    len: usize,
+    index: Vec<Key>,
+    bloom: Bloom,
+}
+
+impl Run {
+    /// Open the run at `path`, failing with `Error::Serialization` if it is
+    /// not a well-formed run
+    pub fn open<P: AsRef<Path>>(path: P) -> error::Result<Run> {
+        let mut file = BufReader::new(File::open(path)?);
+        let size = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut header = [0; HEADER_SIZE as usize];
+        file.read_exact(&mut header).map_err(truncated)?;
+        if &header[..8] != MAGIC {
+            return Err(Error::Serialization("not a sorted run".to_string()));
+        }
+        let len = u64::from_le_bytes(header[8..].try_into().unwrap());
+
+        let mut footer = [0; FOOTER_SIZE as usize];
+        file.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))
+            .map_err(truncated)?;
+        file.read_exact(&mut footer).map_err(truncated)?;
+        let words = u64::from_le_bytes(footer[..8].try_into().unwrap());
+        let hashes = u32::from_le_bytes(footer[8..12].try_into().unwrap());
+        let blocks = len.div_ceil(BLOCK_ENTRIES as u64);
+        let expected = len
+            .checked_mul(ENTRY_SIZE)
+            .and_then(|data| data.checked_add(blocks * 16))
+            .and_then(|size| size.checked_add(words.checked_mul(8)?))
+            .and_then(|size| size.checked_add(HEADER_SIZE + FOOTER_SIZE));
+        if &footer[12..] != MAGIC || expected != Some(size) || hashes == 0 {
+            return Err(Error::Serialization(format!(
+                "malformed sorted run footer ({} entries, {} bytes)",
+                len, size
+            )));
+        }
+
+        file.seek(SeekFrom::Start(HEADER_SIZE + len * ENTRY_SIZE))?;
+        let index = (0..blocks)
+            .map(|_| read_u128(&mut file).map(|k| [k; 1]))
+            .collect::<io::Result<Vec<Key>>>()?;
+        let words = (0..words)
+            .map(|_| {
+                let mut word = [0; 8];
+                file.read_exact(&mut word).map(|_| u64::from_le_bytes(word))
+            })
+            .collect::<io::Result<Vec<u64>>>()?;
+        Ok(Run {
+            file,
+            len: len as usize,
+            index,
+            bloom: Bloom { words, hashes },
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Look `key` up: the bloom filter rules most absent keys out, otherwise
+    /// the block that may hold it is read
+    pub fn get(&mut self, key: &Key) -> error::Result<Option<Value>> {
+        if !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        let block = match self.index.partition_point(|first| first <= key) {
+            0 => return Ok(None),
+            idx => idx - 1,
+        };
+        let start = block * BLOCK_ENTRIES;
+        let entries = BLOCK_ENTRIES.min(self.len - start);
+        self.file
+            .seek(SeekFrom::Start(HEADER_SIZE + start as u64 * ENTRY_SIZE))?;
+        for _ in 0..entries {
+            let (k, v) = read_entry(&mut self.file)?;
+            if k >= *key {
+                return Ok((k == *key).then_some(v));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Iterate over the entries in ascending key order, reading the file
+    /// sequentially
+    pub fn iter(&mut self) -> error::Result<RunIter<'_>> {
+        self.file.seek(SeekFrom::Start(HEADER_SIZE))?;
+        Ok(RunIter {
+            file: &mut self.file,
+            left: self.len,
+        })
+    }
+}
+
+/// Entries of a `Run`, each read from the file
+pub struct RunIter<'a> {
+    file: &'a mut BufReader<File>,
+    left: usize,
+}
+
+impl Iterator for RunIter<'_> {
+    type Item = error::Result<(Key, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+        Some(read_entry(self.file).map_err(Error::from))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.left, Some(self.left))
+    }
+}
+
+fn read_u128<R: Read>(input: &mut R) -> io::Result<u128> {
+    let mut bytes = [0; 16];
+    input.read_exact(&mut bytes)?;
+    Ok(u128::from_le_bytes(bytes))
+}
+
+fn read_entry<R: Read>(input: &mut R) -> io::Result<(Key, Value)> {
+    let key = read_u128(input)?;
+    let mut val = [0; std::mem::size_of::<Value>()];
+    input.read_exact(&mut val)?;
+    Ok(([key; 1], Value::from_le_bytes(val)))
+}
+
+fn truncated(_: io::Error) -> Error {
+    Error::Serialization("sorted run is truncated".to_string())
+}
+
+#[derive(Debug)]
+struct Bloom {
+    words: Vec<u64>,
+    hashes: u32,
+}
+
+impl Bloom {
+    fn with_capacity(keys: usize) -> Bloom {
+        Bloom {
+            words: vec![0; (keys * BLOOM_BITS_PER_KEY).div_ceil(64).max(1)],
+            hashes: BLOOM_HASHES,
+        }
+    }
+
+    fn insert(&mut self, key: &Key) {
+        for bit in bloom_bits(key, self.words.len(), self.hashes) {
+            self.words[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    fn may_contain(&self, key: &Key) -> bool {
+        bloom_bits(key, self.words.len(), self.hashes)
+            .all(|bit| self.words[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+// Bits of `key` in a filter of `words` words, by double hashing
+fn bloom_bits(key: &Key, words: usize, hashes: u32) -> impl Iterator<Item = usize> {
+    let h1 = mix(key[0] as u64 ^ mix((key[0] >> 64) as u64));
+    let h2 = mix(h1) | 1;
+    let bits = words as u64 * 64;
+    (0..hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+}
+
+// splitmix64 finalizer
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_and_read_run() {
+        let path = std::env::temp_dir().join(format!("kvs-run-{}.run", std::process::id()));
+        let mut tree = BTree::new();
+        (0..1000).for_each(|k| {
+            tree.insert([k * 2; 1], (k % 256) as Value);
+        });
+        tree.export_run(&path).unwrap();
+
+        let mut run = Run::open(&path).unwrap();
+        assert_eq!(run.len(), 1000);
+        assert_eq!(run.get(&[0; 1]).unwrap(), Some(0));
+        assert_eq!(run.get(&[1998; 1]).unwrap(), Some((999 % 256) as Value));
+        assert_eq!(run.get(&[256; 1]).unwrap(), Some(128));
+        assert_eq!(run.get(&[257; 1]).unwrap(), None);
+        assert_eq!(run.get(&[5000; 1]).unwrap(), None);
+        let entries: Vec<_> = run.iter().unwrap().map(Result::unwrap).collect();
+        assert!(entries.into_iter().eq(tree.iter()));
+        // Absent keys mostly stop at the bloom filter
+        let passed = (0..1000)
+            .filter(|k| run.bloom.may_contain(&[k * 2 + 1; 1]))
+            .count();
+        assert!(passed < 50, "{} false positives", passed);
+
+        std::fs::write(&path, b"KVSRUN01 not a run").unwrap();
+        assert!(matches!(Run::open(&path), Err(Error::Serialization(_))));
+        std::fs::remove_file(&path).unwrap();
+
+        let mut out = Vec::new();
+        assert!(write_run(&mut out, [([2; 1], 0), ([1; 1], 0)]).is_err());
+    }
+
+    #[test]
+    fn test_import_runs() {
+        let dir = std::env::temp_dir();
+        let paths: Vec<_> = ["evens", "threes", "bad"]
+            .iter()
+            .map(|name| dir.join(format!("kvs-import-{}-{}.run", std::process::id(), name)))
+            .collect();
+        let mut evens = BTree::new();
+        (0..1000).for_each(|k| {
+            evens.insert([k * 2; 1], 1);
+        });
+        evens.export_run(&paths[0]).unwrap();
+        let mut threes = BTree::new();
+        (0..1000).for_each(|k| {
+            threes.insert([k * 3; 1], 2);
+        });
+        threes.export_run(&paths[1]).unwrap();
+
+        let mut tree = BTree::new();
+        (0..1000).for_each(|k| {
+            tree.insert([k; 1], 0);
+        });
+        tree.import_runs(&paths[..2]).unwrap();
+        tree.check_invariants().unwrap();
+        for k in 0..3000 {
+            let expected = match k {
+                k if k % 3 == 0 => Some(2),
+                k if k % 2 == 0 && k < 2000 => Some(1),
+                k if k < 1000 => Some(0),
+                _ => None,
+            };
+            assert_eq!(tree.get(&[k; 1]), expected, "key {}", k);
+        }
+
+        std::fs::write(&paths[2], b"garbage").unwrap();
+        let before = tree.content_hash();
+        assert!(tree.import_runs(&paths).is_err());
+        assert_eq!(tree.content_hash(), before);
+        paths
+            .iter()
+            .for_each(|path| std::fs::remove_file(path).unwrap());
+    }
+}
@@ -0,0 +1,511 @@
+//! Operation traces: a text log of the operations applied to a tree, with the
+//! time at which each one was issued, that can be replayed against another
+//! build to reproduce an access pattern.
+//!
+//! One operation per line, timestamps in nanoseconds since the start of the
+//! recording:
+//!
+//! ```text
+//! 1520 insert 42 7
+//! 2210 get 42
+//! 3005 delete 42
+//! 4100 range 10 50
+//! 4870 pop_first
+//! ```
+//!
+//! Ranges are half-open, `lo..hi`; a range with `lo` after `hi` is empty.
+use crate::bplustree::{BTree, Key, Value};
+use crate::error::{self, Error};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Insert(Key, Value),
+    Get(Key),
+    Delete(Key),
+    Range(Key, Key),
+    RangeRev(Key, Key),
+    DeleteRange(Key, Key),
+    PopFirst,
+    PopLast,
+}
+
+impl Op {
+    /// Apply the operation to `tree`
+    pub fn apply(&self, tree: &mut BTree) {
+        match self {
+            Op::Insert(key, val) => {
+                tree.insert(*key, *val);
+            }
+            Op::Get(key) => {
+                tree.get(key);
+            }
+            Op::Delete(key) => {
+                tree.delete(key);
+            }
+            Op::Range(lo, hi) => {
+                tree.range(*lo..*hi).count();
+            }
+            Op::RangeRev(lo, hi) => {
+                tree.range(*lo..*hi).rev().count();
+            }
+            Op::DeleteRange(lo, hi) => {
+                tree.delete_range(*lo..*hi);
+            }
+            Op::PopFirst => {
+                tree.pop_first();
+            }
+            Op::PopLast => {
+                tree.pop_last();
+            }
+        }
+    }
+}
+
+/// Writes operations to a trace. Timestamps are provided by the caller, so
+/// that the library doesn't depend on a clock.
+pub struct TraceWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TraceWriter<W> {
+    pub fn new(out: W) -> TraceWriter<W> {
+        TraceWriter { out }
+    }
+
+    /// Record `op` as issued `at` after the start of the recording
+    pub fn record(&mut self, at: Duration, op: &Op) -> io::Result<()> {
+        let ns = at.as_nanos();
+        match op {
+            Op::Insert(key, val) => writeln!(self.out, "{} insert {} {}", ns, key[0], val),
+            Op::Get(key) => writeln!(self.out, "{} get {}", ns, key[0]),
+            Op::Delete(key) => writeln!(self.out, "{} delete {}", ns, key[0]),
+            Op::Range(lo, hi) => writeln!(self.out, "{} range {} {}", ns, lo[0], hi[0]),
+            Op::RangeRev(lo, hi) => writeln!(self.out, "{} range_rev {} {}", ns, lo[0], hi[0]),
+            Op::DeleteRange(lo, hi) => {
+                writeln!(self.out, "{} delete_range {} {}", ns, lo[0], hi[0])
+            }
+            Op::PopFirst => writeln!(self.out, "{} pop_first", ns),
+            Op::PopLast => writeln!(self.out, "{} pop_last", ns),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Parse one trace line into its offset from the start of the recording and
+/// its operation
+pub fn parse_line(line: &str) -> error::Result<(Duration, Op)> {
+    parse_fields(line).map_err(Error::Serialization)
+}
+
+fn parse_fields(line: &str) -> Result<(Duration, Op), String> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let ns: u64 = fields
+        .first()
+        .ok_or("empty line")?
+        .parse()
+        .map_err(|_| "invalid timestamp")?;
+    let key = |idx: usize| -> Result<Key, String> {
+        let key = fields.get(idx).ok_or("missing key")?;
+        Ok([key.parse().map_err(|_| "invalid key")?; 1])
+    };
+    let op = match (fields.get(1).copied(), fields.len()) {
+        (Some("insert"), 4) => {
+            let val = fields[3].parse().map_err(|_| "invalid value")?;
+            Op::Insert(key(2)?, val)
+        }
+        (Some("get"), 3) => Op::Get(key(2)?),
+        (Some("delete"), 3) => Op::Delete(key(2)?),
+        (Some("range"), 4) => Op::Range(key(2)?, key(3)?),
+        (Some("range_rev"), 4) => Op::RangeRev(key(2)?, key(3)?),
+        (Some("delete_range"), 4) => Op::DeleteRange(key(2)?, key(3)?),
+        (Some("pop_first"), 2) => Op::PopFirst,
+        (Some("pop_last"), 2) => Op::PopLast,
+        (Some(op), _) => return Err(format!("malformed `{}` operation", op)),
+        (None, _) => return Err("missing operation".to_string()),
+    };
+    Ok((Duration::from_nanos(ns), op))
+}
+
+/// Iterate over the operations of a trace, skipping blank lines. Parse
+/// errors carry the line number.
+pub fn read<R: BufRead>(input: R) -> impl Iterator<Item = error::Result<(Duration, Op)>> {
+    input
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.as_ref().is_ok_and(|l| l.trim().is_empty()))
+        .map(|(idx, line)| {
+            parse_fields(&line?)
+                .map_err(|e| Error::Serialization(format!("line {}: {}", idx + 1, e)))
+        })
+}
+
+/// Drives a tree and a `BTreeMap` model with the same operations, comparing
+/// the result of every operation
+pub struct Checker {
+    tree: BTree,
+    model: BTreeMap<Key, Value>,
+}
+
+impl Checker {
+    pub fn new() -> Checker {
+        Checker {
+            tree: BTree::new(),
+            model: BTreeMap::new(),
+        }
+    }
+
+    /// Apply `op` to the tree and the model. A divergence, or a panic raised
+    /// by the tree, is reported as `Error::Corruption`.
+    pub fn apply(&mut self, op: &Op) -> error::Result<()> {
+        let (tree, model) = (&mut self.tree, &mut self.model);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| match op {
+            Op::Insert(key, val) => {
+                let (got, expected) = (tree.insert(*key, *val), model.insert(*key, *val));
+                if got != expected {
+                    return Err(format!("returned {:?}, expected {:?}", got, expected));
+                }
+                Ok(())
+            }
+            Op::Get(key) => {
+                let (got, expected) = (tree.get(key), model.get(key).copied());
+                if got != expected {
+                    return Err(format!("returned {:?}, expected {:?}", got, expected));
+                }
+                Ok(())
+            }
+            Op::Delete(key) => {
+                let (got, expected) = (tree.delete(key), model.remove(key));
+                if got != expected {
+                    return Err(format!("returned {:?}, expected {:?}", got, expected));
+                }
+                Ok(())
+            }
+            Op::Range(lo, hi) => {
+                let got: Vec<_> = tree.range(*lo..*hi).collect();
+                let expected = model_range(model, lo, hi);
+                if got != expected {
+                    return Err(format!("yielded {:?}, expected {:?}", got, expected));
+                }
+                Ok(())
+            }
+            Op::RangeRev(lo, hi) => {
+                let got: Vec<_> = tree.range(*lo..*hi).rev().collect();
+                let mut expected = model_range(model, lo, hi);
+                expected.reverse();
+                if got != expected {
+                    return Err(format!("yielded {:?}, expected {:?}", got, expected));
+                }
+                Ok(())
+            }
+            Op::DeleteRange(lo, hi) => {
+                let len = model.len();
+                model.retain(|key, _| !(lo..hi).contains(&key));
+                let (got, expected) = (tree.delete_range(*lo..*hi), len - model.len());
+                if got != expected {
+                    return Err(format!("returned {}, expected {}", got, expected));
+                }
+                Ok(())
+            }
+            Op::PopFirst => {
+                let (got, expected) = (tree.pop_first(), model.pop_first());
+                if got != expected {
+                    return Err(format!("returned {:?}, expected {:?}", got, expected));
+                }
+                Ok(())
+            }
+            Op::PopLast => {
+                let (got, expected) = (tree.pop_last(), model.pop_last());
+                if got != expected {
+                    return Err(format!("returned {:?}, expected {:?}", got, expected));
+                }
+                Ok(())
+            }
+        }));
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => Err(format!("panicked: {}", panic_message(&e))),
+        };
+        result.map_err(|e| Error::Corruption(format!("{:?} {}", op, e)))
+    }
+
+    /// Check the tree invariants and that the tree holds exactly the model's
+    /// entries. O(n).
+    pub fn check_contents(&self) -> error::Result<()> {
+        self.tree.check_invariants()?;
+        if self.tree.total_len() != self.model.len() {
+            return Err(Error::Corruption(format!(
+                "tree holds {} entries, expected {}",
+                self.tree.total_len(),
+                self.model.len()
+            )));
+        }
+        for (key, val) in &self.model {
+            if self.tree.get(key) != Some(*val) {
+                return Err(Error::Corruption(format!(
+                    "key {} lost or holds a stale value",
+                    key[0]
+                )));
+            }
+        }
+        if !self
+            .tree
+            .iter()
+            .eq(self.model.iter().map(|(k, v)| (*k, *v)))
+        {
+            return Err(Error::Corruption(
+                "iteration doesn't yield the entries in order".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Checker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Entries of `model` within `lo..hi`, none if `lo` is after `hi` (where
+// `BTreeMap::range` panics)
+fn model_range(model: &BTreeMap<Key, Value>, lo: &Key, hi: &Key) -> Vec<(Key, Value)> {
+    if lo > hi {
+        return Vec::new();
+    }
+    model.range(*lo..*hi).map(|(k, v)| (*k, *v)).collect()
+}
+
+/// Replay `ops` through a [`Checker`], checking the full contents every
+/// `full_check_every` operations and after the last one
+pub fn check(ops: &[Op], full_check_every: usize) -> error::Result<()> {
+    let mut checker = Checker::new();
+    for (idx, op) in ops.iter().enumerate() {
+        checker.apply(op).map_err(|e| at_op(idx, e))?;
+        if (idx + 1) % full_check_every == 0 || idx + 1 == ops.len() {
+            checker.check_contents().map_err(|e| at_op(idx, e))?;
+        }
+    }
+    Ok(())
+}
+
+// Prefix the description of a divergence with the operation it was found at
+fn at_op(idx: usize, e: Error) -> Error {
+    match e {
+        Error::Corruption(msg) => Error::Corruption(format!("op {}: {}", idx, msg)),
+        e => e,
+    }
+}
+
+fn panic_message(e: &Box<dyn std::any::Any + Send>) -> String {
+    match (e.downcast_ref::<&str>(), e.downcast_ref::<String>()) {
+        (Some(msg), _) => msg.to_string(),
+        (_, Some(msg)) => msg.clone(),
+        _ => "unknown panic".to_string(),
+    }
+}
+
+/// Reduce a failing operation sequence to a (locally) minimal one that still
+/// fails, using delta debugging: drop ever smaller chunks of operations as
+/// long as `fails` keeps returning true.
+pub fn shrink(mut ops: Vec<Op>, mut fails: impl FnMut(&[Op]) -> bool) -> Vec<Op> {
+    let mut chunks = 2;
+    while ops.len() >= 2 {
+        let chunk_len = ops.len().div_ceil(chunks);
+        let mut removed = false;
+        let mut start = 0;
+        while start < ops.len() {
+            let end = (start + chunk_len).min(ops.len());
+            let candidate: Vec<Op> = ops[..start].iter().chain(&ops[end..]).copied().collect();
+            if !candidate.is_empty() && fails(&candidate) {
+                ops = candidate;
+                removed = true;
+            } else {
+                start = end;
+            }
+        }
+        if removed {
+            chunks = (chunks - 1).max(2);
+        } else if chunk_len == 1 {
+            break;
+        } else {
+            chunks = (chunks * 2).min(ops.len());
+        }
+    }
+    ops
+}
+
+/// Render `ops` as a unit test asserting the results and final contents a
+/// correct tree produces, ready to be pasted into the `bplustree` tests
+pub fn unit_test(ops: &[Op]) -> String {
+    let mut model = BTreeMap::new();
+    let mut out =
+        String::from("#[test]\nfn test_reduced_case() {\n    let mut btree = BTree::new();\n");
+    for op in ops {
+        let line =
+            match op {
+                Op::Insert(key, val) => format!(
+                    "assert_eq!(btree.insert([{}; 1], {}), {:?});",
+                    key[0],
+                    val,
+                    model.insert(*key, *val)
+                ),
+                Op::Get(key) => format!(
+                    "assert_eq!(btree.get(&[{}; 1]), {:?});",
+                    key[0],
+                    model.get(key)
+                ),
+                Op::Delete(key) => format!(
+                    "assert_eq!(btree.delete(&[{}; 1]), {:?});",
+                    key[0],
+                    model.remove(key)
+                ),
+                Op::Range(lo, hi) => format!(
+                    "assert_eq!(btree.range([{}; 1]..[{}; 1]).collect::<Vec<_>>(), vec!{:?});",
+                    lo[0],
+                    hi[0],
+                    model_range(&model, lo, hi)
+                ),
+                Op::RangeRev(lo, hi) => format!(
+                "assert_eq!(btree.range([{}; 1]..[{}; 1]).rev().collect::<Vec<_>>(), vec!{:?});",
+                lo[0],
+                hi[0],
+                model_range(&model, lo, hi).into_iter().rev().collect::<Vec<_>>()
+            ),
+                Op::DeleteRange(lo, hi) => {
+                    let len = model.len();
+                    model.retain(|key, _| !(lo..hi).contains(&key));
+                    format!(
+                        "assert_eq!(btree.delete_range([{}; 1]..[{}; 1]), {});",
+                        lo[0],
+                        hi[0],
+                        len - model.len()
+                    )
+                }
+                Op::PopFirst => format!("assert_eq!(btree.pop_first(), {:?});", model.pop_first()),
+                Op::PopLast => format!("assert_eq!(btree.pop_last(), {:?});", model.pop_last()),
+            };
+        out.push_str(&format!("    {}\n", line));
+    }
+    out.push_str("    btree.check_invariants().unwrap();\n");
+    out.push_str(&format!(
+        "    assert_eq!(btree.total_len(), {});\n",
+        model.len()
+    ));
+    for (key, val) in &model {
+        out.push_str(&format!(
+            "    assert_eq!(btree.get(&[{}; 1]), Some({}));\n",
+            key[0], val
+        ));
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let ops = vec![
+            Op::Insert([u128::MAX; 1], 7),
+            Op::Get([3; 1]),
+            Op::Delete([u128::MAX; 1]),
+            Op::Range([1; 1], [5; 1]),
+            Op::RangeRev([5; 1], [1; 1]),
+            Op::DeleteRange([0; 1], [u128::MAX; 1]),
+            Op::PopFirst,
+            Op::PopLast,
+        ];
+        let mut writer = TraceWriter::new(Vec::new());
+        for (i, op) in ops.iter().enumerate() {
+            writer.record(Duration::from_micros(i as u64), op).unwrap();
+        }
+
+        let read: Vec<(Duration, Op)> = read(&writer.out[..]).collect::<Result<_, _>>().unwrap();
+        assert_eq!(read.iter().map(|(_, op)| *op).collect::<Vec<_>>(), ops);
+        assert_eq!(read[2].0, Duration::from_micros(2));
+    }
+
+    #[test]
+    fn test_shrink() {
+        let ops: Vec<Op> = (0..1000u128).map(|k| Op::Insert([k; 1], 0)).collect();
+        // "Fails" as long as both keys 17 and 500 are inserted
+        let shrunk = shrink(ops, |ops| {
+            ops.contains(&Op::Insert([17; 1], 0)) && ops.contains(&Op::Insert([500; 1], 0))
+        });
+        assert_eq!(
+            shrunk,
+            vec![Op::Insert([17; 1], 0), Op::Insert([500; 1], 0)]
+        );
+    }
+
+    #[test]
+    fn test_check_and_unit_test() {
+        let ops = vec![
+            Op::Insert([1; 1], 2),
+            Op::Get([1; 1]),
+            Op::Delete([1; 1]),
+            Op::Delete([1; 1]),
+            Op::Insert([3; 1], 4),
+            Op::Insert([3; 1], 5),
+            Op::Insert([7; 1], 1),
+            Op::Insert([9; 1], 2),
+            Op::Insert([11; 1], 3),
+            Op::Range([0; 1], [10; 1]),
+            Op::RangeRev([10; 1], [0; 1]),
+            Op::DeleteRange([5; 1], [8; 1]),
+            Op::PopFirst,
+            Op::PopLast,
+        ];
+        assert!(check(&ops, 1).is_ok());
+        assert_eq!(
+            unit_test(&ops),
+            "#[test]\n\
+             fn test_reduced_case() {\n    \
+                 let mut btree = BTree::new();\n    \
+                 assert_eq!(btree.insert([1; 1], 2), None);\n    \
+                 assert_eq!(btree.get(&[1; 1]), Some(2));\n    \
+                 assert_eq!(btree.delete(&[1; 1]), Some(2));\n    \
+                 assert_eq!(btree.delete(&[1; 1]), None);\n    \
+                 assert_eq!(btree.insert([3; 1], 4), None);\n    \
+                 assert_eq!(btree.insert([3; 1], 5), Some(4));\n    \
+                 assert_eq!(btree.insert([7; 1], 1), None);\n    \
+                 assert_eq!(btree.insert([9; 1], 2), None);\n    \
+                 assert_eq!(btree.insert([11; 1], 3), None);\n    \
+                 assert_eq!(btree.range([0; 1]..[10; 1]).collect::<Vec<_>>(), \
+                 vec![([3], 5), ([7], 1), ([9], 2)]);\n    \
+                 assert_eq!(btree.range([10; 1]..[0; 1]).rev().collect::<Vec<_>>(), vec![]);\n    \
+                 assert_eq!(btree.delete_range([5; 1]..[8; 1]), 1);\n    \
+                 assert_eq!(btree.pop_first(), Some(([3], 5)));\n    \
+                 assert_eq!(btree.pop_last(), Some(([11], 3)));\n    \
+                 btree.check_invariants().unwrap();\n    \
+                 assert_eq!(btree.total_len(), 1);\n    \
+                 assert_eq!(btree.get(&[9; 1]), Some(2));\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_line("12 insert 1").is_err());
+        assert!(parse_line("12 get x").is_err());
+        assert!(parse_line("x get 1").is_err());
+        assert!(parse_line("12 scan 1 2").is_err());
+        assert!(parse_line("12 range 1").is_err());
+        assert!(parse_line("12 pop_first 1").is_err());
+        let errors: Vec<_> = read("1 get 1\n\n2 get\n".as_bytes()).collect();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors[1].as_ref().unwrap_err().to_string(),
+            "serialization error: line 3: malformed `get` operation"
+        );
+    }
+}